@@ -1,13 +1,16 @@
 use bevy::prelude::*;
 use Md_ECS::{
     atom::*,
-    molecular_dynamics::{lj_interaction::*, integration::*},
+    constant,
+    molecular_dynamics::{lj_interaction::*, integration::*, thermostat::*},
     setup::*, 
-    output::{console::*, file::*},  
+    output::{console::*, file::*, checkpoint::CheckpointPlugin},  
     physical_quant_calc::{rdf::{RDF, RDFPlugin}, AnalysisPlugin},
+    runner::{Runner, StopCondition},
 };
 
 use nalgebra::Vector3;
+use std::time::Duration;
 
 
 fn setup_camera(
@@ -50,6 +53,10 @@ fn main() {
     // lennard jones parameters
     let cutoff = 1.2e-9;
 
+    // thermostat parameters, the target temperature in K and the coupling time
+    let temperature = 120.0;
+    let tau = 1e-11;
+
     // output parameters
     let trjname = String::from("./trjs/argon");
     let output_freq = 1;
@@ -79,8 +86,6 @@ fn main() {
 
 
     let rdf_calc_params = RDF::new(
-        String::from("Argon"), 
-        String::from("Argon"), 
         200, 
         rdf_max,
         String::from("rdf.csv")
@@ -97,7 +102,9 @@ fn main() {
 
     app.add_plugin(LJPlugin);
     app.add_plugin(IntegrationPlugin);
+    app.add_plugin(ThermostatPlugin::new(Thermostat::velocity_rescale(temperature, tau)));
     app.add_plugin(OutputPlugin);
+    app.add_plugin(CheckpointPlugin::new(String::from("./trjs/argon"), 100));
     app.add_plugin(AnalysisPlugin);
     app.add_plugin(rdf_plugin);
 
@@ -116,11 +123,16 @@ fn main() {
 
 
     println!("done setup");
-    // run the simulation
-    for _i in 0..n_steps {
-        println!("step {}.", _i);
-        app.update();
-    }
+    // run the simulation until the number of steps is reached,
+    // or until the simulation blows up.
+    let runner = Runner::new()
+        .with_condition(StopCondition::NaN)
+        .with_condition(StopCondition::EnergyBlowUp {
+            tolerance: 10.0,
+            scale: n_atoms as f64 * constant::BOLTZCONST * temperature,
+        })
+        .with_condition(StopCondition::WallClock(Duration::from_secs(3600)));
+    runner.run(&mut app);
 
 }
 
@@ -1,7 +1,8 @@
 use bevy::prelude::*;
 use nalgebra::{Vector3};
-use crate::{simbox::{SimBox}, molecular_dynamics::integration::OldForce};
+use crate::{simbox::{SimBox}, molecular_dynamics::integration::OldForce, output::checkpoint::Checkpoint, random::SimRng, region::AtomRegion, units::{UnitSystem, Quantity}};
 use std::fmt;
+use rand_chacha::ChaCha8Rng;
 use rand_distr::{Distribution, Normal, Uniform};
 
 #[derive(Clone, Component)]
@@ -9,6 +10,12 @@ pub struct AtomID {
     pub id: u64,
 }
 
+/// the molecule an atom belongs to.
+#[derive(Clone, Copy, Component)]
+pub struct MoleculeID {
+    pub id: u64,
+}
+
 #[derive(Clone, Component)]
 pub struct Position {
     pub pos: Vector3<f64>,
@@ -61,7 +68,13 @@ impl Default for Force {
 
 #[derive(Clone, Component)]
 pub struct Mass {
-    /// mass value in atom mass units
+    /// mass value in atom mass units, or in units of the reference mass for reduced LJ units
+    pub value: f64,
+}
+
+#[derive(Clone, Copy, Component)]
+pub struct Charge {
+    /// charge value in elementary charges
     pub value: f64,
 }
 
@@ -84,6 +97,15 @@ impl AtomType {
 #[derive(Default, Component)]
 pub struct Atom;
 
+/// an atom held in place, the integrators do not move it and ignore its force, and it has no
+/// degrees of freedom in the temperature.
+#[derive(Clone, Copy, Default, Component)]
+pub struct Frozen;
+
+/// an atom spawned during the run, whose velocity is the velocity of the step it joins in.
+#[derive(Clone, Copy, Default, Component)]
+pub struct NewAtom;
+
 #[derive(Clone, Copy)]
 pub struct AtomNumber {
     pub n_atoms: u64,
@@ -114,59 +136,84 @@ impl LJParams {
 }
 
 
+/// the sphere used to render an atom, only added when the rendering assets exist.
+pub fn atom_render_bundle (
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+) -> PbrBundle {
+    PbrBundle{
+        mesh: meshes.add(Mesh::from(shape::Icosphere {radius: 5e-7, subdivisions: 2})),
+        material: materials.add(Color::rgb(1.0, 0.0, 0.0).into()),
+        //transform: Transform::from_xyz(1.5, 1.5, 1.5),
+        ..default()
+    }
+}
+
 pub fn create_atoms (
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
+    meshes: Option<ResMut<Assets<Mesh>>>,
+    materials: Option<ResMut<Assets<StandardMaterial>>>,
     n_atoms: Res<AtomNumber>,
     simbox: Res<SimBox>,
+    mut sim_rng: ResMut<SimRng>,
+    units: Res<UnitSystem>,
+    restart: Option<Res<Checkpoint>>,
+    atom_region: Option<Res<AtomRegion>>,
 ) {
+    // when restarting, the atoms are restored from the checkpoint instead.
+    if restart.is_some() {
+        return;
+    }
+
     // we use the approximate gas molecule velocity in room temperature as
     // the default value, and we assume the velocity to be isotropic
-    let v_dist = Normal::new(0.0, 460.0).unwrap();
-
-    let x_dist = Uniform::new(simbox.origin.x, simbox.origin.x + simbox.dimension.x);
-    let y_dist = Uniform::new(simbox.origin.y, simbox.origin.y + simbox.dimension.y);
-    let z_dist = Uniform::new(simbox.origin.z, simbox.origin.z + simbox.dimension.z);
+    let v_dist = Normal::new(0.0, units.from_si(Quantity::Velocity, 460.0)).unwrap();
+
+    // the argon parameters in SI units, converted to the simulation units.
+    let sigma = units.from_si(Quantity::Length, 3.4e-10);
+    let epsilon = units.from_si(Quantity::Energy, 1.654e-21);
+    let mass = units.from_si(Quantity::Mass, 39.948 * crate::constant::AMU);
+
+    // the atoms are placed uniformly in fractional coordinates, which also fills triclinic boxes,
+    // and the positions outside the region to fill, if there is one, are drawn again.
+    let s_dist = Uniform::new(0.0, 1.0);
+    let sample_position = |rng: &mut ChaCha8Rng| -> Vector3<f64> {
+        for _ in 0..10000 {
+            let pos = simbox.from_fractional(Vector3::new(s_dist.sample(rng), s_dist.sample(rng), s_dist.sample(rng)));
+            if atom_region.as_ref().is_none_or(|atom_region| atom_region.region.contains(&pos, &simbox)) {
+                return pos;
+            }
+        }
+        panic!("the region to create the atoms in is too small a part of the box");
+    };
 
-    let mut rng = rand::thread_rng();
+    let rng = &mut sim_rng.rng;
+    let mut render = meshes.zip(materials);
 
     for i in 0..n_atoms.n_atoms {
-        commands.spawn()
-            .insert(
-                Position {
-                    pos: Vector3::new (
-                        x_dist.sample(&mut rng),
-                        y_dist.sample(&mut rng),
-                        z_dist.sample(&mut rng),
-                    )
-                }
-            )
+        let mut entity = commands.spawn();
+        entity
+            .insert(Position { pos: sample_position(rng) })
             .insert(AtomID {id: i+1})
             .insert(
                 Velocity {
                     vel: Vector3::new(
-                        v_dist.sample(&mut rng),
-                        v_dist.sample(&mut rng),
-                        v_dist.sample(&mut rng),
+                        v_dist.sample(rng),
+                        v_dist.sample(rng),
+                        v_dist.sample(rng),
                     )
                 }
             )
             .insert(Force::default())
             .insert(OldForce(Force::default()))
-            .insert(Mass {value: 39.948*crate::constant::AMU})
+            .insert(Mass {value: mass})
             .insert(Atom)
             // to be fixed, now the lj parameters are hard coded.
-            .insert(AtomType::new(String::from("Argon"), 3.4e-10, 1.654e-21))
-            // for rendering purpose
-            .insert_bundle(
-                PbrBundle{
-                    mesh: meshes.add(Mesh::from(shape::Icosphere {radius: 5e-7, subdivisions: 2})),
-                    material: materials.add(Color::rgb(1.0, 0.0, 0.0).into()),
-                    //transform: Transform::from_xyz(1.5, 1.5, 1.5),
-                    ..default()
-                }
-            );
+            .insert(AtomType::new(String::from("Argon"), sigma, epsilon));
+        // for rendering purpose
+        if let Some((meshes, materials)) = render.as_mut() {
+            entity.insert_bundle(atom_render_bundle(meshes, materials));
+        }
     }
 }
 
@@ -1,11 +1,10 @@
 
-//! Mathematical and physical constants.
-
-/// Reduced plank constant in SI units
-pub const HBAR: f64 = 1.0545718e-34;
-
-/// Gravitational acceleration in SI units
-pub const GC: f64 = 9.80665;
+//! Mathematical and physical constants, in SI units.
+//! See `units::UnitSystem` for the values in the simulation units.
+//!
+//! The physical constants are the CODATA 2018 recommended values. Since the 2019
+//! redefinition of the SI, the Planck constant, the elementary charge, the Boltzmann
+//! constant, the Avogadro constant and the speed of light are exact.
 
 /// Mathematical constant exp(1)
 pub const EXP: f64 = std::f64::consts::E;
@@ -13,17 +12,125 @@ pub const EXP: f64 = std::f64::consts::E;
 /// Mathematica constant pi
 pub const PI: f64 = std::f64::consts::PI;
 
-// The Bohr magneton, defined in SI units of Joules/Tesla.
-pub const BOHRMAG: f64 = 9.274e-24;
+/// Sqrt of 2
+pub const SQRT2: f64 = std::f64::consts::SQRT_2;
+
+/// Speed of light in SI units of m/s (exact)
+pub const C: f64 = 299792458.0;
+
+/// Planck constant in SI units of J s (exact)
+pub const PLANCK: f64 = 6.62607015e-34;
+
+/// Reduced plank constant in SI units
+pub const HBAR: f64 = PLANCK / (2.0 * PI);
 
-/// Boltzmann constant in SI units
-pub const BOLTZCONST: f64 = 1.38e-23;
+/// Elementary charge in SI units of C (exact)
+pub const ELEMCHARGE: f64 = 1.602176634e-19;
+
+/// Boltzmann constant in SI units of J/K (exact)
+pub const BOLTZCONST: f64 = 1.380649e-23;
+
+/// Avogadro constant in SI units of 1/mol (exact)
+pub const AVOGADRO: f64 = 6.02214076e23;
 
 /// The value of 1 Atomic Mass Unit (amu) in SI units of kg.
-pub const AMU: f64 = 1.6605e-27;
+pub const AMU: f64 = 1.66053906660e-27;
 
-/// Speed of light in SI units of m/s
-pub const C: f64 = 299297458.0;
+/// Electron mass in SI units of kg
+pub const ELECTRON_MASS: f64 = 9.1093837015e-31;
 
-/// Sqrt of 2
-pub const SQRT2: f64 = std::f64::consts::SQRT_2;
\ No newline at end of file
+/// Proton mass in SI units of kg
+pub const PROTON_MASS: f64 = 1.67262192369e-27;
+
+/// Vacuum electric permittivity in SI units of F/m
+pub const VACUUM_PERMITTIVITY: f64 = 8.8541878128e-12;
+
+/// Vacuum magnetic permeability in SI units of N/A^2
+pub const VACUUM_PERMEABILITY: f64 = 1.25663706212e-6;
+
+/// The Bohr magneton, defined in SI units of Joules/Tesla.
+pub const BOHRMAG: f64 = 9.2740100783e-24;
+
+/// Bohr radius in SI units of m
+pub const BOHR_RADIUS: f64 = 5.29177210903e-11;
+
+/// Hartree energy in SI units of J
+pub const HARTREE: f64 = 4.3597447222071e-18;
+
+/// Newtonian constant of gravitation in SI units of m^3/(kg s^2)
+pub const NEWTON_G: f64 = 6.67430e-11;
+
+/// Gravitational acceleration in SI units (standard gravity, exact)
+pub const GC: f64 = 9.80665;
+
+/* Derived constants */
+
+/// Coulomb constant 1/(4 pi epsilon_0) in SI units of N m^2/C^2
+pub const COULOMB: f64 = 1.0 / (4.0 * PI * VACUUM_PERMITTIVITY);
+
+/// Molar gas constant in SI units of J/(mol K) (exact)
+pub const GAS_CONSTANT: f64 = AVOGADRO * BOLTZCONST;
+
+/// Faraday constant in SI units of C/mol (exact)
+pub const FARADAY: f64 = AVOGADRO * ELEMCHARGE;
+
+/// One electronvolt in SI units of J (exact)
+pub const ELECTRONVOLT: f64 = ELEMCHARGE;
+
+/// One kilocalorie per mole (thermochemical calorie) in SI units of J per particle (exact)
+pub const KCAL_PER_MOL: f64 = 4184.0 / AVOGADRO;
+
+/// One kilojoule per mole in SI units of J per particle (exact)
+pub const KJ_PER_MOL: f64 = 1000.0 / AVOGADRO;
+
+/// One standard atmosphere in SI units of Pa (exact)
+pub const ATM: f64 = 101325.0;
+
+/// One bar in SI units of Pa (exact)
+pub const BAR: f64 = 1e5;
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_exact_constants() {
+        assert_eq!(C, 299792458.0);
+        assert_eq!(PLANCK, 6.62607015e-34);
+        assert_eq!(ELEMCHARGE, 1.602176634e-19);
+        assert_eq!(BOLTZCONST, 1.380649e-23);
+        assert_eq!(AVOGADRO, 6.02214076e23);
+        assert_eq!(GC, 9.80665);
+    }
+
+    #[test]
+    fn test_measured_constants() {
+        assert_eq!(AMU, 1.66053906660e-27);
+        assert_eq!(ELECTRON_MASS, 9.1093837015e-31);
+        assert_eq!(PROTON_MASS, 1.67262192369e-27);
+        assert_eq!(VACUUM_PERMITTIVITY, 8.8541878128e-12);
+        assert_eq!(VACUUM_PERMEABILITY, 1.25663706212e-6);
+        assert_eq!(BOHRMAG, 9.2740100783e-24);
+        assert_eq!(BOHR_RADIUS, 5.29177210903e-11);
+        assert_eq!(HARTREE, 4.3597447222071e-18);
+        assert_eq!(NEWTON_G, 6.67430e-11);
+    }
+
+    #[test]
+    fn test_derived_constants() {
+        // the CODATA 2018 values of the derived constants, to their relative precision.
+        let relative = |value: f64, expected: f64| (value / expected - 1.0).abs();
+        assert!(relative(HBAR, 1.054571817e-34) < 1e-9);
+        assert!(relative(COULOMB, 8.9875517923e9) < 1e-10);
+        assert!(relative(GAS_CONSTANT, 8.314462618) < 1e-10);
+        assert!(relative(FARADAY, 96485.33212) < 1e-10);
+        assert!(relative(KCAL_PER_MOL, 6.9477e-21) < 1e-4);
+        assert!(relative(BOLTZCONST / ELECTRONVOLT, 8.617333262e-5) < 1e-9);
+        // epsilon_0 mu_0 c^2 = 1
+        assert_approx_eq!(VACUUM_PERMITTIVITY * VACUUM_PERMEABILITY * C * C, 1.0, 1e-9);
+        // the atomic mass unit is 1 g/mol
+        assert_approx_eq!(AMU * AVOGADRO, 1e-3, 1e-12);
+    }
+}
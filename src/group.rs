@@ -0,0 +1,371 @@
+//! Atom groups, named sets of atoms chosen with a small selection language, e.g.
+//!
+//! `type Argon Neon`, `id 1:100 200`, `molecule 3:5`, `region pore`, `all`,
+//! `type Argon and not (id 1:10 or molecule 2)`.
+//!
+//! The id and molecule ranges are inclusive, `and` binds tighter than `or`, and both are
+//! left associative. The regions are looked up by name when the selection is parsed. A
+//! `Selection` picks the matching atoms of the world as a set of entities, for one-off
+//! changes such as freezing or restraining them, and the `GroupPlugin` names it, so that
+//! the thermostats, outputs and analyses act on the atoms of the group only. The
+//! membership of every atom is kept in a `GroupMask`, updated before the forces of each step,
+//! so atoms created later join the groups they match, and the groups of a region follow the
+//! atoms moving in and out of it.
+
+use crate::atom::*;
+use crate::region::{Region, Regions};
+use crate::simbox::SimBox;
+use bevy::prelude::*;
+
+/// what the selections know about an atom.
+#[derive(Clone, Copy)]
+pub struct AtomInfo<'a> {
+    pub id: &'a AtomID,
+    pub atom_type: Option<&'a AtomType>,
+    pub molecule: Option<&'a MoleculeID>,
+    pub position: Option<&'a Position>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Selection {
+    All,
+    Type(Vec<String>),
+    /// inclusive ranges of atom ids.
+    ID(Vec<(u64, u64)>),
+    /// inclusive ranges of molecule ids.
+    Molecule(Vec<(u64, u64)>),
+    Region(Region),
+    And(Box<Selection>, Box<Selection>),
+    Or(Box<Selection>, Box<Selection>),
+    Not(Box<Selection>),
+}
+
+impl Selection {
+    /// parses a selection without regions, the error tells what was expected.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        Self::parse_with_regions(text, &Regions::default())
+    }
+
+    /// parses a selection, with the regions it refers to.
+    pub fn parse_with_regions(text: &str, regions: &Regions) -> Result<Self, String> {
+        let spaced = text.replace('(', " ( ").replace(')', " ) ");
+        let tokens: Vec<&str> = spaced.split_whitespace().collect();
+        let mut parser = Parser { tokens, next: 0, regions };
+        let selection = parser.or()?;
+        match parser.peek() {
+            None => Ok(selection),
+            Some(token) => Err(format!("unexpected '{}' in selection '{}'", token, text)),
+        }
+    }
+
+    /// whether an atom in the box is selected.
+    pub fn matches(&self, atom: &AtomInfo, simbox: &SimBox) -> bool {
+        let in_ranges = |ranges: &Vec<(u64, u64)>, id: u64| ranges.iter().any(|&(first, last)| first <= id && id <= last);
+        match self {
+            Selection::All => true,
+            Selection::Type(names) => atom.atom_type.is_some_and(|atom_type| names.contains(&atom_type.name)),
+            Selection::ID(ranges) => in_ranges(ranges, atom.id.id),
+            Selection::Molecule(ranges) => atom.molecule.is_some_and(|molecule| in_ranges(ranges, molecule.id)),
+            Selection::Region(region) => atom.position.is_some_and(|pos| region.contains(&pos.pos, simbox)),
+            Selection::And(a, b) => a.matches(atom, simbox) && b.matches(atom, simbox),
+            Selection::Or(a, b) => a.matches(atom, simbox) || b.matches(atom, simbox),
+            Selection::Not(a) => !a.matches(atom, simbox),
+        }
+    }
+}
+
+const KEYWORDS: [&str; 10] = ["and", "or", "not", "all", "type", "id", "molecule", "region", "(", ")"];
+
+/// a recursive descent parser over the tokens of a selection.
+struct Parser<'a> {
+    tokens: Vec<&'a str>,
+    next: usize,
+    regions: &'a Regions,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.next).copied()
+    }
+
+    fn advance(&mut self) -> Option<&'a str> {
+        let token = self.peek();
+        self.next += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Selection, String> {
+        let mut selection = self.and()?;
+        while self.peek() == Some("or") {
+            self.advance();
+            selection = Selection::Or(Box::new(selection), Box::new(self.and()?));
+        }
+        Ok(selection)
+    }
+
+    fn and(&mut self) -> Result<Selection, String> {
+        let mut selection = self.not()?;
+        while self.peek() == Some("and") {
+            self.advance();
+            selection = Selection::And(Box::new(selection), Box::new(self.not()?));
+        }
+        Ok(selection)
+    }
+
+    fn not(&mut self) -> Result<Selection, String> {
+        match self.advance() {
+            Some("not") => Ok(Selection::Not(Box::new(self.not()?))),
+            Some("(") => {
+                let selection = self.or()?;
+                match self.advance() {
+                    Some(")") => Ok(selection),
+                    _ => Err(String::from("expected ')'")),
+                }
+            }
+            Some("all") => Ok(Selection::All),
+            Some("type") => Ok(Selection::Type(self.arguments("type")?.iter().map(|name| name.to_string()).collect())),
+            Some("id") => Ok(Selection::ID(self.ranges("id")?)),
+            Some("molecule") => Ok(Selection::Molecule(self.ranges("molecule")?)),
+            Some("region") => match self.advance().filter(|token| !KEYWORDS.contains(token)) {
+                Some(name) => match self.regions.get(name) {
+                    Some(region) => Ok(Selection::Region(region.clone())),
+                    None => Err(format!("there is no region {}", name)),
+                },
+                None => Err(String::from("'region' needs the name of a region")),
+            },
+            Some(token) => Err(format!("expected a selection, found '{}'", token)),
+            None => Err(String::from("expected a selection, found the end")),
+        }
+    }
+
+    /// the arguments of a keyword, up to the next keyword.
+    fn arguments(&mut self, keyword: &str) -> Result<Vec<&'a str>, String> {
+        let mut arguments = Vec::new();
+        while let Some(token) = self.peek().filter(|token| !KEYWORDS.contains(token)) {
+            arguments.push(token);
+            self.advance();
+        }
+        if arguments.is_empty() {
+            return Err(format!("'{}' needs at least one argument", keyword));
+        }
+        Ok(arguments)
+    }
+
+    fn ranges(&mut self, keyword: &str) -> Result<Vec<(u64, u64)>, String> {
+        let parse = |number: &str| number.parse::<u64>().map_err(|_| format!("'{}' is not an {}", number, keyword));
+        self.arguments(keyword)?
+            .iter()
+            .map(|argument| match argument.split_once(':') {
+                Some((first, last)) => Ok((parse(first)?, parse(last)?)),
+                None => parse(argument).map(|id| (id, id)),
+            })
+            .collect()
+    }
+}
+
+/// the entities of the atoms in the selection.
+pub fn select(world: &mut World, selection: &Selection) -> Vec<Entity> {
+    let simbox = world.get_resource::<SimBox>().copied().unwrap_or_default();
+    world
+        .query::<(Entity, &AtomID, Option<&AtomType>, Option<&MoleculeID>, Option<&Position>)>()
+        .iter(world)
+        .filter(|(_, id, atom_type, molecule, position)| {
+            selection.matches(&AtomInfo { id, atom_type: *atom_type, molecule: *molecule, position: *position }, &simbox)
+        })
+        .map(|(entity, ..)| entity)
+        .collect()
+}
+
+/// the groups an atom belongs to, one bit per group of `AtomGroups`.
+#[derive(Clone, Copy, Default, Component)]
+pub struct GroupMask {
+    pub mask: u64,
+}
+
+/// the named groups, at most 64.
+#[derive(Clone, Default)]
+pub struct AtomGroups {
+    pub groups: Vec<(String, Selection)>,
+}
+
+impl AtomGroups {
+    pub fn add(&mut self, name: String, selection: Selection) {
+        assert!(self.groups.len() < 64, "there can be at most 64 groups");
+        assert!(self.groups.iter().all(|(other, _)| *other != name), "the group {} is defined twice", name);
+        self.groups.push((name, selection));
+    }
+
+    /// the bit of a group in the `GroupMask`.
+    pub fn bit(&self, name: &str) -> u64 {
+        match self.groups.iter().position(|(other, _)| other == name) {
+            Some(index) => 1 << index,
+            None => panic!("there is no group {}", name),
+        }
+    }
+
+    /// the filter for the atoms of a group, or for all atoms.
+    pub fn filter(&self, name: Option<&str>) -> GroupFilter {
+        GroupFilter { bit: name.map(|name| self.bit(name)) }
+    }
+
+    fn mask(&self, atom: &AtomInfo, simbox: &SimBox) -> u64 {
+        self.groups
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, selection))| selection.matches(atom, simbox))
+            .fold(0, |mask, (index, _)| mask | 1 << index)
+    }
+}
+
+/// picks the atoms of one group, or all atoms.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GroupFilter {
+    bit: Option<u64>,
+}
+
+impl GroupFilter {
+    pub fn all() -> Self {
+        Self { bit: None }
+    }
+
+    pub fn matches(&self, mask: Option<&GroupMask>) -> bool {
+        match self.bit {
+            None => true,
+            Some(bit) => mask.is_some_and(|mask| mask.mask & bit != 0),
+        }
+    }
+}
+
+/// the filter for an optional group of an optional `AtomGroups`, as used by the systems
+/// which act on a group.
+pub fn group_filter(groups: &Option<Res<AtomGroups>>, name: &Option<String>) -> GroupFilter {
+    match (groups, name) {
+        (_, None) => GroupFilter::all(),
+        (Some(groups), Some(name)) => groups.filter(Some(name)),
+        (None, Some(name)) => panic!("there is no group {}, no GroupPlugin was added", name),
+    }
+}
+
+/// checks, while the plugins are built, that the group a plugin acts on was added before it.
+pub fn assert_group_defined(app: &App, name: &str) {
+    let groups = app.world.get_resource::<AtomGroups>()
+        .unwrap_or_else(|| panic!("there is no group {}, the GroupPlugin must be added before the plugins using it", name));
+    groups.bit(name);
+}
+
+type GroupQuery<'a> = (
+    Entity,
+    &'a AtomID,
+    Option<&'a AtomType>,
+    Option<&'a MoleculeID>,
+    Option<&'a Position>,
+    Option<&'a mut GroupMask>,
+);
+
+pub fn update_groups(
+    mut commands: Commands,
+    groups: Res<AtomGroups>,
+    simbox: Option<Res<SimBox>>,
+    mut query: Query<GroupQuery>,
+) {
+    let simbox = simbox.map_or_else(SimBox::default, |simbox| *simbox);
+    for (entity, id, atom_type, molecule, position, group_mask) in query.iter_mut() {
+        let mask = groups.mask(&AtomInfo { id, atom_type, molecule, position }, &simbox);
+        match group_mask {
+            Some(mut group_mask) => group_mask.mask = mask,
+            None => {
+                commands.entity(entity).insert(GroupMask { mask });
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Hash, Debug, Eq, SystemLabel)]
+pub enum GroupSystems {
+    UpdateGroups,
+}
+
+/// adds a named group, several groups are added with several plugins.
+pub struct GroupPlugin {
+    pub name: String,
+    pub selection: Selection,
+}
+
+impl GroupPlugin {
+    pub fn new(name: &str, selection: Selection) -> Self {
+        Self { name: String::from(name), selection }
+    }
+}
+
+impl Plugin for GroupPlugin {
+    fn build(&self, app: &mut App) {
+        // the first group adds the system, which updates the masks of all the groups.
+        if !app.world.contains_resource::<AtomGroups>() {
+            app.init_resource::<AtomGroups>();
+            app.add_system_to_stage(CoreStage::PreUpdate, update_groups.label(GroupSystems::UpdateGroups));
+        }
+        app.world.get_resource_mut::<AtomGroups>().unwrap().add(self.name.clone(), self.selection.clone());
+    }
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use nalgebra::Vector3;
+
+    #[test]
+    fn test_selection() {
+        let selection = Selection::parse("type Argon and not (id 1:10 or molecule 2)").unwrap();
+        assert_eq!(selection, Selection::And(
+            Box::new(Selection::Type(vec![String::from("Argon")])),
+            Box::new(Selection::Not(Box::new(Selection::Or(
+                Box::new(Selection::ID(vec![(1, 10)])),
+                Box::new(Selection::Molecule(vec![(2, 2)])),
+            )))),
+        ));
+        assert!(Selection::parse("type").is_err());
+        assert!(Selection::parse("id 1:x").is_err());
+        assert!(Selection::parse("(all").is_err());
+        assert!(Selection::parse("all all").is_err());
+        assert!(Selection::parse("region pore").is_err());
+
+        // twenty atoms in ten molecules, of two types
+        let mut app = App::new();
+        app.add_plugin(GroupPlugin::new("argon", Selection::parse("type Argon").unwrap()));
+        app.add_plugin(GroupPlugin::new("first", Selection::parse("id 1:5 15 or molecule 10").unwrap()));
+        // the atoms are on a line along x, at x = id
+        let mut regions = Regions::default();
+        regions.add("middle", Region::block(Vector3::new(9.5, 0.0, 0.0), Vector3::new(12.5, 1.0, 1.0)));
+        app.add_plugin(GroupPlugin::new("middle", Selection::parse_with_regions("region middle and not id 11", &regions).unwrap()));
+        app.world.insert_resource(SimBox::new(Vector3::zeros(), 30.0, 1.0, 1.0));
+        for id in 1..=20 {
+            let name = if id % 2 == 0 { "Argon" } else { "Neon" };
+            app.world.spawn()
+                .insert(AtomID { id })
+                .insert(Position { pos: Vector3::new(id as f64, 0.5, 0.5) })
+                .insert(AtomType::new(String::from(name), 1.0, 1.0))
+                .insert(MoleculeID { id: id.div_ceil(2) });
+        }
+        let selected = |app: &mut App, selection: &str| -> Vec<u64> {
+            let entities = select(&mut app.world, &Selection::parse(selection).unwrap());
+            let mut ids: Vec<u64> = entities.iter().map(|entity| app.world.get::<AtomID>(*entity).unwrap().id).collect();
+            ids.sort_unstable();
+            ids
+        };
+        assert_eq!(selected(&mut app, "type Argon and id 1:6"), vec![2, 4, 6]);
+        assert_eq!(selected(&mut app, "molecule 2 or id 20"), vec![3, 4, 20]);
+        assert_eq!(selected(&mut app, "not type Argon Neon"), Vec::<u64>::new());
+
+        app.update();
+        let groups = app.world.get_resource::<AtomGroups>().unwrap().clone();
+        let (argon, first, middle) = (groups.filter(Some("argon")), groups.filter(Some("first")), groups.filter(Some("middle")));
+        let mut query = app.world.query::<(&AtomID, &GroupMask)>();
+        for (atom_id, mask) in query.iter(&app.world) {
+            assert_eq!(argon.matches(Some(mask)), atom_id.id % 2 == 0);
+            assert_eq!(first.matches(Some(mask)), atom_id.id <= 5 || atom_id.id >= 19 || atom_id.id == 15);
+            assert_eq!(middle.matches(Some(mask)), atom_id.id == 10 || atom_id.id == 12);
+            assert!(GroupFilter::all().matches(Some(mask)));
+        }
+    }
+}
@@ -2,10 +2,15 @@ pub mod atom;
 pub mod constant;
 pub mod setup;
 pub mod simbox;
+pub mod group;
+pub mod region;
 pub mod output;
 pub mod bevy_bridge;
 pub mod lj_params;
 pub mod physical_quant_calc;
 pub mod molecular_dynamics;
 pub mod monte_carlo;
-pub mod energy_minimization;
\ No newline at end of file
+pub mod energy_minimization;
+pub mod runner;
+pub mod random;
+pub mod units;
\ No newline at end of file
@@ -0,0 +1,149 @@
+//! Adaptive time step control, for starting configurations with overlapping atoms which
+//! blow up with a fixed time step.
+//!
+//! The time step of each step is chosen so that no atom moves further than a given distance,
+//! with the displacement v dt + a dt^2 / 2 of velocity verlet, and is bounded by a minimum
+//! and a maximum time step. It is chosen at the start of `EndIntegration`, once the
+//! velocities of the step are complete and before the positions move, so the next velocity
+//! update completes the step with the same time step. The `SimTime` keeps track of the
+//! simulated time, as it is no longer `CurStep` times the time step.
+
+use crate::atom::*;
+use crate::molecular_dynamics::integration::*;
+use crate::output::checkpoint::Checkpoint;
+use crate::units::UnitSystem;
+use bevy::prelude::*;
+
+/// the largest distance an atom may move in one step, and the bounds of the time step.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptiveTimeStep {
+    pub max_displacement: f64,
+    pub min_dt: f64,
+    pub max_dt: f64,
+}
+
+impl AdaptiveTimeStep {
+    pub fn new(max_displacement: f64, min_dt: f64, max_dt: f64) -> Self {
+        assert!(min_dt <= max_dt, "the minimum time step is larger than the maximum time step");
+        Self { max_displacement, min_dt, max_dt }
+    }
+
+    /// the largest time step in which an atom with the given speed and acceleration moves
+    /// at most the maximum displacement, from v dt + a dt^2 / 2 = max_displacement.
+    pub fn atom_time_step(&self, speed: f64, acceleration: f64) -> f64 {
+        let d = self.max_displacement;
+        2.0 * d / (speed + (speed * speed + 2.0 * acceleration * d).sqrt())
+    }
+}
+
+pub fn adapt_time_step(
+    adaptive: Res<AdaptiveTimeStep>,
+    units: Res<UnitSystem>,
+    mut timestep: ResMut<TimeStep>,
+    query: Query<(&Velocity, &Force, &Mass), Without<Frozen>>,
+) {
+    let mass_conversion = units.mass_conversion();
+    let dt = query
+        .iter()
+        .map(|(vel, force, mass)| {
+            adaptive.atom_time_step(vel.vel.norm(), force.force.norm() / (mass_conversion * mass.value))
+        })
+        .fold(adaptive.max_dt, f64::min);
+    timestep.delta = dt.max(adaptive.min_dt);
+}
+
+#[derive(PartialEq, Clone, Hash, Debug, Eq, SystemLabel)]
+pub enum AdaptiveSystems {
+    AdaptTimeStep,
+}
+
+pub struct AdaptiveTimeStepPlugin {
+    pub adaptive: AdaptiveTimeStep,
+}
+
+impl AdaptiveTimeStepPlugin {
+    pub fn new(adaptive: AdaptiveTimeStep) -> Self {
+        Self { adaptive }
+    }
+}
+
+impl Plugin for AdaptiveTimeStepPlugin {
+    fn build(&self, app: &mut App) {
+        // when restarting, the next velocity update completes the step with the stored time step.
+        if let Some(time_step) = app.world.get_resource::<Checkpoint>().map(|checkpoint| checkpoint.time_step) {
+            app.world.insert_resource(TimeStep::new(time_step));
+        }
+        app.world.insert_resource(self.adaptive);
+        app.init_resource::<SimTime>();
+
+        // the time step is chosen before any system of the stage moves the atoms.
+        app.add_system_to_stage(IntegrationStages::EndIntegration,
+            adapt_time_step.exclusive_system().at_start().label(AdaptiveSystems::AdaptTimeStep));
+    }
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::molecular_dynamics::{energy::total_energy, lj_interaction::*};
+    #[allow(unused_imports)]
+    use crate::{setup::SetupPlugin, simbox::SimBox};
+    #[allow(unused_imports)]
+    use bevy::tasks::{ComputeTaskPool, TaskPool};
+    #[allow(unused_imports)]
+    use nalgebra::Vector3;
+
+    #[test]
+    fn test_adaptive_overlapping_pair() {
+        // two argon atoms at 0.8 sigma, in reduced units, which fly apart once released.
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin {
+            units: UnitSystem::argon_lj(),
+            time_step: TimeStep::new(0.005),
+            box_size: SimBox::new(Vector3::repeat(-50.0), 100.0, 100.0, 100.0),
+            lj_cutoff: LJCutOff::new(2.5),
+            ..default()
+        });
+        app.add_plugin(LJPlugin);
+        app.add_plugin(IntegrationPlugin);
+        app.add_plugin(AdaptiveTimeStepPlugin::new(AdaptiveTimeStep::new(0.002, 1e-5, 0.005)));
+        for (id, x) in [(1, -0.4), (2, 0.4)] {
+            app.world.spawn()
+                .insert(Position { pos: Vector3::new(x, 0.0, 0.0) })
+                .insert(Velocity { vel: Vector3::zeros() })
+                .insert(Force::default())
+                .insert(OldForce::default())
+                .insert(Mass { value: 1.0 })
+                .insert(AtomType::new(String::from("Argon"), 1.0, 1.0))
+                .insert(AtomID { id });
+        }
+
+        let separation = |app: &mut App| -> f64 {
+            let mut query = app.world.query::<&Position>();
+            let x: Vec<f64> = query.iter(&app.world).map(|pos| pos.pos.x).collect();
+            (x[0] - x[1]).abs()
+        };
+        let mut time = 0.0;
+        let mut energies = Vec::new();
+        let mut last = separation(&mut app);
+        for _ in 0..1000 {
+            app.update();
+            time += app.world.get_resource::<TimeStep>().unwrap().delta;
+            energies.push(total_energy(&app.world));
+            let current = separation(&mut app);
+            // each atom moves at most the maximum displacement
+            assert!(current - last <= 2.0 * 0.002 + 1e-12, "step of {}", current - last);
+            last = current;
+        }
+        assert!((app.world.get_resource::<SimTime>().unwrap().t - time).abs() < 1e-12);
+        // the energy is kept to a few parts per thousand, with the jump of the potential at the cutoff
+        assert!((energies[999] - energies[0]).abs() / energies[0] < 5e-3, "energy {} {}", energies[0], energies[999]);
+        // in free flight, the time step is the maximum displacement over the speed
+        let speed = app.world.query::<&Velocity>().iter(&app.world).next().unwrap().vel.norm();
+        let dt = app.world.get_resource::<TimeStep>().unwrap().delta;
+        assert!(last > 2.5, "separation {}", last);
+        assert!((dt * speed / 0.002 - 1.0).abs() < 1e-6, "time step {}", dt);
+    }
+}
@@ -0,0 +1,239 @@
+//! Pressure coupling by rescaling the box and the atom positions every step.
+//!
+//! The Berendsen barostat relaxes the pressure exponentially to the target, while the
+//! stochastic cell rescaling of Bernetti and Bussi, J. Chem. Phys. 153, 114107 (2020),
+//! adds a noise term so that the volume fluctuations of the isothermal-isobaric ensemble
+//! are sampled, and scales the velocities inversely to the positions.
+
+use crate::atom::*;
+use crate::molecular_dynamics::energy::PressureTensor;
+use crate::molecular_dynamics::integration::*;
+use crate::random::SimRng;
+use crate::simbox::SimBox;
+use crate::units::UnitSystem;
+use bevy::prelude::*;
+use bevy::tasks::ComputeTaskPool;
+use nalgebra::{Matrix3, Vector3};
+use rand_distr::{Distribution, StandardNormal};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BarostatKind {
+    /// Berendsen weak coupling.
+    Berendsen,
+    /// Bernetti-Bussi stochastic cell rescaling.
+    CRescale,
+}
+
+/// which box dimensions are scaled together.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PressureCoupling {
+    /// the three dimensions are scaled by the same factor, from the scalar pressure.
+    Isotropic,
+    /// x and y are scaled together from the mean of their pressures, and z on its own,
+    /// e.g. for membranes.
+    SemiIsotropic,
+    /// each dimension is scaled from its own diagonal pressure component.
+    Anisotropic,
+}
+
+impl PressureCoupling {
+    /// the groups of dimensions scaled by the same factor.
+    fn groups(&self) -> Vec<Vec<usize>> {
+        match self {
+            PressureCoupling::Isotropic => vec![vec![0, 1, 2]],
+            PressureCoupling::SemiIsotropic => vec![vec![0, 1], vec![2]],
+            PressureCoupling::Anisotropic => vec![vec![0], vec![1], vec![2]],
+        }
+    }
+}
+
+/// the barostat parameters, in the units of the simulation. The compressibility is in
+/// inverse pressure units, and the temperature is only used by the stochastic cell
+/// rescaling, it should be the target temperature of the thermostat.
+#[derive(Clone, Copy)]
+pub struct Barostat {
+    pub kind: BarostatKind,
+    pub coupling: PressureCoupling,
+    pub target: f64,
+    pub tau: f64,
+    pub compressibility: f64,
+    pub temperature: f64,
+}
+
+impl Barostat {
+    pub fn berendsen(target: f64, tau: f64, compressibility: f64) -> Self {
+        Self {
+            kind: BarostatKind::Berendsen,
+            coupling: PressureCoupling::Isotropic,
+            target,
+            tau,
+            compressibility,
+            temperature: 0.0,
+        }
+    }
+
+    pub fn c_rescale(target: f64, temperature: f64, tau: f64, compressibility: f64) -> Self {
+        Self {
+            kind: BarostatKind::CRescale,
+            coupling: PressureCoupling::Isotropic,
+            target,
+            tau,
+            compressibility,
+            temperature,
+        }
+    }
+
+    pub fn with_coupling(mut self, coupling: PressureCoupling) -> Self {
+        self.coupling = coupling;
+        self
+    }
+
+    /// The factors the box dimensions are scaled by in one time step.
+    pub fn scaling_factors<R: rand::Rng>(&self, pressure: &Matrix3<f64>, volume: f64, kb: f64, dt: f64, rng: &mut R) -> Vector3<f64> {
+        let mut mu = Vector3::new(1.0, 1.0, 1.0);
+        let beta = self.compressibility;
+        for group in self.coupling.groups() {
+            let n = group.len() as f64;
+            let p = group.iter().map(|&d| pressure[(d, d)]).sum::<f64>() / n;
+            let factor = match self.kind {
+                BarostatKind::Berendsen => 1.0 - beta * dt / (3.0 * self.tau) * (self.target - p),
+                BarostatKind::CRescale => {
+                    let kt = kb * self.temperature;
+                    let noise: f64 = StandardNormal.sample(rng);
+                    let deterministic = -beta * dt / self.tau * (self.target - p - kt / volume) / 3.0;
+                    let stochastic = (n * 2.0 * kt * beta * dt / (self.tau * volume) / 3.0).sqrt() / n * noise;
+                    (deterministic + stochastic).exp()
+                }
+            };
+            for &d in group.iter() {
+                mu[d] = factor;
+            }
+        }
+        mu
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn apply_barostat(
+    pool: Res<ComputeTaskPool>,
+    batch_size: Res<BatchSize>,
+    barostat: Res<Barostat>,
+    timestep: Res<TimeStep>,
+    units: Res<UnitSystem>,
+    pressure: Res<PressureTensor>,
+    mut simbox: ResMut<SimBox>,
+    mut sim_rng: ResMut<SimRng>,
+    mut query: Query<(&mut Position, &mut Velocity), Without<Frozen>>,
+) {
+    let mu = barostat.scaling_factors(&pressure.tensor, simbox.volume(), units.boltzmann(), timestep.delta, &mut sim_rng.rng);
+    let origin = simbox.origin;
+    simbox.scale(&mu);
+
+    let scale_velocities = barostat.kind == BarostatKind::CRescale;
+    query.par_for_each_mut(
+        &pool,
+        batch_size.0,
+        |(mut pos, mut vel)| {
+            // the atoms stay in the box as it is scaled around its origin.
+            pos.pos = origin + (pos.pos - origin).component_mul(&mu);
+            if scale_velocities {
+                vel.vel = vel.vel.component_div(&mu);
+            }
+        }
+    );
+}
+
+#[derive(PartialEq, Clone, Hash, Debug, Eq, SystemLabel)]
+pub enum BarostatSystems {
+    Barostat,
+    ParrinelloRahman,
+}
+
+pub struct BarostatPlugin {
+    pub barostat: Barostat,
+}
+
+impl BarostatPlugin {
+    pub fn new(barostat: Barostat) -> Self {
+        Self { barostat }
+    }
+}
+
+impl Plugin for BarostatPlugin {
+    fn build(&self, app: &mut App) {
+        assert!(!matches!(app.world.get_resource::<Integrator>(), Some(Integrator::MTK(_))),
+            "the MTK integrator has its own barostat, the BarostatPlugin cannot be added to it");
+        app.world.insert_resource(self.barostat);
+        app.init_resource::<PressureTensor>();
+
+        // the box is scaled once the positions of the next step are known,
+        // with the pressure evaluated in this step.
+        app.add_system_to_stage(IntegrationStages::EndIntegration,
+            apply_barostat.label(BarostatSystems::Barostat)
+                .after(IntegrationSystems::VelocityVerletIntegratePosition)
+                .after(IntegrationSystems::LeapfrogIntegratePosition)
+                .after(IntegrationSystems::PositionVerletIntegratePosition)
+                .after(IntegrationSystems::LangevinIntegrate)
+                .after(IntegrationSystems::RespaIntegrate)
+                .after(IntegrationSystems::BrownianIntegrate)
+                .before(IntegrationSystems::ClearForce));
+    }
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::molecular_dynamics::thermostat::*;
+    #[allow(unused_imports)]
+    use crate::setup::SetupPlugin;
+    #[allow(unused_imports)]
+    use crate::molecular_dynamics::integration::tests::spawn_ideal_gas;
+    #[allow(unused_imports)]
+    use bevy::tasks::TaskPool;
+
+    /// an ideal argon gas at 120 K, compressed to twice its initial pressure.
+    #[allow(dead_code)]
+    fn compressed_gas_app(barostat: Barostat) -> App {
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin { time_step: TimeStep::new(2e-15), ..default() });
+        app.add_plugin(IntegrationPlugin);
+        app.add_plugin(ThermostatPlugin::new(Thermostat::velocity_rescale(120.0, 2e-14)));
+        app.add_plugin(BarostatPlugin::new(barostat));
+        spawn_ideal_gas(&mut app, 200.0);
+        app
+    }
+
+    #[test]
+    fn test_ideal_gas_compression() {
+        let kt = crate::constant::BOLTZCONST * 120.0;
+        let target = 2.0 * 100.0 * kt / 1e-24;
+        // the ideal gas volume, with the momentum of the center of mass removed.
+        let expected = 99.0 * kt / target;
+
+        let mut app = compressed_gas_app(Barostat::berendsen(target, 2e-13, 1.0 / target));
+        let mut mean_volume = 0.0;
+        for i in 0..2000 {
+            app.update();
+            if i >= 1000 {
+                mean_volume += app.world.get_resource::<SimBox>().unwrap().volume() / 1000.0;
+            }
+        }
+        assert!((mean_volume / expected - 1.0).abs() < 0.05, "berendsen volume {}", mean_volume / expected);
+
+        let barostat = Barostat::c_rescale(target, 120.0, 2e-13, 1.0 / target)
+            .with_coupling(PressureCoupling::Anisotropic);
+        let mut app = compressed_gas_app(barostat);
+        let mut mean_volume = 0.0;
+        for i in 0..2000 {
+            app.update();
+            if i >= 1000 {
+                mean_volume += app.world.get_resource::<SimBox>().unwrap().volume() / 1000.0;
+            }
+        }
+        assert!((mean_volume / expected - 1.0).abs() < 0.1, "c-rescale volume {}", mean_volume / expected);
+        let simbox = app.world.get_resource::<SimBox>().unwrap();
+        assert!(simbox.dimension.x != simbox.dimension.y);
+    }
+}
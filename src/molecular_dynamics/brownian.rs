@@ -0,0 +1,174 @@
+//! Overdamped Brownian dynamics, for colloids whose velocities relax much faster than their
+//! positions change.
+//!
+//! The inertia is neglected, so the `Velocity` of the atoms is not used at all and the
+//! positions follow the forces through the mobility D / kT, with the Euler-Maruyama step
+//!
+//! x(t+dt) = x(t) + D F(t) / kT dt + sqrt(2 D dt) xi,
+//!
+//! where D is the diffusion coefficient of the atom and xi a vector of standard Gaussian
+//! random numbers. Atoms without velocities add nothing to the kinetic energy and temperature.
+
+use crate::atom::*;
+use crate::molecular_dynamics::integration::*;
+use crate::random::{RngPurpose, SimRng};
+use crate::simbox::SimBox;
+use crate::units::UnitSystem;
+use bevy::prelude::*;
+use bevy::tasks::ComputeTaskPool;
+use nalgebra::Vector3;
+use rand_distr::{Distribution, StandardNormal};
+
+/// the temperature of the solvent, and the diffusion coefficient (in length^2 / time) of the
+/// atoms without a `Diffusion` of their own.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BrownianParams {
+    pub temperature: f64,
+    pub diffusion: f64,
+}
+
+impl BrownianParams {
+    pub fn new(temperature: f64, diffusion: f64) -> Self {
+        Self { temperature, diffusion }
+    }
+}
+
+/// the diffusion coefficient of an atom, e.g. of a colloid of a given size.
+#[derive(Clone, Copy, Component)]
+pub struct Diffusion {
+    pub coefficient: f64,
+}
+
+impl Diffusion {
+    pub fn new(coefficient: f64) -> Self {
+        Self { coefficient }
+    }
+
+    /// the diffusion coefficient kT / gamma of an atom with the friction coefficient gamma,
+    /// in force / velocity units.
+    pub fn from_friction(friction: f64, temperature: f64, units: &UnitSystem) -> Self {
+        Self { coefficient: units.boltzmann() * temperature / friction }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn brownian_integrate(
+    pool: Res<ComputeTaskPool>,
+    batch_size: Res<BatchSize>,
+    timestep: Res<TimeStep>,
+    params: Res<BrownianParams>,
+    simbox: Res<SimBox>,
+    sim_rng: Res<SimRng>,
+    mut cur_step: ResMut<CurStep>,
+    units: Res<UnitSystem>,
+    mut query: Query<(&mut Position, &Force, Option<&Diffusion>, &AtomID), Without<Frozen>>,
+) {
+    let dt = timestep.delta;
+    let kt = units.boltzmann() * params.temperature;
+    let step = cur_step.n;
+
+    query.par_for_each_mut(
+        &pool,
+        batch_size.0,
+        |(mut pos, force, diffusion, atom_id)| {
+            let d = diffusion.map_or(params.diffusion, |diffusion| diffusion.coefficient);
+            let mut rng = sim_rng.atom_rng(RngPurpose::Brownian, step, atom_id.id);
+            let noise = Vector3::new(
+                StandardNormal.sample(&mut rng),
+                StandardNormal.sample(&mut rng),
+                StandardNormal.sample(&mut rng),
+            );
+            pos.pos += d * force.force / kt * dt + (2.0 * d * dt).sqrt() * noise;
+            // the velocities are not used, only the positions are mirrored at reflective faces
+            simbox.apply_boundaries(&mut pos.pos, &mut Vector3::zeros());
+        }
+    );
+    cur_step.n += 1;
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::setup::SetupPlugin;
+    #[allow(unused_imports)]
+    use crate::output::checkpoint::tests::{argon_app, assert_restart_matches};
+    #[allow(unused_imports)]
+    use bevy::tasks::TaskPool;
+
+    /// a constant force along x on every atom.
+    #[allow(dead_code)]
+    fn push(mut query: Query<&mut Force>) {
+        for mut force in query.iter_mut() {
+            force.force.x += 2.0;
+        }
+    }
+
+    #[test]
+    fn test_brownian_drift_and_diffusion() {
+        // in reduced units, at kT = 1 with D = 0.5 the atoms drift with the velocity D F / kT = 1.
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(
+            SetupPlugin {
+                units: UnitSystem::argon_lj(),
+                time_step: TimeStep::new(0.01),
+                box_size: SimBox::new(Vector3::repeat(-100.0), 200.0, 200.0, 200.0),
+                ..default()
+            }
+            .with_integrator(Integrator::Brownian(BrownianParams::new(1.0, 0.5)))
+        );
+        app.add_plugin(IntegrationPlugin);
+        app.add_stage_before(CoreStage::Update, "push", SystemStage::parallel());
+        app.add_system_to_stage("push", push);
+        for i in 0..500 {
+            // the second half of the atoms diffuses twice as fast
+            let mut atom = app.world.spawn();
+            atom.insert(Position::default())
+                .insert(Force::default())
+                .insert(OldForce::default())
+                .insert(AtomID { id: i + 1 });
+            if i >= 250 {
+                atom.insert(Diffusion::new(1.0));
+            }
+        }
+
+        for _ in 0..500 {
+            app.update();
+        }
+        // after t = 5, the displacement along x is D F t / kT on average, and its variance 2 D t
+        let mut query = app.world.query::<(&Position, Option<&Diffusion>)>();
+        for d in [0.5, 1.0] {
+            let x: Vec<f64> = query.iter(&app.world)
+                .filter(|(_, diffusion)| diffusion.map_or(0.5, |diffusion| diffusion.coefficient) == d)
+                .map(|(pos, _)| pos.pos.x)
+                .collect();
+            let mean = x.iter().sum::<f64>() / x.len() as f64;
+            let variance = x.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (x.len() - 1) as f64;
+            assert!((mean - d * 2.0 * 5.0).abs() < 0.5, "D = {}, mean displacement {}", d, mean);
+            assert!((variance / (2.0 * d * 5.0) - 1.0).abs() < 0.25, "D = {}, variance {}", d, variance);
+        }
+    }
+
+    #[test]
+    fn test_brownian_restart_matches_uninterrupted_run() {
+        // a third of the atoms diffuses with its own coefficient, which the checkpoint keeps
+        let (mut app, mut restarted) = assert_restart_matches("brownian_restart_test", |dir, restart| {
+            argon_app(dir, restart, Integrator::Brownian(BrownianParams::new(120.0, 2e-9)), |_| {})
+        }, |id, atom| {
+            if id % 3 == 0 {
+                atom.insert(Diffusion::new(5e-9));
+            }
+        });
+        let diffusion = |app: &mut App| {
+            let mut query = app.world.query::<(&AtomID, Option<&Diffusion>)>();
+            let mut diffusion: Vec<_> = query
+                .iter(&app.world)
+                .map(|(id, diffusion)| (id.id, diffusion.map(|diffusion| diffusion.coefficient)))
+                .collect();
+            diffusion.sort_by_key(|d| d.0);
+            diffusion
+        };
+        assert_eq!(diffusion(&mut app), diffusion(&mut restarted));
+    }
+}
@@ -0,0 +1,168 @@
+//! Dissipative particle dynamics, following Groot and Warren, J. Chem. Phys. 107, 4423 (1997).
+//!
+//! Each pair of atoms closer than the cutoff interacts with a soft conservative repulsion,
+//! a dissipative force damping their relative velocity and a random force, all along the
+//! line joining them, so the total momentum is conserved. With w(r) = 1 - r/rc,
+//!
+//! F_C = a w(r) e, F_D = -gamma w(r)^2 (e . v_ij) e, F_R = sigma w(r) theta_ij e / sqrt(dt),
+//!
+//! where sigma^2 = 2 gamma kT and theta_ij is a Gaussian random number shared by the pair,
+//! drawn from the stream of the pair for the step.
+//! The dissipative force depends on the velocities, which are only complete once the
+//! forces are known, so the modified velocity verlet scheme evaluates it with the predicted
+//! velocities v + lambda F dt / m. The rest of the step is the usual velocity verlet.
+
+use crate::atom::*;
+use crate::molecular_dynamics::energy::{PotentialEnergy, Virial};
+use crate::molecular_dynamics::integration::*;
+use crate::molecular_dynamics::lj_interaction::*;
+use crate::random::{RngPurpose, SimRng};
+use crate::simbox::SimBox;
+use crate::units::UnitSystem;
+use bevy::prelude::*;
+use rand_distr::{Distribution, StandardNormal};
+
+/// the parameters of the DPD interaction, in the units of the simulation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DPDParams {
+    /// the maximum conservative repulsion, at zero separation.
+    pub a: f64,
+    /// the friction coefficient of the dissipative force.
+    pub gamma: f64,
+    /// the temperature the random and dissipative forces keep the system at.
+    pub temperature: f64,
+    /// the cutoff distance of all three forces.
+    pub rc: f64,
+    /// the weight of the force in the predicted velocities, 0.5 gives velocity verlet.
+    pub lambda: f64,
+}
+
+impl DPDParams {
+    pub fn new(a: f64, gamma: f64, temperature: f64, rc: f64) -> Self {
+        Self { a, gamma, temperature, rc, lambda: 0.65 }
+    }
+
+    pub fn with_lambda(mut self, lambda: f64) -> Self {
+        self.lambda = lambda;
+        self
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn calc_dpd_force(
+    params: Res<DPDParams>,
+    timestep: Res<TimeStep>,
+    box_size: Res<SimBox>,
+    units: Res<UnitSystem>,
+    sim_rng: Res<SimRng>,
+    cur_step: Res<CurStep>,
+    mut potential: ResMut<PotentialEnergy>,
+    mut virial: ResMut<Virial>,
+    mut query: Query<(&mut Force, &OldForce, &Position, &Velocity, &Mass, &AtomID)>,
+) {
+    let dt = timestep.delta;
+    let mass_conversion = units.mass_conversion();
+    let sigma = (2.0 * params.gamma * units.boltzmann() * params.temperature).sqrt();
+
+    let mut dpd_energy = 0.0;
+
+    for_each_pair_within(&mut query, &box_size, params.rc, |(_, _, pos, ..)| pos.pos,
+        |(mut force1, old_force1, _, vel1, mass1, id1), (mut force2, old_force2, _, vel2, mass2, id2), r1, r_square| {
+            if r_square == 0.0 {
+                return;
+            }
+            let r = r_square.sqrt();
+            let e = r1 / r;
+            let w = 1.0 - r / params.rc;
+
+            // the predicted velocities of the modified velocity verlet scheme
+            let v1 = vel1.vel + params.lambda * old_force1.0.force / (mass_conversion * mass1.value) * dt;
+            let v2 = vel2.vel + params.lambda * old_force2.0.force / (mass_conversion * mass2.value) * dt;
+
+            let theta: f64 = StandardNormal.sample(&mut sim_rng.pair_rng(RngPurpose::DPD, cur_step.n, id1.id, id2.id));
+            let conservative = params.a * w;
+            let dissipative = -params.gamma * w * w * e.dot(&(v1 - v2));
+            let random = sigma * w * theta / dt.sqrt();
+
+            let pair_force = (conservative + dissipative + random) * e;
+            force1.force += pair_force;
+            force2.force -= pair_force;
+            virial.tensor += r1 * pair_force.transpose();
+
+            dpd_energy += 0.5 * params.a * params.rc * w * w;
+        });
+    potential.value += dpd_energy;
+}
+
+pub struct DPDPlugin {
+    pub params: DPDParams,
+}
+
+impl DPDPlugin {
+    pub fn new(params: DPDParams) -> Self {
+        Self { params }
+    }
+}
+
+impl Plugin for DPDPlugin {
+    fn build(&self, app: &mut App) {
+        app.world.insert_resource(self.params);
+        app.init_resource::<PotentialEnergy>();
+        app.init_resource::<Virial>();
+        add_force_stage(app);
+        // both force systems add to the forces of the same atoms, so they run one after the other.
+        app.add_system_to_stage(ForceStages::LJStage,
+            calc_dpd_force.label(ForceSystems::DPDSystem).after(ForceSystems::LJSystem));
+    }
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::{molecular_dynamics::energy::Temperature, setup::SetupPlugin};
+    #[allow(unused_imports)]
+    use bevy::tasks::{ComputeTaskPool, TaskPool};
+    #[allow(unused_imports)]
+    use nalgebra::Vector3;
+
+    #[test]
+    fn test_dpd_temperature_and_momentum() {
+        // the DPD fluid of Groot and Warren at density 3, in reduced units.
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin {
+            units: UnitSystem::argon_lj(),
+            time_step: TimeStep::new(0.04),
+            box_size: SimBox::new(Vector3::new(0.0, 0.0, 0.0), 4.0, 4.0, 4.0),
+            ..default()
+        });
+        app.add_plugin(DPDPlugin::new(DPDParams::new(25.0, 4.5, 1.0, 1.0)));
+        app.add_plugin(IntegrationPlugin);
+        for i in 0..192 {
+            let x = i as f64;
+            app.world.spawn()
+                .insert(Position { pos: Vector3::new((i % 4) as f64, ((i / 4) % 4) as f64, (i / 16) as f64 / 3.0) })
+                .insert(Velocity { vel: Vector3::new(0.5 * x.sin(), 0.5 * x.cos(), 0.5 * (2.0 * x).sin()) })
+                .insert(Force::default())
+                .insert(OldForce::default())
+                .insert(Mass { value: 1.0 })
+                .insert(AtomID { id: i + 1 });
+        }
+        let momentum = |app: &mut App| -> Vector3<f64> {
+            let mut query = app.world.query::<&Velocity>();
+            query.iter(&app.world).map(|vel| vel.vel).sum()
+        };
+        let initial_momentum = momentum(&mut app);
+
+        let mut mean_temperature = 0.0;
+        for i in 0..1000 {
+            app.update();
+            if i >= 200 {
+                mean_temperature += app.world.get_resource::<Temperature>().unwrap().value / 800.0;
+            }
+        }
+        assert!((momentum(&mut app) - initial_momentum).norm() < 1e-9);
+        assert!((mean_temperature - 1.0).abs() < 0.05, "mean temperature {}", mean_temperature);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::atom::*;
+use crate::molecular_dynamics::restraint::PositionRestraint;
+use crate::simbox::SimBox;
+use crate::units::UnitSystem;
+use bevy::prelude::*;
+use nalgebra::Matrix3;
+
+/// the potential energy of the system in the current step, force systems
+/// add their contribution to it while evaluating the forces.
+#[derive(Clone, Copy, Default)]
+pub struct PotentialEnergy {
+    pub value: f64,
+}
+
+/// the kinetic energy of the system, evaluated after the velocity update.
+#[derive(Clone, Copy, Default)]
+pub struct KineticEnergy {
+    pub value: f64,
+}
+
+/// the instantaneous temperature of the system, evaluated from the kinetic energy.
+#[derive(Clone, Copy, Default)]
+pub struct Temperature {
+    pub value: f64,
+}
+
+/// the energy exchanged with the thermostats and barostats, so that the kinetic,
+/// potential and extended energies add up to a conserved quantity.
+#[derive(Clone, Copy, Default)]
+pub struct ExtendedEnergy {
+    pub value: f64,
+}
+
+/// the virial tensor of the current step, the sum of r_ij f_ij^T over the interacting
+/// pairs, where r_ij = r_i - r_j and f_ij is the force atom j exerts on atom i.
+/// Force systems add their contribution to it like to the potential energy.
+#[derive(Clone, Copy)]
+pub struct Virial {
+    pub tensor: Matrix3<f64>,
+}
+
+impl Default for Virial {
+    fn default() -> Self {
+        Self { tensor: Matrix3::zeros() }
+    }
+}
+
+/// the instantaneous pressure tensor, (sum of m v v^T + virial) / volume.
+#[derive(Clone, Copy)]
+pub struct PressureTensor {
+    pub tensor: Matrix3<f64>,
+}
+
+impl PressureTensor {
+    /// the scalar pressure, a third of the trace of the tensor.
+    pub fn pressure(&self) -> f64 {
+        self.tensor.trace() / 3.0
+    }
+}
+
+impl Default for PressureTensor {
+    fn default() -> Self {
+        Self { tensor: Matrix3::zeros() }
+    }
+}
+
+/// the total energy of the system, i.e. kinetic plus potential energy.
+pub fn total_energy(world: &World) -> f64 {
+    let kinetic = world.get_resource::<KineticEnergy>().map_or(0.0, |e| e.value);
+    let potential = world.get_resource::<PotentialEnergy>().map_or(0.0, |e| e.value);
+    kinetic + potential
+}
+
+/// the conserved energy of the system, the total energy plus the extended energy.
+pub fn conserved_energy(world: &World) -> f64 {
+    let extended = world.get_resource::<ExtendedEnergy>().map_or(0.0, |e| e.value);
+    total_energy(world) + extended
+}
+
+/// the atoms held to the lab frame, frozen or restrained to reference positions. While
+/// there are any, the total momentum of the other atoms is not conserved.
+pub type Anchored = Or<(With<Frozen>, With<PositionRestraint>)>;
+
+/// the number of degrees of freedom of n atoms, less the three of the total momentum
+/// if it is conserved.
+pub fn degrees_of_freedom(n_atoms: usize, momentum_conserved: bool) -> f64 {
+    if momentum_conserved && n_atoms > 1 {
+        (3 * n_atoms - 3) as f64
+    } else {
+        (3 * n_atoms) as f64
+    }
+}
+
+/// the temperature corresponding to the given kinetic energy.
+pub fn kinetic_temperature(kinetic: f64, n_dof: f64, units: &UnitSystem) -> f64 {
+    if n_dof > 0.0 {
+        2.0 * kinetic / (n_dof * units.boltzmann())
+    } else {
+        0.0
+    }
+}
+
+pub fn clear_potential_energy(mut potential: ResMut<PotentialEnergy>) {
+    potential.value = 0.0;
+}
+
+pub fn clear_virial(mut virial: ResMut<Virial>) {
+    virial.tensor = Matrix3::zeros();
+}
+
+/// evaluates the kinetic energy and the temperature, and the pressure tensor, whose
+/// kinetic part is evaluated with the same velocities.
+#[allow(clippy::too_many_arguments)]
+pub fn calc_kinetic_energy(
+    mut kinetic: ResMut<KineticEnergy>,
+    mut temperature: ResMut<Temperature>,
+    mut pressure: ResMut<PressureTensor>,
+    virial: Res<Virial>,
+    simbox: Res<SimBox>,
+    units: Res<UnitSystem>,
+    query: Query<(&Velocity, &Mass), Without<Frozen>>,
+    anchored: Query<(), Anchored>,
+) {
+    let mass_conversion = units.mass_conversion();
+    let kinetic_tensor: Matrix3<f64> = query
+        .iter()
+        .map(|(vel, mass)| mass_conversion * mass.value * vel.vel * vel.vel.transpose())
+        .sum();
+    kinetic.value = 0.5 * kinetic_tensor.trace();
+    let n_dof = degrees_of_freedom(query.iter().count(), anchored.is_empty());
+    temperature.value = kinetic_temperature(kinetic.value, n_dof, &units);
+    pressure.tensor = (kinetic_tensor + virial.tensor) / simbox.volume();
+}
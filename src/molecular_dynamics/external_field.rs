@@ -0,0 +1,260 @@
+//! External fields, which add a force on each atom independent of the other atoms: a uniform
+//! gravity on the `Mass` of the atoms, an electric field, constant or oscillating in time, on
+//! their `Charge`, and an arbitrary force given by a user function. A field can be limited to
+//! the atoms inside a region of the box.
+//!
+//! Like the `fix addforce`, `fix gravity` and `fix efield` of LAMMPS, the fields add neither to
+//! the potential energy nor to the virial, as the position of an atom in a periodic box does
+//! not define its energy in the field.
+
+use crate::atom::*;
+use crate::constant;
+use crate::molecular_dynamics::integration::SimTime;
+use crate::molecular_dynamics::lj_interaction::*;
+use crate::region::Region;
+use crate::simbox::SimBox;
+use crate::units::{Quantity, UnitSystem};
+use bevy::prelude::*;
+use nalgebra::Vector3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExternalField {
+    /// a uniform gravitational acceleration, in length / time^2.
+    Gravity { acceleration: Vector3<f64> },
+    /// the electric field amplitude * cos(2 pi frequency t + phase), in force per elementary
+    /// charge, with a frequency of 0 for a constant field.
+    Electric { amplitude: Vector3<f64>, frequency: f64, phase: f64 },
+}
+
+impl ExternalField {
+    /// the standard gravity, along the given direction.
+    pub fn standard_gravity(direction: Vector3<f64>, units: &UnitSystem) -> Self {
+        let length = units.si_value(Quantity::Length);
+        let time = units.si_value(Quantity::Time);
+        ExternalField::Gravity { acceleration: direction.normalize() * constant::GC * time * time / length }
+    }
+
+    /// a constant electric field.
+    pub fn electric(field: Vector3<f64>) -> Self {
+        ExternalField::Electric { amplitude: field, frequency: 0.0, phase: 0.0 }
+    }
+
+    /// an electric field given in V/m, converted to force per elementary charge.
+    pub fn electric_si(field: Vector3<f64>, units: &UnitSystem) -> Self {
+        Self::electric(field.map(|e| units.from_si(Quantity::Force, constant::ELEMCHARGE * e)))
+    }
+
+    /// an electric field oscillating with the given frequency, in 1 / time.
+    pub fn oscillating_electric(amplitude: Vector3<f64>, frequency: f64, phase: f64) -> Self {
+        ExternalField::Electric { amplitude, frequency, phase }
+    }
+
+    /// the force of the field at the time t on an atom with the given mass and charge.
+    pub fn force(&self, t: f64, mass: Option<&Mass>, charge: Option<&Charge>, units: &UnitSystem) -> Vector3<f64> {
+        match *self {
+            ExternalField::Gravity { acceleration } => {
+                mass.map_or(Vector3::zeros(), |mass| units.mass_conversion() * mass.value * acceleration)
+            }
+            ExternalField::Electric { amplitude, frequency, phase } => {
+                let field = amplitude * (2.0 * constant::PI * frequency * t + phase).cos();
+                charge.map_or(Vector3::zeros(), |charge| charge.value * field)
+            }
+        }
+    }
+}
+
+/// all the external fields of the simulation, with the regions they act in.
+#[derive(Clone, Default)]
+pub struct ExternalFields {
+    pub fields: Vec<(ExternalField, Option<Region>)>,
+}
+
+/// the force on an atom, from its id and position at the simulated time.
+pub type UserForceFn = dyn Fn(u64, &Vector3<f64>, f64) -> Vector3<f64> + Send + Sync;
+
+/// a force on each atom, given by a user function.
+pub struct UserForce {
+    pub force: Box<UserForceFn>,
+}
+
+impl UserForce {
+    pub fn new(force: impl Fn(u64, &Vector3<f64>, f64) -> Vector3<f64> + Send + Sync + 'static) -> Self {
+        Self { force: Box::new(force) }
+    }
+}
+
+pub fn calc_external_field_force(
+    fields: Res<ExternalFields>,
+    sim_time: Res<SimTime>,
+    units: Res<UnitSystem>,
+    simbox: Res<SimBox>,
+    mut query: Query<(&mut Force, &Position, Option<&Mass>, Option<&Charge>)>,
+) {
+    for (mut force, pos, mass, charge) in query.iter_mut() {
+        for (field, region) in fields.fields.iter() {
+            if region.as_ref().is_none_or(|region| region.contains(&pos.pos, &simbox)) {
+                force.force += field.force(sim_time.t, mass, charge, &units);
+            }
+        }
+    }
+}
+
+pub fn calc_user_force(
+    user_force: Res<UserForce>,
+    sim_time: Res<SimTime>,
+    mut query: Query<(&mut Force, &Position, &AtomID)>,
+) {
+    for (mut force, pos, atom_id) in query.iter_mut() {
+        force.force += (user_force.force)(atom_id.id, &pos.pos, sim_time.t);
+    }
+}
+
+/// adds an external field, several fields are added with several plugins.
+pub struct ExternalFieldPlugin {
+    pub field: ExternalField,
+    pub region: Option<Region>,
+}
+
+impl ExternalFieldPlugin {
+    pub fn new(field: ExternalField) -> Self {
+        Self { field, region: None }
+    }
+
+    /// the field only acts on the atoms inside the region.
+    pub fn in_region(mut self, region: Region) -> Self {
+        self.region = Some(region);
+        self
+    }
+}
+
+impl Plugin for ExternalFieldPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SimTime>();
+        add_force_stage(app);
+
+        // the fields are kept with their regions in `ExternalFields`, so that several of them,
+        // e.g. gravity and an electric field, act through one force system.
+        if !app.world.contains_resource::<ExternalFields>() {
+            app.init_resource::<ExternalFields>();
+            app.add_system_to_stage(ForceStages::LJStage,
+                calc_external_field_force.label(ForceSystems::ExternalFieldSystem)
+                    .after(ForceSystems::LJSystem)
+                    .after(ForceSystems::DPDSystem)
+                    .after(ForceSystems::WallSystem));
+        }
+        app.world.get_resource_mut::<ExternalFields>().unwrap().fields.push((self.field, self.region.clone()));
+    }
+}
+
+/// adds the force of a user function, only one can be added.
+pub struct UserForcePlugin;
+
+impl Plugin for UserForcePlugin {
+    fn build(&self, app: &mut App) {
+        assert!(app.world.contains_resource::<UserForce>(), "the UserForce resource must be inserted before the UserForcePlugin");
+        app.init_resource::<SimTime>();
+        add_force_stage(app);
+        app.add_system_to_stage(ForceStages::LJStage,
+            calc_user_force.label(ForceSystems::UserForceSystem)
+                .after(ForceSystems::LJSystem)
+                .after(ForceSystems::DPDSystem)
+                .after(ForceSystems::WallSystem)
+                .after(ForceSystems::ExternalFieldSystem));
+    }
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::molecular_dynamics::integration::*;
+    #[allow(unused_imports)]
+    use crate::{setup::SetupPlugin, simbox::SimBox};
+    #[allow(unused_imports)]
+    use crate::output::checkpoint::tests::{argon_app, assert_restart_matches};
+    #[allow(unused_imports)]
+    use bevy::tasks::{ComputeTaskPool, TaskPool};
+    #[allow(unused_imports)]
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_external_fields() {
+        // in SI units, three atoms of 1 kg fall with the standard gravity, while the charged
+        // one follows an oscillating field along x and the third is pulled by a user spring.
+        let units = UnitSystem::SI;
+        let dt = 1e-3;
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin {
+            units,
+            time_step: TimeStep::new(dt),
+            box_size: SimBox::new(Vector3::repeat(-100.0), 200.0, 200.0, 200.0),
+            ..default()
+        });
+        app.add_plugin(ExternalFieldPlugin::new(ExternalField::standard_gravity(-Vector3::z(), &units)));
+        let omega = 2.0 * constant::PI;
+        app.add_plugin(ExternalFieldPlugin::new(ExternalField::oscillating_electric(Vector3::x(), 1.0, 0.0)));
+        // a field in a far away part of the box, which none of the atoms reaches
+        app.add_plugin(ExternalFieldPlugin::new(ExternalField::electric(Vector3::repeat(1e3)))
+            .in_region(Region::sphere(Vector3::repeat(50.0), 10.0)));
+        app.insert_resource(UserForce::new(|id, pos, _| if id == 3 { Vector3::new(-pos.x, 0.0, 0.0) } else { Vector3::zeros() }));
+        app.add_plugin(UserForcePlugin);
+        app.add_plugin(IntegrationPlugin);
+        for id in 1..=3 {
+            let mut atom = app.world.spawn();
+            atom.insert(Position { pos: if id == 3 { Vector3::x() } else { Vector3::zeros() } })
+                .insert(Velocity { vel: Vector3::zeros() })
+                .insert(Force::default())
+                .insert(OldForce::default())
+                .insert(Mass { value: 1.0 / constant::AMU })
+                .insert(AtomID { id });
+            if id == 2 {
+                atom.insert(Charge { value: 1.0 });
+            }
+        }
+
+        // one second of motion
+        for _ in 0..1000 {
+            app.update();
+        }
+        let t = app.world.get_resource::<SimTime>().unwrap().t;
+        assert_approx_eq!(t, 1.0, 1e-9);
+        let mut query = app.world.query::<(&Position, &AtomID)>();
+        for (pos, atom_id) in query.iter(&app.world) {
+            let expected = match atom_id.id {
+                // every atom falls the same distance g t^2 / 2
+                1 => Vector3::new(0.0, 0.0, -constant::GC * t * t / 2.0),
+                // x = (1 - cos(omega t)) / omega^2 for a unit force
+                2 => Vector3::new((1.0 - (omega * t).cos()) / (omega * omega), 0.0, -constant::GC * t * t / 2.0),
+                // a harmonic oscillator with omega = 1
+                _ => Vector3::new(t.cos(), 0.0, -constant::GC * t * t / 2.0),
+            };
+            assert!((pos.pos - expected).norm() < 1e-5, "atom {} at {:?}, expected {:?}", atom_id.id, pos.pos, expected);
+        }
+    }
+
+    #[test]
+    fn test_electric_field_restart_matches_uninterrupted_run() {
+        // the field keeps acting on the charged atoms after the restart
+        let (mut app, mut restarted) = assert_restart_matches("field_restart_test", |dir, restart| {
+            argon_app(dir, restart, Integrator::VelocityVerlet, |app| {
+                let units = *app.world.get_resource::<UnitSystem>().unwrap();
+                app.add_plugin(ExternalFieldPlugin::new(ExternalField::electric_si(Vector3::new(1e9, 0.0, 0.0), &units)));
+            })
+        }, |id, atom| {
+            if id % 2 == 1 {
+                atom.insert(Charge { value: 1.0 });
+            }
+        });
+        let charges = |app: &mut App| {
+            let mut query = app.world.query::<(&AtomID, Option<&Charge>)>();
+            let mut charges: Vec<_> = query
+                .iter(&app.world)
+                .map(|(id, charge)| (id.id, charge.map(|charge| charge.value)))
+                .collect();
+            charges.sort_by_key(|c| c.0);
+            charges
+        };
+        assert_eq!(charges(&mut app), charges(&mut restarted));
+    }
+}
@@ -1,6 +1,13 @@
 use crate::atom::*;
-use crate::constant;
 use crate::simbox::*;
+use crate::units::UnitSystem;
+use crate::molecular_dynamics::brownian::*;
+use crate::molecular_dynamics::energy::*;
+use crate::molecular_dynamics::langevin::*;
+use crate::molecular_dynamics::mtk::*;
+use crate::molecular_dynamics::nose_hoover::NoseHooverSystems;
+use crate::molecular_dynamics::respa::*;
+use crate::output::checkpoint::Checkpoint;
 use bevy::prelude::*;
 use bevy::tasks::ComputeTaskPool;
 use nalgebra::Vector3;
@@ -20,6 +27,12 @@ impl CurStep {
 
 
 
+/// the simulated time, which is only `CurStep` times the `TimeStep` when the time step is fixed.
+#[derive(Clone, Copy, Default)]
+pub struct SimTime {
+    pub t: f64,
+}
+
 #[derive(Clone, Copy)]
 pub struct Step {
     pub n: u64,
@@ -59,6 +72,31 @@ impl Default for TimeStep {
 #[derive(Component, Default)]
 pub struct OldForce(pub Force);
 
+/// the integrator used by the `IntegrationPlugin`, chosen in the `SetupPlugin`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Integrator {
+    /// velocity verlet, for constant energy simulations.
+    #[default]
+    VelocityVerlet,
+    /// the leapfrog scheme of GROMACS. The `Velocity` of the atoms is the half step velocity
+    /// v(t - dt/2) of the positions x(t), and the initial velocities are taken as v(-dt/2).
+    /// The kinetic energy is evaluated with the full step velocities (v(t - dt/2) + v(t + dt/2)) / 2.
+    Leapfrog,
+    /// position verlet, with the forces evaluated at the half step positions. The `Position`
+    /// of the atoms between two updates is the half step position x(t + dt/2) of the
+    /// velocities v(t), and the kinetic energy is evaluated with the velocities at the half step.
+    PositionVerlet,
+    /// Langevin dynamics with the BAOAB splitting.
+    Langevin(LangevinParams),
+    /// isothermal-isobaric dynamics with the MTK barostat and Nosé-Hoover chains.
+    MTK(MTKParams),
+    /// the r-RESPA multiple time step integrator, with the given loop counts of the inner levels.
+    Respa(RespaParams),
+    /// overdamped Brownian dynamics, moving the positions with the forces and the noise
+    /// of the solvent, without velocities.
+    Brownian(BrownianParams),
+}
+
 pub const INTEGRATE_POSITION_SYSTEM_NAME: &str = "integrate_position";
 
 #[derive(Clone, Copy)]
@@ -77,70 +115,204 @@ impl Default for BatchSize {
 
 
 
+/// x(t+dt) = x(t) + v(t) dt + f(t)/2m dt^2, using the full step velocity of the step.
 fn velocity_verlet_integrate_position (
     pool: Res<ComputeTaskPool>,
     batch_size: Res<BatchSize>,
     timestep: ResMut<TimeStep>,
     simbox: Res<SimBox>,
-    mut query: Query<(&mut Position, &Velocity, &Force, &Mass)>,
+    mut cur_step: ResMut<CurStep>,
+    units: Res<UnitSystem>,
+    mut query: Query<(&mut Position, &mut Velocity, &Force, &Mass), Without<Frozen>>,
 ) {
 
     let dt = timestep.delta;
+    let mass_conversion = units.mass_conversion();
     query.par_for_each_mut(
         &pool,
         batch_size.0,
-        |(mut pos, vel, force, mass)|{
-            pos.pos = pos.pos + vel.vel * dt + force.force/(constant::AMU*mass.value) / 2.0 * dt * dt;
-
-            // to deal with the pbc
-            pos.pos.x = pbc(pos.pos.x, simbox.origin.x, simbox.dimension.x);
-            pos.pos.y = pbc(pos.pos.y, simbox.origin.y, simbox.dimension.y);
-            pos.pos.z = pbc(pos.pos.z, simbox.origin.z, simbox.dimension.z);
-
+        |(mut pos, mut vel, force, mass)|{
+            let kick = force.force / (mass_conversion * mass.value) / 2.0 * dt;
+            pos.pos = pos.pos + vel.vel * dt + kick * dt;
+
+            // to deal with the boundaries, an atom bouncing off a reflective face moved with the
+            // half step velocity v + kick, which is the one mirrored. The velocity update adds the
+            // kick of the old force again, so it is taken off the mirrored velocity.
+            let mirror = simbox.apply_boundaries(&mut pos.pos, &mut vel.vel);
+            vel.vel -= kick.component_mul(&(Vector3::repeat(1.0) - mirror));
         }
     );
+    cur_step.n += 1;
 }
 
 
 
-fn pbc (coord: f64, min: f64, range:f64) -> f64 {
-    let max = min + range;
-    let _coord = coord - min;
-    let mut new_coord = 0.0;
-    if _coord < 0.0 {
-
-        new_coord = max - (-_coord % range);
-    }
-    else {
-        new_coord = min +  (_coord % range);
+/// the half kick with the new forces closing the step of the integrators whose last half kick
+/// is done in the next update, skipped in the very first step.
+pub fn half_kick(
+    pool: Res<ComputeTaskPool>,
+    batch_size: Res<BatchSize>,
+    timestep: Res<TimeStep>,
+    cur_step: Res<CurStep>,
+    units: Res<UnitSystem>,
+    mut query: Query<(&mut Velocity, &Force, &Mass), Without<Frozen>>,
+) {
+    if cur_step.n == 0 {
+        return;
     }
+    let dt = timestep.delta;
+    let mass_conversion = units.mass_conversion();
+    query.par_for_each_mut(
+        &pool,
+        batch_size.0,
+        |(mut vel, force, mass)| {
+            vel.vel += force.force / (mass_conversion * mass.value) / 2.0 * dt;
+        }
+    );
+}
+
+/// v = v + f/2m dt, from v(t - dt/2) to v(t) and then from v(t) to v(t + dt/2), without
+/// skipping the first step as the initial velocities are at the half step already.
+fn leapfrog_half_kick(
+    pool: Res<ComputeTaskPool>,
+    batch_size: Res<BatchSize>,
+    timestep: Res<TimeStep>,
+    units: Res<UnitSystem>,
+    mut query: Query<(&mut Velocity, &Force, &Mass), Without<Frozen>>,
+) {
+    let dt = timestep.delta;
+    let mass_conversion = units.mass_conversion();
+    query.par_for_each_mut(
+        &pool,
+        batch_size.0,
+        |(mut vel, force, mass)| {
+            vel.vel += force.force / (mass_conversion * mass.value) / 2.0 * dt;
+        }
+    );
+}
 
-    new_coord
+/// x(t+dt) = x(t) + v(t+dt/2) dt.
+fn leapfrog_integrate_position(
+    pool: Res<ComputeTaskPool>,
+    batch_size: Res<BatchSize>,
+    timestep: Res<TimeStep>,
+    simbox: Res<SimBox>,
+    mut cur_step: ResMut<CurStep>,
+    mut query: Query<(&mut Position, &mut Velocity), Without<Frozen>>,
+) {
+    let dt = timestep.delta;
+    query.par_for_each_mut(
+        &pool,
+        batch_size.0,
+        |(mut pos, mut vel)| {
+            pos.pos += vel.vel * dt;
+            simbox.apply_boundaries(&mut pos.pos, &mut vel.vel);
+        }
+    );
+    cur_step.n += 1;
+}
+
+/// x(t+3dt/2) = x(t+dt/2) + v(t+dt) dt, the two half drifts around the forces of position
+/// verlet. The very first step starts from the initial positions, so it drifts by half a step.
+fn position_verlet_integrate_position(
+    pool: Res<ComputeTaskPool>,
+    batch_size: Res<BatchSize>,
+    timestep: Res<TimeStep>,
+    simbox: Res<SimBox>,
+    mut cur_step: ResMut<CurStep>,
+    mut query: Query<(&mut Position, &mut Velocity), Without<Frozen>>,
+) {
+    let dt = if cur_step.n == 0 { timestep.delta / 2.0 } else { timestep.delta };
+    query.par_for_each_mut(
+        &pool,
+        batch_size.0,
+        |(mut pos, mut vel)| {
+            pos.pos += vel.vel * dt;
+            simbox.apply_boundaries(&mut pos.pos, &mut vel.vel);
+        }
+    );
+    cur_step.n += 1;
 }
 
 pub const INTEGRATE_VELOCITY_SYSTEM_NAME: &str = "integrate_velocity";
 
+/// v(t) = v(t-dt) + (f(t-dt) + f(t))/2m dt, completing the velocity of the previous step
+/// once the forces of the current positions are known. In the very first step the
+/// velocities are the initial velocities already, so they are left untouched.
 fn velocity_verlet_integrate_velocity (
     pool: Res<ComputeTaskPool>,
     batch_size: Res<BatchSize>,
     timestep: Res<TimeStep>,
-    mut cur_step: ResMut<CurStep>,
-    mut query: Query<(&mut Velocity, &Force, &OldForce, &Mass)>,
+    cur_step: Res<CurStep>,
+    units: Res<UnitSystem>,
+    mut query: Query<(&mut Velocity, &Force, &OldForce, &Mass), Without<Frozen>>,
 ) {
+    if cur_step.n == 0 {
+        return;
+    }
     let dt = timestep.delta;
+    let mass_conversion = units.mass_conversion();
     //println!("integration running!");
     query.par_for_each_mut (
         &pool,
         batch_size.0,
         |(mut vel, force, old_force, mass)| {
-            vel.vel += (force.force + old_force.0.force) / (constant::AMU * mass.value) / 2.0 * dt;
+            vel.vel += (force.force + old_force.0.force) / (mass_conversion * mass.value) / 2.0 * dt;
         }    
     );
-    cur_step.n += 1;
 }
 
 
 
+/// moves the faces of the shrink-wrapped axes of the box to the extent of the atoms,
+/// once they have moved.
+fn shrink_wrap_box(mut simbox: ResMut<SimBox>, margin: Res<ShrinkWrapMargin>, query: Query<&Position>) {
+    if !simbox.boundary.contains(&Boundary::ShrinkWrapped) || query.is_empty() {
+        return;
+    }
+    let mut low = Vector3::repeat(f64::MAX);
+    let mut high = Vector3::repeat(f64::MIN);
+    for pos in query.iter() {
+        low = low.inf(&pos.pos);
+        high = high.sup(&pos.pos);
+    }
+    simbox.shrink_wrap(&low, &high, &margin);
+}
+
+/// advances the simulated time by the time step of this step.
+fn advance_sim_time(timestep: Res<TimeStep>, mut sim_time: ResMut<SimTime>) {
+    sim_time.t += timestep.delta;
+}
+
+/// the atoms spawned since the last update have no forces of the previous step, so the first
+/// velocity update closing the previous step would give them the half kick f(t)/2m dt on top of
+/// the velocity v(t) they are given. The kick is taken off beforehand, except with leapfrog,
+/// whose velocities are half step velocities the kick belongs to, and brownian dynamics.
+fn prepare_new_atoms(world: &mut World) {
+    let new_atoms: Vec<Entity> = world.query_filtered::<Entity, With<NewAtom>>().iter(world).collect();
+    if new_atoms.is_empty() {
+        return;
+    }
+    let kicked = world.get_resource::<CurStep>().map_or(0, |cur_step| cur_step.n) > 0
+        && !matches!(world.get_resource::<Integrator>(), Some(Integrator::Leapfrog) | Some(Integrator::Brownian(_)));
+    let dt = world.get_resource::<TimeStep>().map_or(0.0, |timestep| timestep.delta);
+    let mass_conversion = world.get_resource::<UnitSystem>().map_or(1.0, |units| units.mass_conversion());
+    for entity in new_atoms {
+        let mut atom = world.entity_mut(entity);
+        atom.remove::<NewAtom>();
+        if !kicked {
+            continue;
+        }
+        let (force, mass) = match (atom.get::<Force>(), atom.get::<Mass>()) {
+            (Some(force), Some(mass)) => (force.force, mass.value),
+            _ => continue,
+        };
+        if let Some(mut vel) = atom.get_mut::<Velocity>() {
+            vel.vel -= force / (mass_conversion * mass) / 2.0 * dt;
+        }
+    }
+}
+
 fn clear_force (
     mut query: Query<(&mut Force, &mut OldForce)>,
     pool: Res<ComputeTaskPool>,
@@ -161,8 +333,24 @@ fn clear_force (
 pub enum IntegrationSystems {
     VelocityVerletIntegratePosition,
     VelocityVerletIntegrateVelocity,
-    AddOldForceToNewAtoms,
+    PrepareNewAtoms,
     ClearForce,
+    ClearPotentialEnergy,
+    ClearVirial,
+    CalcKineticEnergy,
+    AdvanceSimTime,
+    ShrinkWrapBox,
+    HalfKick,
+    LeapfrogHalfStepKick,
+    LeapfrogIntegratePosition,
+    PositionVerletSecondKick,
+    PositionVerletIntegratePosition,
+    LangevinIntegrate,
+    MTKCloseStep,
+    MTKOpenStep,
+    MTKIntegrate,
+    RespaIntegrate,
+    BrownianIntegrate,
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, StageLabel)]
@@ -174,27 +362,332 @@ pub enum IntegrationStages {
 pub struct IntegrationPlugin;
 impl Plugin for IntegrationPlugin {
     fn build(&self, app: &mut App) {
-        // we add two stages after core stage for the position update and velocity update respectively
+        // we add two stages after core stage for the velocity update and position update respectively,
+        // the forces of the current positions are evaluated before them, so the velocities of the
+        // previous step are completed first, and the positions are moved with the full step velocities.
         app.add_stage_after(CoreStage::Update, IntegrationStages::BeginIntegration, SystemStage::parallel());
         app.add_stage_after(IntegrationStages::BeginIntegration, IntegrationStages::EndIntegration, SystemStage::parallel());
         
 
-        // we add the position updating system to the begin integration stage
-        app.add_system_to_stage(IntegrationStages::BeginIntegration, 
-            velocity_verlet_integrate_position.label(IntegrationSystems::VelocityVerletIntegratePosition));
+        let integrator = app.world.get_resource::<Integrator>().cloned().unwrap_or_default();
+        match integrator {
+            Integrator::VelocityVerlet => {
+                //  we add the velocity updating system to the begin integration stage
+                app.add_system_to_stage(IntegrationStages::BeginIntegration,
+                    velocity_verlet_integrate_velocity.label(IntegrationSystems::VelocityVerletIntegrateVelocity));
+
+                // we add the position updating system to the end integration stage
+                app.add_system_to_stage(IntegrationStages::EndIntegration, 
+                    velocity_verlet_integrate_position.label(IntegrationSystems::VelocityVerletIntegratePosition));
+
+                // then we store the current force to old force then clear the current force after position updating
+                app.add_system_to_stage(IntegrationStages::EndIntegration, 
+                    clear_force.label(IntegrationSystems::ClearForce).after(IntegrationSystems::VelocityVerletIntegratePosition));
+            }
+            Integrator::Leapfrog => {
+                // the kick from v(t - dt/2) to v(t + dt/2) is split in two halves around the
+                // kinetic energy, which is then evaluated with the full step velocities.
+                app.add_system_to_stage(IntegrationStages::BeginIntegration,
+                    leapfrog_half_kick.label(IntegrationSystems::HalfKick));
+                app.add_system_to_stage(IntegrationStages::BeginIntegration,
+                    leapfrog_half_kick.label(IntegrationSystems::LeapfrogHalfStepKick)
+                        .after(IntegrationSystems::CalcKineticEnergy)
+                        .after(NoseHooverSystems::OpenStep));
+                app.add_system_to_stage(IntegrationStages::EndIntegration,
+                    leapfrog_integrate_position.label(IntegrationSystems::LeapfrogIntegratePosition));
+                app.add_system_to_stage(IntegrationStages::EndIntegration, 
+                    clear_force.label(IntegrationSystems::ClearForce).after(IntegrationSystems::LeapfrogIntegratePosition));
+            }
+            Integrator::PositionVerlet => {
+                // the kick from v(t) to v(t + dt) is split in the same way, so that the kinetic
+                // energy is evaluated at the half step, like the potential energy.
+                app.add_system_to_stage(IntegrationStages::BeginIntegration,
+                    half_kick.label(IntegrationSystems::HalfKick));
+                app.add_system_to_stage(IntegrationStages::BeginIntegration,
+                    half_kick.label(IntegrationSystems::PositionVerletSecondKick)
+                        .after(IntegrationSystems::CalcKineticEnergy)
+                        .after(NoseHooverSystems::OpenStep));
+                app.add_system_to_stage(IntegrationStages::EndIntegration,
+                    position_verlet_integrate_position.label(IntegrationSystems::PositionVerletIntegratePosition));
+                app.add_system_to_stage(IntegrationStages::EndIntegration, 
+                    clear_force.label(IntegrationSystems::ClearForce).after(IntegrationSystems::PositionVerletIntegratePosition));
+            }
+            Integrator::Langevin(params) => {
+                app.world.insert_resource(params);
+                app.add_system_to_stage(IntegrationStages::BeginIntegration,
+                    half_kick.label(IntegrationSystems::HalfKick));
+                app.add_system_to_stage(IntegrationStages::EndIntegration,
+                    langevin_integrate.label(IntegrationSystems::LangevinIntegrate));
+                app.add_system_to_stage(IntegrationStages::EndIntegration, 
+                    clear_force.label(IntegrationSystems::ClearForce).after(IntegrationSystems::LangevinIntegrate));
+            }
+            Integrator::MTK(params) => {
+                // when restarting, the cell and the chains continue from the checkpoint.
+                let state = app.world.get_resource::<Checkpoint>()
+                    .and_then(|checkpoint| checkpoint.mtk.clone())
+                    .unwrap_or_else(|| MTKState::new(&params));
+                assert_eq!(state.particle_chain.xi.len(), params.thermostat.chain_length, "the chain length differs from the checkpoint");
+                app.world.insert_resource(params);
+                app.world.insert_resource(state);
+                app.init_resource::<ExtendedEnergy>();
+                app.add_system_to_stage(IntegrationStages::BeginIntegration,
+                    mtk_close_step.label(IntegrationSystems::MTKCloseStep));
+                app.add_system_to_stage(IntegrationStages::BeginIntegration,
+                    mtk_open_step.label(IntegrationSystems::MTKOpenStep).after(IntegrationSystems::CalcKineticEnergy));
+                app.add_system_to_stage(IntegrationStages::EndIntegration,
+                    mtk_integrate.label(IntegrationSystems::MTKIntegrate));
+                app.add_system_to_stage(IntegrationStages::EndIntegration, 
+                    clear_force.label(IntegrationSystems::ClearForce).after(IntegrationSystems::MTKIntegrate));
+            }
+            Integrator::Respa(params) => {
+                app.world.insert_resource(RespaStages {
+                    stages: (0..params.loops.len()).map(|_| SystemStage::single_threaded()).collect(),
+                });
+                app.world.insert_resource(params);
+                app.init_resource::<RespaState>();
+                app.add_system_to_stage(IntegrationStages::BeginIntegration,
+                    half_kick.label(IntegrationSystems::HalfKick));
+                app.add_system_to_stage(IntegrationStages::EndIntegration,
+                    respa_integrate.exclusive_system().label(IntegrationSystems::RespaIntegrate));
+                app.add_system_to_stage(IntegrationStages::EndIntegration, 
+                    clear_force.label(IntegrationSystems::ClearForce).after(IntegrationSystems::RespaIntegrate));
+            }
+            Integrator::Brownian(params) => {
+                app.world.insert_resource(params);
+                app.add_system_to_stage(IntegrationStages::EndIntegration,
+                    brownian_integrate.label(IntegrationSystems::BrownianIntegrate));
+                app.add_system_to_stage(IntegrationStages::EndIntegration, 
+                    clear_force.label(IntegrationSystems::ClearForce).after(IntegrationSystems::BrownianIntegrate));
+            }
+        }
 
-        //  we add the velocity updating system to the end integration stage
+        // the potential energy and the virial are accumulated by the force systems, so they are
+        // cleared before they run, and the kinetic energy and the pressure are evaluated once
+        // the velocities are updated.
+        app.init_resource::<PotentialEnergy>();
+        app.init_resource::<KineticEnergy>();
+        app.init_resource::<Temperature>();
+        app.init_resource::<Virial>();
+        app.init_resource::<PressureTensor>();
+        app.init_resource::<SimTime>();
+        app.add_system_to_stage(CoreStage::PreUpdate,
+            clear_potential_energy.label(IntegrationSystems::ClearPotentialEnergy));
+        app.add_system_to_stage(CoreStage::PreUpdate,
+            clear_virial.label(IntegrationSystems::ClearVirial));
+        app.add_system_to_stage(IntegrationStages::BeginIntegration,
+            calc_kinetic_energy.label(IntegrationSystems::CalcKineticEnergy)
+                .after(IntegrationSystems::VelocityVerletIntegrateVelocity)
+                .after(IntegrationSystems::HalfKick)
+                .after(IntegrationSystems::MTKCloseStep));
         app.add_system_to_stage(IntegrationStages::EndIntegration,
-            velocity_verlet_integrate_velocity.label(IntegrationSystems::VelocityVerletIntegrateVelocity));
-
-        // then we store the current force to old force then clear the current force after velocity updating
-        app.add_system_to_stage(IntegrationStages::EndIntegration, 
-            clear_force.label(IntegrationSystems::ClearForce).after(IntegrationSystems::VelocityVerletIntegrateVelocity));
+            advance_sim_time.label(IntegrationSystems::AdvanceSimTime));
+        // every integrator stores the forces once the atoms have moved, as do the barostats
+        // before it, so the box is shrink-wrapped after that.
+        app.add_system_to_stage(IntegrationStages::EndIntegration,
+            shrink_wrap_box.label(IntegrationSystems::ShrinkWrapBox).after(IntegrationSystems::ClearForce));
 
-        // This is only useful when we need to add atoms during the simulation,
-        // which we are not implementing yet.
-        //app.add_system_to_stage(IntegrationStages::BeginIntegration, 
-        //    add_old_force_to_new_atoms.label(IntegrationSystems::AddOldForceToNewAtoms));
+        // the atoms added during the run join in with the velocities they are given.
+        app.add_system_to_stage(IntegrationStages::BeginIntegration,
+            prepare_new_atoms.exclusive_system().at_start().label(IntegrationSystems::PrepareNewAtoms));
     }
 }
 
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::setup::SetupPlugin;
+    #[allow(unused_imports)]
+    use bevy::tasks::TaskPool;
+
+    /// spawns an ideal gas of 100 argon atoms on a plane of the box, with speeds of about the
+    /// given one.
+    #[allow(dead_code)]
+    pub fn spawn_ideal_gas(app: &mut App, speed: f64) {
+        for i in 0..100 {
+            let x = i as f64;
+            app.world.spawn()
+                .insert(Position { pos: Vector3::new(x % 10.0, (x / 10.0).floor(), 0.5) * 1e-9 })
+                .insert(Velocity { vel: Vector3::new(speed * x.sin(), speed * x.cos(), speed * (2.0 * x).sin()) })
+                .insert(Force::default())
+                .insert(OldForce::default())
+                .insert(Mass { value: 39.948 })
+                .insert(AtomID { id: i + 1 });
+        }
+    }
+
+    /// a harmonic tether of every atom to the origin, with a period of 2 pi.
+    #[allow(dead_code)]
+    fn tether(mut potential: ResMut<PotentialEnergy>, mut query: Query<(&mut Force, &Position)>) {
+        for (mut force, pos) in query.iter_mut() {
+            force.force -= pos.pos;
+            potential.value += 0.5 * pos.pos.norm_squared();
+        }
+    }
+
+    #[test]
+    fn test_leapfrog_and_position_verlet() {
+        for integrator in [Integrator::Leapfrog, Integrator::PositionVerlet] {
+            let mut app = App::new();
+            app.insert_resource(ComputeTaskPool(TaskPool::new()));
+            app.add_plugin(
+                SetupPlugin {
+                    units: UnitSystem::argon_lj(),
+                    time_step: TimeStep::new(0.01),
+                    box_size: SimBox::new(Vector3::repeat(-10.0), 20.0, 20.0, 20.0),
+                    ..default()
+                }
+                .with_integrator(integrator.clone())
+            );
+            app.add_plugin(IntegrationPlugin);
+            app.add_stage_before(CoreStage::Update, "tether", SystemStage::parallel());
+            app.add_system_to_stage("tether", tether);
+            app.world.spawn()
+                .insert(Position { pos: Vector3::new(1.0, 0.0, 0.0) })
+                .insert(Velocity { vel: Vector3::new(0.0, 1.0, 0.0) })
+                .insert(Force::default())
+                .insert(OldForce::default())
+                .insert(Mass { value: 1.0 });
+
+            let mut energies = Vec::new();
+            for _ in 0..1000 {
+                app.update();
+                energies.push(total_energy(&app.world));
+            }
+            let max = energies.iter().cloned().fold(f64::MIN, f64::max);
+            let min = energies.iter().cloned().fold(f64::MAX, f64::min);
+            assert!((max - min) / energies[0] < 1e-4, "{:?} energy fluctuation {}", integrator, (max - min) / energies[0]);
+
+            // the atom goes around a circle of radius 1, the positions of position verlet are
+            // half a step ahead of the number of steps done, and leapfrog lags a little as its
+            // initial velocity is taken half a step earlier.
+            let t: f64 = match integrator {
+                Integrator::PositionVerlet => 999.5 * 0.01,
+                _ => 1000.0 * 0.01,
+            };
+            let pos = app.world.query::<&Position>().iter(&app.world).next().unwrap().pos;
+            assert!((pos - Vector3::new(t.cos(), t.sin(), 0.0)).norm() < 1e-2, "{:?} position {:?}", integrator, pos);
+        }
+    }
+
+    #[test]
+    fn test_boundaries_in_integration() {
+        // free atoms in a box which is periodic along x, reflective along y and shrink-wrapped along z
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin {
+            units: UnitSystem::argon_lj(),
+            time_step: TimeStep::new(0.01),
+            box_size: SimBox::new(Vector3::zeros(), 2.0, 2.0, 2.0)
+                .with_boundary([Boundary::Periodic, Boundary::Reflective, Boundary::ShrinkWrapped]),
+            ..default()
+        });
+        app.add_plugin(IntegrationPlugin);
+        for (i, vel) in [Vector3::new(1.0, 1.0, 1.0), Vector3::new(-1.0, -0.5, -2.0)].iter().enumerate() {
+            app.world.spawn()
+                .insert(Position { pos: Vector3::repeat(1.0) })
+                .insert(Velocity { vel: *vel })
+                .insert(Force::default())
+                .insert(OldForce::default())
+                .insert(Mass { value: 1.0 })
+                .insert(AtomID { id: i as u64 + 1 });
+        }
+
+        for _ in 0..150 {
+            app.update();
+            let simbox = *app.world.get_resource::<SimBox>().unwrap();
+            for pos in app.world.query::<&Position>().iter(&app.world) {
+                assert!(pos.pos.x >= 0.0 && pos.pos.x < 2.0);
+                assert!(pos.pos.y >= 0.0 && pos.pos.y <= 2.0);
+            }
+            // after t, the atoms are at z = 1 + t and z = 1 - 2t, and the faces 2e-4 further
+            let t = app.world.get_resource::<SimTime>().unwrap().t;
+            assert!((simbox.origin.z - (1.0 - 2.0 * t - 2e-4)).abs() < 1e-9);
+            assert!((simbox.dimension.z - (3.0 * t + 4e-4)).abs() < 1e-9);
+        }
+        // the first atom went through y = 2 and bounced back once
+        let vel: Vec<Vector3<f64>> = app.world.query::<&Velocity>().iter(&app.world).map(|vel| vel.vel).collect();
+        assert_eq!(vel[0], Vector3::new(1.0, -1.0, 1.0));
+        assert_eq!(vel[1], Vector3::new(-1.0, -0.5, -2.0));
+    }
+
+    #[test]
+    fn test_shrink_wrapped_single_atom() {
+        // a single atom has no extent, along the shrink-wrapped z axis of a tilted box whose
+        // wrapping along x and y goes through the fractional z coordinate.
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin {
+            units: UnitSystem::argon_lj(),
+            time_step: TimeStep::new(0.01),
+            box_size: SimBox::triclinic(Vector3::zeros(), Vector3::repeat(2.0), Vector3::new(0.5, 0.5, 0.5))
+                .with_boundary([Boundary::Periodic, Boundary::Periodic, Boundary::ShrinkWrapped]),
+            ..default()
+        });
+        app.add_plugin(IntegrationPlugin);
+        app.world.spawn()
+            .insert(Position { pos: Vector3::repeat(1.0) })
+            .insert(Velocity { vel: Vector3::new(1.0, 0.5, 0.0) })
+            .insert(Force::default())
+            .insert(OldForce::default())
+            .insert(Mass { value: 1.0 });
+
+        for _ in 0..300 {
+            app.update();
+        }
+        let simbox = *app.world.get_resource::<SimBox>().unwrap();
+        assert!((simbox.origin.z - (1.0 - 2e-4)).abs() < 1e-12 && (simbox.dimension.z - 4e-4).abs() < 1e-12, "{:?}", simbox);
+        let pos = app.world.query::<&Position>().iter(&app.world).next().unwrap().pos;
+        // the atom went 3 along x and 1.5 along y, wrapped into the box
+        assert!((simbox.minimum_image(pos - Vector3::new(4.0, 2.5, 1.0))).norm() < 1e-9, "{:?}", pos);
+        let s = simbox.fractional(pos - simbox.origin);
+        assert!(s.min() >= 0.0 && s.max() < 1.0, "{:?}", s);
+    }
+
+    #[test]
+    fn test_bounce_under_force() {
+        // an atom falling with g = 1 bounces on the reflective floor of the box, and keeps
+        // its energy v^2 / 2 + g z.
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin {
+            units: UnitSystem::argon_lj(),
+            time_step: TimeStep::new(0.01),
+            box_size: SimBox::new(Vector3::zeros(), 10.0, 10.0, 10.0)
+                .with_boundary([Boundary::Periodic, Boundary::Periodic, Boundary::Reflective]),
+            ..default()
+        });
+        app.add_plugin(IntegrationPlugin);
+        app.add_stage_before(CoreStage::Update, "gravity", SystemStage::parallel());
+        app.add_system_to_stage("gravity", |mut query: Query<&mut Force>| {
+            for mut force in query.iter_mut() {
+                force.force.z -= 1.0;
+            }
+        });
+        app.world.spawn()
+            .insert(Position { pos: Vector3::new(5.0, 5.0, 2.0) })
+            .insert(Velocity { vel: Vector3::new(0.0, 0.0, 0.3) })
+            .insert(Force::default())
+            .insert(OldForce::default())
+            .insert(Mass { value: 1.0 });
+
+        // the velocity after an update still misses the kicks of the old and the new forces,
+        // which close the step in the next update, i.e. -g dt.
+        let energy = |app: &mut App| {
+            let (pos, vel) = app.world.query::<(&Position, &Velocity)>().iter(&app.world).next().map(|(pos, vel)| (pos.pos, vel.vel)).unwrap();
+            (vel - Vector3::new(0.0, 0.0, 0.01)).norm_squared() / 2.0 + pos.z
+        };
+        let initial = 0.3f64.powi(2) / 2.0 + 2.0;
+        let mut max_error: f64 = 0.0;
+        // about five bounces
+        for _ in 0..2000 {
+            app.update();
+            max_error = max_error.max((energy(&mut app) - initial).abs());
+        }
+        // the flights conserve the energy exactly, and the mirrored position of a bounce is
+        // higher than the one below the floor by 2 |z| < 2 |v| dt, so each bounce changes the
+        // energy by less than 2 g |v| dt = 0.04, without adding up over the bounces.
+        assert!(max_error < 0.02, "energy error {}", max_error);
+    }
+}
@@ -0,0 +1,117 @@
+//! Langevin dynamics with the BAOAB splitting of Leimkuhler and Matthews,
+//! Appl. Math. Res. Express 2013, 34 (2013).
+//!
+//! The step is split into a half kick (B), half a drift (A), the exact solution of the
+//! friction and noise (O), another half drift (A) and the last half kick (B). As the forces
+//! are evaluated at the start of each update, the last half kick of a step is done in
+//! `BeginIntegration` of the next update by `half_kick`, which gives the full step velocities
+//! the kinetic energy is evaluated with, and B A O A is done in `EndIntegration`.
+
+use crate::atom::*;
+use crate::molecular_dynamics::integration::*;
+use crate::random::{RngPurpose, SimRng};
+use crate::simbox::SimBox;
+use crate::units::UnitSystem;
+use bevy::prelude::*;
+use bevy::tasks::ComputeTaskPool;
+use nalgebra::Vector3;
+use rand_distr::{Distribution, StandardNormal};
+
+/// the friction coefficient (in inverse time units) and the temperature of the bath.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LangevinParams {
+    pub friction: f64,
+    pub temperature: f64,
+}
+
+impl LangevinParams {
+    pub fn new(friction: f64, temperature: f64) -> Self {
+        Self { friction, temperature }
+    }
+}
+
+/// the B A O A part of the step.
+#[allow(clippy::too_many_arguments)]
+pub fn langevin_integrate(
+    pool: Res<ComputeTaskPool>,
+    batch_size: Res<BatchSize>,
+    timestep: Res<TimeStep>,
+    params: Res<LangevinParams>,
+    simbox: Res<SimBox>,
+    sim_rng: Res<SimRng>,
+    mut cur_step: ResMut<CurStep>,
+    units: Res<UnitSystem>,
+    mut query: Query<(&mut Position, &mut Velocity, &Force, &Mass, &AtomID), Without<Frozen>>,
+) {
+    let dt = timestep.delta;
+    let mass_conversion = units.mass_conversion();
+    let kt = units.boltzmann() * params.temperature;
+    let c1 = (-params.friction * dt).exp();
+    let c2 = (1.0 - c1 * c1).sqrt();
+    let step = cur_step.n;
+
+    query.par_for_each_mut(
+        &pool,
+        batch_size.0,
+        |(mut pos, mut vel, force, mass, atom_id)| {
+            let m = mass_conversion * mass.value;
+            // B
+            vel.vel += force.force / m / 2.0 * dt;
+            // A
+            pos.pos += vel.vel * dt / 2.0;
+            // O, each atom draws its noise from its own stream, whatever thread it runs on
+            let mut rng = sim_rng.atom_rng(RngPurpose::Langevin, step, atom_id.id);
+            let noise = Vector3::new(
+                StandardNormal.sample(&mut rng),
+                StandardNormal.sample(&mut rng),
+                StandardNormal.sample(&mut rng),
+            );
+            vel.vel = c1 * vel.vel + c2 * (kt / m).sqrt() * noise;
+            // A
+            pos.pos += vel.vel * dt / 2.0;
+
+            simbox.apply_boundaries(&mut pos.pos, &mut vel.vel);
+        }
+    );
+    cur_step.n += 1;
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::{molecular_dynamics::energy::Temperature, setup::SetupPlugin};
+    #[allow(unused_imports)]
+    use bevy::tasks::TaskPool;
+
+    #[test]
+    fn test_langevin_temperature() {
+        // an ideal argon gas at rest, heated by a 120 K bath.
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(
+            SetupPlugin { time_step: TimeStep::new(2e-15), ..default() }
+                .with_integrator(Integrator::Langevin(LangevinParams::new(1e13, 120.0)))
+        );
+        app.add_plugin(IntegrationPlugin);
+        for i in 0..200 {
+            app.world.spawn()
+                .insert(Position::default())
+                .insert(Velocity { vel: Vector3::new(0.0, 0.0, 0.0) })
+                .insert(Force::default())
+                .insert(OldForce::default())
+                .insert(Mass { value: 39.948 })
+                .insert(AtomID { id: i + 1 });
+        }
+
+        let mut mean_temperature = 0.0;
+        for i in 0..1000 {
+            app.update();
+            if i >= 500 {
+                mean_temperature += app.world.get_resource::<Temperature>().unwrap().value / 500.0;
+            }
+        }
+        assert_eq!(app.world.get_resource::<CurStep>().unwrap().n, 1000);
+        assert!((mean_temperature - 120.0).abs() < 6.0, "mean temperature {}", mean_temperature);
+    }
+}
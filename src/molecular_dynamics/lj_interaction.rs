@@ -2,6 +2,8 @@ use crate::atom::*;
 use crate::constant;
 use crate::simbox::*;
 use crate::molecular_dynamics::integration::*;
+use crate::molecular_dynamics::energy::{PotentialEnergy, Virial};
+use bevy::ecs::query::{FilterFetch, QueryItem, WorldQuery};
 use bevy::prelude::*;
 use bevy::tasks::ComputeTaskPool;
 use nalgebra::Vector3;
@@ -30,40 +32,33 @@ pub fn calc_lj_force (
     timestep: ResMut<TimeStep>,
     box_size: ResMut<SimBox>,
     cut_off: ResMut<LJCutOff>,
+    mut potential: ResMut<PotentialEnergy>,
+    mut virial: ResMut<Virial>,
     mut query: Query<(&mut Force, &mut OldForce, &Position, &AtomType)>,
     //query_j: Query<(&Force, &OldForce, &Position, &LJParams)>
 ) {
     
-    const K: usize = 2;
-    let mut particle_combos = query.iter_combinations_mut::<K>();
+    let mut lj_energy = 0.0;
 
-    while let Some([(mut force1, mut old_force1, pos1, atom1), (mut force2, mut old_force2, pos2, atom2)]) 
-    = particle_combos.fetch_next() {
-        //println!("force calculation!");
-        let lj_params1 = atom1.lj_params;
-        let lj_params2 = atom2.lj_params;
-        // here we have a pair of atoms in the system labeled as 1 and 2 for calculating the interaction between them.
-        // since the iter_combo methods returns a combinations of targeted entity withou repeatation 
-        // we can calculate the force asserted on each atom of the combo and update the said force.
-        // The LJ potential is in form of Vlj = 4*epsilon * [ (sigma/r)^12 - (sigma/r)^6 ] which gives force in the form of 
-        // f = dV/dr = 
-        let mut r1 = pos1.pos - pos2.pos; // use the first atom as reference
-        // the pbc treatment
-        r1[0] = r1[0] - box_size.dimension.x * (r1[0]/box_size.dimension.x).round(); 
-        r1[1] = r1[1] - box_size.dimension.y * (r1[1]/box_size.dimension.y).round();
-        r1[2] = r1[2] - box_size.dimension.z * (r1[2]/box_size.dimension.z).round();
+    for_each_pair_within(&mut query, &box_size, cut_off.rc, |(_, _, pos, _)| pos.pos,
+        |(mut force1, _, _, atom1), (mut force2, _, _, atom2), r1, r_square| {
+            //println!("force calculation!");
+            let lj_params1 = atom1.lj_params;
+            let lj_params2 = atom2.lj_params;
+            // here we have a pair of atoms in the system labeled as 1 and 2 within the cut-off distance,
+            // r1 is the separation of the pair with the pbc treatment, using the first atom as reference.
+            // The LJ potential is in form of Vlj = 4*epsilon * [ (sigma/r)^12 - (sigma/r)^6 ] which gives force in the form of 
+            // f = dV/dr = 
 
-        let r_square = r1.norm_squared();
-        
-        // adapting the lorentz-berthelot combining rule
-        let sigma_12 = (lj_params1.sigma + lj_params2.sigma) / 2.0;
-        let epsilon_12 = (lj_params1.epsilon * lj_params2.epsilon).powf(0.5);
-
-        // converting to A and B
-        //let a = 4.0 * epsilon_12 * sigma_12.powf(12.0);
-        //let b = 4.0 * epsilon_12 * sigma_12.powf(6.0);
-        if r_square < cut_off.rc.powf(2.0) { // check for cut-off distance
-            let lj_ff = 48.0 * epsilon_12 * (1.0/r_square)*(1.0/r_square.powf(3.0)) * (sigma_12.powf(12.0) * r_square.powf(3.0) - sigma_12.powf(6.0) * 0.5);
+            // adapting the lorentz-berthelot combining rule
+            let sigma_12 = (lj_params1.sigma + lj_params2.sigma) / 2.0;
+            let epsilon_12 = (lj_params1.epsilon * lj_params2.epsilon).powf(0.5);
+
+            // converting to A and B
+            //let a = 4.0 * epsilon_12 * sigma_12.powf(12.0);
+            //let b = 4.0 * epsilon_12 * sigma_12.powf(6.0);
+            let sr6 = (sigma_12.powf(2.0) / r_square).powf(3.0);
+            let lj_ff = 48.0 * epsilon_12 * (1.0/r_square) * (sr6 * sr6 - sr6 * 0.5);
             let lj_force_x = lj_ff * r1[0];
             let lj_force_y = lj_ff * r1[1];
             let lj_force_z = lj_ff * r1[2];
@@ -72,9 +67,37 @@ pub fn calc_lj_force (
             //old_force1.0.force = force1.force;
             //old_force2.0.force = force2.force;
             // updating the force for both particles
-            force1.force = force1.force + Vector3::new(lj_force_x, lj_force_y, lj_force_z);
-            force2.force = force2.force - Vector3::new(lj_force_x, lj_force_y, lj_force_z);
+            let lj_force = Vector3::new(lj_force_x, lj_force_y, lj_force_z);
+            force1.force = force1.force + lj_force;
+            force2.force = force2.force - lj_force;
+            virial.tensor += r1 * lj_force.transpose();
             //println!("{}, {}, {}", force1.force.x, force1.force.y, force1.force.z);
+
+            lj_energy += 4.0 * epsilon_12 * (sr6 * sr6 - sr6);
+        });
+    potential.value += lj_energy;
+}
+
+/// Visits each pair of atoms of the query closer than the cut-off once, with the minimum image
+/// of the separation r_1 - r_2 of the pair and its square. The pair interactions all go through
+/// this loop, the position of an atom is taken from its query item.
+pub fn for_each_pair_within<'a, Q: WorldQuery, F: WorldQuery>(
+    query: &'a mut Query<'_, '_, Q, F>,
+    simbox: &SimBox,
+    rc: f64,
+    position: impl Fn(&QueryItem<'a, 'a, Q>) -> Vector3<f64>,
+    mut visit: impl FnMut(QueryItem<'a, 'a, Q>, QueryItem<'a, 'a, Q>, Vector3<f64>, f64),
+) where
+    Q::Fetch: Clone,
+    F::Fetch: FilterFetch + Clone,
+{
+    const K: usize = 2;
+    let mut particle_combos = query.iter_combinations_mut::<K>();
+    while let Some([item1, item2]) = particle_combos.fetch_next() {
+        let r1 = simbox.minimum_image(position(&item1) - position(&item2));
+        let r_square = r1.norm_squared();
+        if r_square < rc.powf(2.0) {
+            visit(item1, item2, r1, r_square);
         }
     }
 }
@@ -87,25 +110,75 @@ pub enum ForceStages {
 #[derive(PartialEq, Clone, Hash, Debug, Eq, SystemLabel)]
 pub enum ForceSystems {
     LJSystem,
+    DPDSystem,
+    WallSystem,
+    ExternalFieldSystem,
+    UserForceSystem,
+    RestraintSystem,
+}
+
+/// adds the force stage, unless another force plugin has already added it.
+pub fn add_force_stage(app: &mut App) {
+    if app.schedule.get_stage::<SystemStage>(&ForceStages::LJStage).is_none() {
+        app.add_stage_before(CoreStage::Update, ForceStages::LJStage, SystemStage::parallel());
+    }
 }
 
 pub struct LJPlugin;
 impl Plugin for LJPlugin {
     fn build(&self, app: &mut App) {
-        app.add_stage_before(CoreStage::Update, ForceStages::LJStage, SystemStage::parallel());
+        app.init_resource::<PotentialEnergy>();
+        app.init_resource::<Virial>();
+        add_force_stage(app);
         app.add_system_to_stage(ForceStages::LJStage, calc_lj_force.label(ForceSystems::LJSystem));
     }
 }
-/* 
 pub mod tests {
     #[allow(unused_imports)]
     use super::*;
+    #[allow(unused_imports)]
+    use crate::setup::SetupPlugin;
+    #[allow(unused_imports)]
+    use bevy::tasks::TaskPool;
+
+    /// the force on the first of two atoms at the distance r along x, and their energy.
+    #[allow(dead_code)]
+    fn lj_pair(r: f64) -> (Vector3<f64>, f64) {
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin {
+            box_size: SimBox::new(Vector3::zeros(), 10.0, 10.0, 10.0),
+            lj_cutoff: LJCutOff::new(3.0),
+            ..default()
+        });
+        app.add_plugin(LJPlugin);
+        let argon = AtomType::new(String::from("Argon"), 1.0, 1.0);
+        for (id, x) in [(1, 5.0), (2, 5.0 + r)] {
+            app.world.spawn()
+                .insert(Position { pos: Vector3::new(x, 5.0, 5.0) })
+                .insert(Force::default())
+                .insert(OldForce::default())
+                .insert(argon.clone())
+                .insert(AtomID { id });
+        }
+        app.update();
+        let mut query = app.world.query::<(&Force, &AtomID)>();
+        let force = query.iter(&app.world).find(|(_, atom_id)| atom_id.id == 1).unwrap().0.force;
+        (force, app.world.get_resource::<PotentialEnergy>().unwrap().value)
+    }
 
     #[test]
     fn test_calc_lj_force() {
-        let mut app = App::new();
-        app.add_plugin()
+        // the force on the first atom is the derivative of the energy along the distance,
+        // F = dV/dr, as the first atom sits at -r from the second.
+        for r in [0.95, 1.0, 2f64.powf(1.0 / 6.0), 1.5, 2.5] {
+            let h = 1e-6;
+            let numerical = (lj_pair(r + h).1 - lj_pair(r - h).1) / (2.0 * h);
+            let (force, energy) = lj_pair(r);
+            let sr6 = r.powi(-6);
+            assert!((energy - 4.0 * (sr6 * sr6 - sr6)).abs() < 1e-12, "energy {} at {}", energy, r);
+            assert!((force.x - numerical).abs() < 1e-6 * numerical.abs().max(1.0), "force {} at {}, expected {}", force.x, r, numerical);
+            assert!(force.y.abs() < 1e-12 && force.z.abs() < 1e-12);
+        }
     }
 }
-
-*/
\ No newline at end of file
@@ -1,2 +1,17 @@
 pub mod integration;
-pub mod lj_interaction;
\ No newline at end of file
+pub mod lj_interaction;
+pub mod energy;
+pub mod thermostat;
+pub mod nose_hoover;
+pub mod langevin;
+pub mod dpd;
+pub mod barostat;
+pub mod mtk;
+pub mod parrinello_rahman;
+pub mod respa;
+pub mod brownian;
+pub mod adaptive;
+pub mod wall;
+pub mod external_field;
+pub mod restraint;
+pub mod atom_changes;
\ No newline at end of file
@@ -0,0 +1,419 @@
+//! Isothermal-isobaric dynamics with the Martyna-Tobias-Klein barostat, J. Chem. Phys. 101,
+//! 4177 (1994), integrated with the reversible splitting of Martyna, Tuckerman, Tobias and
+//! Klein, Mol. Phys. 87, 1117 (1996).
+//!
+//! The cell velocity v_g couples the atoms to the box through
+//!
+//! dx/dt = v + v_g x, dv/dt = F/m - (v_g + Tr(v_g)/N_f) v, dh/dt = v_g h,
+//!
+//! and is driven by the difference between the internal and the target pressures. The atoms
+//! and the cell each have their own Nosé-Hoover chain. Like in Langevin dynamics the velocity
+//! half kick with the new forces closes the step in `BeginIntegration`, with the cell half kick
+//! and the half step of the chains, and the chains, the cell half kick, the velocity half kick
+//! and the drift open the next one.
+//!
+//! In the isotropic mode v_g is a multiple of the identity. In the flexible mode it is the
+//! upper triangle of a full matrix, driven by the symmetric part of the pressure tensor as in
+//! the Parrinello-Rahman barostat, so the lengths and the tilts of the box fluctuate and the
+//! box keeps the LAMMPS triclinic form.
+
+use crate::atom::*;
+use crate::molecular_dynamics::energy::*;
+use crate::molecular_dynamics::integration::*;
+use crate::molecular_dynamics::nose_hoover::*;
+use crate::simbox::SimBox;
+use crate::units::UnitSystem;
+use bevy::prelude::*;
+use bevy::tasks::ComputeTaskPool;
+use nalgebra::{Matrix3, Matrix6};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CellMode {
+    /// the box is scaled uniformly, from the scalar pressure.
+    Isotropic,
+    /// each length and tilt of the box follows its own pressure component.
+    Flexible,
+}
+
+/// the parameters of the MTK integrator, the chain of the atoms gives the target temperature
+/// and its coupling time, the target pressure and the coupling time of the barostat are in
+/// the units of the simulation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MTKParams {
+    pub thermostat: NoseHooverChain,
+    pub pressure: f64,
+    pub tau_p: f64,
+    pub mode: CellMode,
+}
+
+impl MTKParams {
+    pub fn new(thermostat: NoseHooverChain, pressure: f64, tau_p: f64) -> Self {
+        Self { thermostat, pressure, tau_p, mode: CellMode::Isotropic }
+    }
+
+    pub fn with_mode(mut self, mode: CellMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// the number of degrees of freedom of the cell.
+    fn cell_dof(&self) -> f64 {
+        match self.mode {
+            CellMode::Isotropic => 1.0,
+            CellMode::Flexible => 6.0,
+        }
+    }
+
+    /// the chain coupled to the cell, with the same temperature as the atoms.
+    fn cell_chain(&self) -> NoseHooverChain {
+        NoseHooverChain { tau: self.tau_p, ..self.thermostat }
+    }
+
+    /// the mass of each degree of freedom of the cell, W = (N_f + 3) kT tau_p^2 / 3 in the
+    /// flexible mode, and three times that in the isotropic mode.
+    fn cell_mass(&self, n_dof: f64, kt: f64) -> f64 {
+        let n_g = match self.mode {
+            CellMode::Isotropic => 1.0,
+            CellMode::Flexible => 3.0,
+        };
+        (n_dof + 3.0) * kt * self.tau_p * self.tau_p / n_g
+    }
+
+    /// twice the kinetic energy of the cell.
+    fn cell_kinetic2(&self, v_g: &Matrix3<f64>, w: f64) -> f64 {
+        match self.mode {
+            CellMode::Isotropic => w * v_g[(0, 0)] * v_g[(0, 0)],
+            CellMode::Flexible => w * v_g.norm_squared(),
+        }
+    }
+
+    /// the force on the cell velocity, G = [V (P_int - P) + 2K / N_f] / W, from the symmetric
+    /// part of the pressure tensor, with the trace on the diagonal in the isotropic mode.
+    fn cell_force(&self, kinetic_tensor: &Matrix3<f64>, virial: &Matrix3<f64>, volume: f64, n_dof: f64, w: f64) -> Matrix3<f64> {
+        let kinetic2 = kinetic_tensor.trace();
+        let stress = kinetic_tensor + (virial + virial.transpose()) / 2.0;
+        let g = (stress - Matrix3::identity() * (volume * self.pressure - kinetic2 / n_dof)) / w;
+        match self.mode {
+            CellMode::Isotropic => Matrix3::identity() * g.trace(),
+            CellMode::Flexible => g.upper_triangle(),
+        }
+    }
+}
+
+/// the cell velocity and the chains of the atoms and of the cell.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MTKState {
+    /// the cell velocity, upper triangular, and a multiple of the identity in the isotropic mode.
+    pub v_g: Matrix3<f64>,
+    pub particle_chain: NoseHooverState,
+    pub cell_chain: NoseHooverState,
+    /// the energy last added to the `ExtendedEnergy`.
+    pub energy: f64,
+}
+
+impl MTKState {
+    pub fn new(params: &MTKParams) -> Self {
+        Self {
+            v_g: Matrix3::zeros(),
+            particle_chain: NoseHooverState::new(params.thermostat.chain_length),
+            cell_chain: NoseHooverState::new(params.thermostat.chain_length),
+            energy: 0.0,
+        }
+    }
+}
+
+fn kinetic_tensor(query: &Query<(&mut Velocity, &Force, &Mass), Without<Frozen>>, mass_conversion: f64) -> Matrix3<f64> {
+    query
+        .iter()
+        .map(|(vel, _force, mass)| mass_conversion * mass.value * vel.vel * vel.vel.transpose())
+        .sum()
+}
+
+/// the matrix the velocities are multiplied with before and after a half kick,
+/// exp(-(v_g + Tr(v_g)/N_f) dt/4).
+fn kick_scaling(v_g: &Matrix3<f64>, n_dof: f64, dt: f64) -> Matrix3<f64> {
+    (-(v_g + Matrix3::identity() * v_g.trace() / n_dof) * dt / 4.0).exp()
+}
+
+/// the exact solution of dx/dt = v + v_g x over the time step, for constant v, is
+/// x(dt) = exp(v_g dt) x + (integral of exp(v_g s) ds from 0 to dt) v. Both matrices are the
+/// upper blocks of the exponential of the 6x6 matrix [[v_g, I], [0, 0]] dt.
+fn drift_matrices(v_g: &Matrix3<f64>, dt: f64) -> (Matrix3<f64>, Matrix3<f64>) {
+    let mut generator = Matrix6::zeros();
+    generator.fixed_slice_mut::<3, 3>(0, 0).copy_from(&(v_g * dt));
+    generator.fixed_slice_mut::<3, 3>(0, 3).copy_from(&(Matrix3::identity() * dt));
+    let exponential = generator.exp();
+    (exponential.fixed_slice::<3, 3>(0, 0).into_owned(), exponential.fixed_slice::<3, 3>(0, 3).into_owned())
+}
+
+/// the velocity half kick with the new forces, the cell half kick and the half step of the
+/// chains, closing the step. In the very first step only the energy is evaluated.
+#[allow(clippy::too_many_arguments)]
+pub fn mtk_close_step(
+    pool: Res<ComputeTaskPool>,
+    batch_size: Res<BatchSize>,
+    params: Res<MTKParams>,
+    timestep: Res<TimeStep>,
+    cur_step: Res<CurStep>,
+    units: Res<UnitSystem>,
+    simbox: Res<SimBox>,
+    virial: Res<Virial>,
+    mut state: ResMut<MTKState>,
+    mut extended: ResMut<ExtendedEnergy>,
+    mut query: Query<(&mut Velocity, &Force, &Mass), Without<Frozen>>,
+    anchored: Query<(), Anchored>,
+) {
+    let dt = timestep.delta;
+    let mass_conversion = units.mass_conversion();
+    let n_dof = degrees_of_freedom(query.iter().count(), anchored.is_empty());
+    let kt = units.boltzmann() * params.thermostat.target;
+    let w = params.cell_mass(n_dof, kt);
+
+    if cur_step.n > 0 {
+        let scaling = kick_scaling(&state.v_g, n_dof, dt);
+        query.par_for_each_mut(
+            &pool,
+            batch_size.0,
+            |(mut vel, force, mass)| {
+                vel.vel = scaling * (scaling * vel.vel + force.force / (mass_conversion * mass.value) / 2.0 * dt);
+            }
+        );
+
+        let kinetic = kinetic_tensor(&query, mass_conversion);
+        state.v_g += params.cell_force(&kinetic, &virial.tensor, simbox.volume(), n_dof, w) * dt / 2.0;
+
+        let particle_scale = state.particle_chain.propagate(&params.thermostat, kinetic.trace(), n_dof, kt, dt / 2.0);
+        query.par_for_each_mut(&pool, batch_size.0, |(mut vel, _force, _mass)| {
+            vel.vel *= particle_scale;
+        });
+        let cell_kinetic2 = params.cell_kinetic2(&state.v_g, w);
+        let cell_scale = state.cell_chain.propagate(&params.cell_chain(), cell_kinetic2, params.cell_dof(), kt, dt / 2.0);
+        state.v_g *= cell_scale;
+    }
+
+    // the extended energy at the end of the step, P V, the kinetic energy of the cell and
+    // the energies of the chains.
+    let energy = params.pressure * simbox.volume()
+        + 0.5 * params.cell_kinetic2(&state.v_g, w)
+        + state.particle_chain.chain_energy(&params.thermostat, n_dof, kt)
+        + state.cell_chain.chain_energy(&params.cell_chain(), params.cell_dof(), kt);
+    extended.value += energy - state.energy;
+    state.energy = energy;
+}
+
+/// the half step of the chains and the cell half kick, opening the step.
+#[allow(clippy::too_many_arguments)]
+pub fn mtk_open_step(
+    pool: Res<ComputeTaskPool>,
+    batch_size: Res<BatchSize>,
+    params: Res<MTKParams>,
+    timestep: Res<TimeStep>,
+    units: Res<UnitSystem>,
+    simbox: Res<SimBox>,
+    virial: Res<Virial>,
+    mut state: ResMut<MTKState>,
+    mut query: Query<(&mut Velocity, &Force, &Mass), Without<Frozen>>,
+    anchored: Query<(), Anchored>,
+) {
+    let dt = timestep.delta;
+    let mass_conversion = units.mass_conversion();
+    let n_dof = degrees_of_freedom(query.iter().count(), anchored.is_empty());
+    let kt = units.boltzmann() * params.thermostat.target;
+    let w = params.cell_mass(n_dof, kt);
+
+    let cell_kinetic2 = params.cell_kinetic2(&state.v_g, w);
+    let cell_scale = state.cell_chain.propagate(&params.cell_chain(), cell_kinetic2, params.cell_dof(), kt, dt / 2.0);
+    state.v_g *= cell_scale;
+    let kinetic2 = kinetic_tensor(&query, mass_conversion).trace();
+    let particle_scale = state.particle_chain.propagate(&params.thermostat, kinetic2, n_dof, kt, dt / 2.0);
+    query.par_for_each_mut(&pool, batch_size.0, |(mut vel, _force, _mass)| {
+        vel.vel *= particle_scale;
+    });
+
+    let kinetic = kinetic_tensor(&query, mass_conversion);
+    state.v_g += params.cell_force(&kinetic, &virial.tensor, simbox.volume(), n_dof, w) * dt / 2.0;
+}
+
+/// the velocity half kick and the drift of the positions and of the box.
+#[allow(clippy::too_many_arguments)]
+pub fn mtk_integrate(
+    pool: Res<ComputeTaskPool>,
+    batch_size: Res<BatchSize>,
+    timestep: Res<TimeStep>,
+    units: Res<UnitSystem>,
+    state: Res<MTKState>,
+    mut simbox: ResMut<SimBox>,
+    mut cur_step: ResMut<CurStep>,
+    mut query: Query<(&mut Position, &mut Velocity, &Force, &Mass), Without<Frozen>>,
+    anchored: Query<(), Anchored>,
+) {
+    let dt = timestep.delta;
+    let mass_conversion = units.mass_conversion();
+    let n_dof = degrees_of_freedom(query.iter().count(), anchored.is_empty());
+    let scaling = kick_scaling(&state.v_g, n_dof, dt);
+
+    let (position_scaling, velocity_scaling) = drift_matrices(&state.v_g, dt);
+    let origin = simbox.origin;
+    let h = position_scaling * simbox.cell_matrix();
+    simbox.set_cell_matrix(&h);
+    let new_box = *simbox;
+
+    query.par_for_each_mut(
+        &pool,
+        batch_size.0,
+        |(mut pos, mut vel, force, mass)| {
+            vel.vel = scaling * (scaling * vel.vel + force.force / (mass_conversion * mass.value) / 2.0 * dt);
+
+            pos.pos = origin + position_scaling * (pos.pos - origin) + velocity_scaling * vel.vel;
+
+            new_box.apply_boundaries(&mut pos.pos, &mut vel.vel);
+        }
+    );
+    cur_step.n += 1;
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::molecular_dynamics::lj_interaction::*;
+    #[allow(unused_imports)]
+    use crate::{setup::SetupPlugin, units::UnitSystem};
+    #[allow(unused_imports)]
+    use crate::output::checkpoint::tests::{argon_app, assert_restart_matches};
+    #[allow(unused_imports)]
+    use crate::molecular_dynamics::integration::tests::spawn_ideal_gas;
+    #[allow(unused_imports)]
+    use bevy::tasks::TaskPool;
+    #[allow(unused_imports)]
+    use nalgebra::Vector3;
+
+    /// an ideal argon gas at 120 K, kept at twice its initial pressure.
+    #[allow(dead_code)]
+    fn isobaric_gas_app(mode: CellMode, pressure: f64) -> App {
+        let params = MTKParams::new(NoseHooverChain::new(120.0, 1e-13), pressure, 5e-13).with_mode(mode);
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(
+            SetupPlugin { time_step: TimeStep::new(2e-15), ..default() }
+                .with_integrator(Integrator::MTK(params))
+        );
+        app.add_plugin(IntegrationPlugin);
+        spawn_ideal_gas(&mut app, 200.0);
+        app
+    }
+
+    #[test]
+    fn test_ideal_gas_equation_of_state() {
+        let kt = crate::constant::BOLTZCONST * 120.0;
+        let pressure = 2.0 * 100.0 * kt / 1e-24;
+        // the isothermal-isobaric ideal gas has <V> = N kT / P, with N - 1 free atoms
+        // once the momentum of the center of mass is removed.
+        let expected = 99.0 * kt / pressure;
+
+        for mode in [CellMode::Isotropic, CellMode::Flexible] {
+            let mut app = isobaric_gas_app(mode, pressure);
+            let mut conserved = Vec::new();
+            let mut mean_volume = 0.0;
+            for i in 0..4000 {
+                app.update();
+                conserved.push(conserved_energy(&app.world));
+                if i >= 1000 {
+                    mean_volume += app.world.get_resource::<SimBox>().unwrap().volume() / 3000.0;
+                }
+            }
+            let drift = (conserved[3999] / conserved[0] - 1.0).abs();
+            assert!(drift < 1e-5, "{:?} relative conserved energy drift {}", mode, drift);
+            assert!((mean_volume / expected - 1.0).abs() < 0.1, "{:?} volume {}", mode, mean_volume / expected);
+        }
+    }
+
+    #[test]
+    fn test_flexible_cell_shear() {
+        // the atoms of the ideal gas all move along (1, 1, 0) or against it, so the pressure
+        // tensor has a positive xy component and none in xz and yz, and the cell tilts in xy only.
+        let kt = crate::constant::BOLTZCONST * 120.0;
+        let mut app = isobaric_gas_app(CellMode::Flexible, 2.0 * 100.0 * kt / 1e-24);
+        let mut query = app.world.query::<(&mut Velocity, &AtomID)>();
+        for (mut vel, atom_id) in query.iter_mut(&mut app.world) {
+            let sign = if atom_id.id % 2 == 0 { 1.0 } else { -1.0 };
+            vel.vel = Vector3::new(200.0, 200.0, 0.0) * sign;
+        }
+
+        let mut conserved = Vec::new();
+        for _ in 0..500 {
+            app.update();
+            conserved.push(conserved_energy(&app.world));
+        }
+        let drift = (conserved[499] / conserved[0] - 1.0).abs();
+        assert!(drift < 1e-5, "relative conserved energy drift {}", drift);
+        let simbox = app.world.get_resource::<SimBox>().unwrap();
+        assert!(simbox.tilt.x > 1e-3 * simbox.dimension.x, "tilt {:?} of the box {:?}", simbox.tilt, simbox.dimension);
+        assert!(simbox.tilt.y.abs() < 1e-9 * simbox.tilt.x && simbox.tilt.z.abs() < 1e-9 * simbox.tilt.x, "tilt {:?}", simbox.tilt);
+    }
+
+    #[test]
+    fn test_lj_argon_equation_of_state() {
+        // the equation of state of Johnson, Zollweg and Gubbins, Mol. Phys. 78, 591 (1993),
+        // gives P = 1.767 for the LJ fluid at T = 2 and rho = 0.6, in reduced units, and the
+        // pressure of the interactions beyond the cutoff of 2.5 is
+        // 16/3 pi rho^2 (2/3 rc^-9 - rc^-3) = -0.385, so the truncated fluid has P = 2.152.
+        let (temperature, pressure, density) = (2.0, 2.152, 0.6);
+        let a = (4.0f64 / density).powf(1.0 / 3.0);
+        let params = MTKParams::new(NoseHooverChain::new(temperature, 0.5), pressure, 2.0);
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin {
+            units: UnitSystem::argon_lj(),
+            time_step: TimeStep::new(0.005),
+            box_size: SimBox::new(Vector3::zeros(), 3.0 * a, 3.0 * a, 3.0 * a),
+            lj_cutoff: LJCutOff::new(2.5),
+            ..default()
+        }.with_integrator(Integrator::MTK(params)));
+        app.add_plugin(LJPlugin);
+        app.add_plugin(IntegrationPlugin);
+
+        // 108 atoms on an FCC lattice, which melts at this temperature
+        let basis = [Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.5, 0.5, 0.0), Vector3::new(0.5, 0.0, 0.5), Vector3::new(0.0, 0.5, 0.5)];
+        let mut id = 0;
+        for i in 0..3 {
+            for j in 0..3 {
+                for k in 0..3 {
+                    for b in basis.iter() {
+                        id += 1;
+                        let x = id as f64;
+                        app.world.spawn()
+                            .insert(Position { pos: (Vector3::new(i as f64, j as f64, k as f64) + b) * a })
+                            .insert(Velocity { vel: Vector3::new(2.0 * x.sin(), 2.0 * x.cos(), 2.0 * (2.0 * x).sin()) })
+                            .insert(Force::default())
+                            .insert(OldForce::default())
+                            .insert(Mass { value: 1.0 })
+                            .insert(AtomType::new(String::from("Argon"), 1.0, 1.0))
+                            .insert(AtomID { id });
+                    }
+                }
+            }
+        }
+
+        let mut mean_density = 0.0;
+        for i in 0..2000 {
+            app.update();
+            if i >= 500 {
+                mean_density += 108.0 / app.world.get_resource::<SimBox>().unwrap().volume() / 1500.0;
+            }
+        }
+        assert!((mean_density / density - 1.0).abs() < 0.02, "density {}, expected {}", mean_density, density);
+    }
+
+    #[test]
+    fn test_npt_restart_matches_uninterrupted_run() {
+        // the cell velocity and both chains of the MTK integrator continue from the checkpoint
+        let params = MTKParams::new(NoseHooverChain::new(120.0, 1e-13), 1e8, 5e-13).with_mode(CellMode::Flexible);
+        let (app, restarted) = assert_restart_matches("mtk_restart_test", |dir, restart| {
+            argon_app(dir, restart, Integrator::MTK(params), |_| {})
+        }, |_, _| {});
+        assert_eq!(app.world.get_resource::<MTKState>(), restarted.world.get_resource::<MTKState>());
+        let cell = |app: &App| app.world.get_resource::<SimBox>().unwrap().cell_matrix();
+        assert_eq!(cell(&app), cell(&restarted));
+    }
+}
@@ -0,0 +1,309 @@
+//! Nosé-Hoover chain thermostat for canonical sampling.
+//!
+//! The chain is propagated with the Trotter splitting of Martyna, Tuckerman, Tobias and
+//! Klein, Mol. Phys. 87, 1117 (1996), with multiple time stepping and Suzuki-Yoshida
+//! weights. Half a chain step closes each step, right after the velocities are completed
+//! in `BeginIntegration`, and the other half opens the next one before the positions are
+//! moved in `EndIntegration`, so the velocity verlet step is enclosed symmetrically.
+
+use crate::atom::*;
+use crate::molecular_dynamics::energy::*;
+use crate::molecular_dynamics::integration::*;
+use crate::output::checkpoint::Checkpoint;
+use crate::units::UnitSystem;
+use bevy::prelude::*;
+use bevy::tasks::ComputeTaskPool;
+
+/// the parameters of the chain, the target temperature is in the temperature units
+/// and the coupling time in the time units of the simulation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoseHooverChain {
+    pub target: f64,
+    pub tau: f64,
+    /// the number of thermostats in the chain.
+    pub chain_length: usize,
+    /// the number of sub-steps the chain is propagated with in each half step.
+    pub n_respa: usize,
+    /// the order of the Suzuki-Yoshida scheme, 1, 3, 5 or 7.
+    pub n_yoshida: usize,
+}
+
+impl NoseHooverChain {
+    pub fn new(target: f64, tau: f64) -> Self {
+        Self { target, tau, chain_length: 3, n_respa: 1, n_yoshida: 3 }
+    }
+
+    pub fn with_chain_length(mut self, chain_length: usize) -> Self {
+        self.chain_length = chain_length;
+        self
+    }
+
+    pub fn with_multiple_time_steps(mut self, n_respa: usize, n_yoshida: usize) -> Self {
+        self.n_respa = n_respa;
+        self.n_yoshida = n_yoshida;
+        self
+    }
+
+    /// the masses of the thermostats, Q_1 = N_f kT tau^2 and Q_j = kT tau^2.
+    fn masses(&self, n_dof: f64, kt: f64) -> Vec<f64> {
+        (0..self.chain_length)
+            .map(|j| if j == 0 { n_dof } else { 1.0 } * kt * self.tau * self.tau)
+            .collect()
+    }
+}
+
+/// the positions and velocities of the thermostats in the chain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NoseHooverState {
+    pub xi: Vec<f64>,
+    pub v_xi: Vec<f64>,
+    /// the energy of the chain last added to the `ExtendedEnergy`.
+    pub energy: f64,
+}
+
+impl NoseHooverState {
+    pub fn new(chain_length: usize) -> Self {
+        Self { xi: vec![0.0; chain_length], v_xi: vec![0.0; chain_length], energy: 0.0 }
+    }
+
+    /// the energy of the chain, sum of Q_j v_j^2 / 2 + N_f kT xi_1 + kT sum of xi_j for j > 1.
+    pub fn chain_energy(&self, params: &NoseHooverChain, n_dof: f64, kt: f64) -> f64 {
+        let q = params.masses(n_dof, kt);
+        let kinetic: f64 = q.iter().zip(self.v_xi.iter()).map(|(q, v)| 0.5 * q * v * v).sum();
+        let potential: f64 = self.xi.iter().enumerate()
+            .map(|(j, xi)| if j == 0 { n_dof } else { 1.0 } * kt * xi)
+            .sum();
+        kinetic + potential
+    }
+
+    /// Propagates the chain over `dt` given twice the kinetic energy of the atoms, and
+    /// returns the factor the atom velocities are scaled by.
+    pub fn propagate(&mut self, params: &NoseHooverChain, kinetic2: f64, n_dof: f64, kt: f64, dt: f64) -> f64 {
+        let m = params.chain_length;
+        let q = params.masses(n_dof, kt);
+        let force = |j: usize, v_xi: &[f64], kinetic2: f64| {
+            if j == 0 {
+                (kinetic2 - n_dof * kt) / q[0]
+            } else {
+                (q[j - 1] * v_xi[j - 1] * v_xi[j - 1] - kt) / q[j]
+            }
+        };
+
+        let mut scale = 1.0;
+        let mut kinetic2 = kinetic2;
+        for _ in 0..params.n_respa {
+            for w in suzuki_yoshida_weights(params.n_yoshida) {
+                let delta = w * dt / params.n_respa as f64;
+
+                // update the thermostat velocities from the end of the chain
+                self.v_xi[m - 1] += force(m - 1, &self.v_xi, kinetic2) * delta / 2.0;
+                for j in (0..m - 1).rev() {
+                    let aa = (-self.v_xi[j + 1] * delta / 4.0).exp();
+                    self.v_xi[j] = self.v_xi[j] * aa * aa + force(j, &self.v_xi, kinetic2) * delta / 2.0 * aa;
+                }
+
+                // scale the atom velocities and move the thermostats
+                let aa = (-self.v_xi[0] * delta).exp();
+                scale *= aa;
+                kinetic2 *= aa * aa;
+                for j in 0..m {
+                    self.xi[j] += self.v_xi[j] * delta;
+                }
+
+                // update the thermostat velocities from the start of the chain
+                for j in 0..m - 1 {
+                    let aa = (-self.v_xi[j + 1] * delta / 4.0).exp();
+                    self.v_xi[j] = self.v_xi[j] * aa * aa + force(j, &self.v_xi, kinetic2) * delta / 2.0 * aa;
+                }
+                self.v_xi[m - 1] += force(m - 1, &self.v_xi, kinetic2) * delta / 2.0;
+            }
+        }
+        scale
+    }
+}
+
+/// The weights of the Suzuki-Yoshida decomposition of the given order.
+pub fn suzuki_yoshida_weights(order: usize) -> Vec<f64> {
+    match order {
+        1 => vec![1.0],
+        3 => {
+            let w1 = 1.0 / (2.0 - 2.0f64.powf(1.0 / 3.0));
+            vec![w1, 1.0 - 2.0 * w1, w1]
+        }
+        5 => {
+            let w1 = 1.0 / (4.0 - 4.0f64.powf(1.0 / 3.0));
+            vec![w1, w1, 1.0 - 4.0 * w1, w1, w1]
+        }
+        7 => {
+            let w1 = 0.784513610477560;
+            let w2 = 0.235573213359357;
+            let w3 = -1.17767998417887;
+            let w4 = 1.0 - 2.0 * (w1 + w2 + w3);
+            vec![w1, w2, w3, w4, w3, w2, w1]
+        }
+        _ => panic!("unsupported Suzuki-Yoshida order {}, use 1, 3, 5 or 7", order),
+    }
+}
+
+/// propagates the chain over half a time step and scales the velocities accordingly.
+#[allow(clippy::too_many_arguments)]
+fn half_step(
+    pool: &ComputeTaskPool,
+    batch_size: usize,
+    params: &NoseHooverChain,
+    state: &mut NoseHooverState,
+    dt: f64,
+    units: &UnitSystem,
+    query: &mut Query<(&mut Velocity, &Mass), Without<Frozen>>,
+    momentum_conserved: bool,
+) -> (f64, f64) {
+    let mass_conversion = units.mass_conversion();
+    let kinetic2: f64 = query
+        .iter()
+        .map(|(vel, mass)| mass_conversion * mass.value * vel.vel.norm_squared())
+        .sum();
+    let n_dof = degrees_of_freedom(query.iter().count(), momentum_conserved);
+    let kt = units.boltzmann() * params.target;
+
+    let scale = state.propagate(params, kinetic2, n_dof, kt, dt / 2.0);
+    query.par_for_each_mut(pool, batch_size, |(mut vel, _mass)| {
+        vel.vel *= scale;
+    });
+    (n_dof, kt)
+}
+
+/// the second half of the chain step, closing the step once the velocities are complete.
+#[allow(clippy::too_many_arguments)]
+pub fn nose_hoover_close_step(
+    pool: Res<ComputeTaskPool>,
+    batch_size: Res<BatchSize>,
+    params: Res<NoseHooverChain>,
+    timestep: Res<TimeStep>,
+    cur_step: Res<CurStep>,
+    units: Res<UnitSystem>,
+    mut state: ResMut<NoseHooverState>,
+    mut extended: ResMut<ExtendedEnergy>,
+    mut query: Query<(&mut Velocity, &Mass), Without<Frozen>>,
+    anchored: Query<(), Anchored>,
+) {
+    // nothing to close in the very first step.
+    if cur_step.n == 0 {
+        return;
+    }
+    let (n_dof, kt) = half_step(&pool, batch_size.0, &params, &mut state, timestep.delta, &units, &mut query, anchored.is_empty());
+
+    // the energy of the chain at the end of the step, so that the conserved energy
+    // is evaluated with the kinetic energy of the same velocities.
+    let energy = state.chain_energy(&params, n_dof, kt);
+    extended.value += energy - state.energy;
+    state.energy = energy;
+}
+
+/// the first half of the chain step, before the positions are moved.
+#[allow(clippy::too_many_arguments)]
+pub fn nose_hoover_open_step(
+    pool: Res<ComputeTaskPool>,
+    batch_size: Res<BatchSize>,
+    params: Res<NoseHooverChain>,
+    timestep: Res<TimeStep>,
+    units: Res<UnitSystem>,
+    mut state: ResMut<NoseHooverState>,
+    mut query: Query<(&mut Velocity, &Mass), Without<Frozen>>,
+    anchored: Query<(), Anchored>,
+) {
+    half_step(&pool, batch_size.0, &params, &mut state, timestep.delta, &units, &mut query, anchored.is_empty());
+}
+
+#[derive(PartialEq, Clone, Hash, Debug, Eq, SystemLabel)]
+pub enum NoseHooverSystems {
+    CloseStep,
+    OpenStep,
+}
+
+/// couples the chain to all the atoms that are not frozen. Unlike the `Thermostat`, it has no
+/// group, as the MTK integrator shares the chain with the barostat, which acts on the whole box.
+pub struct NoseHooverPlugin {
+    pub params: NoseHooverChain,
+}
+
+impl NoseHooverPlugin {
+    pub fn new(params: NoseHooverChain) -> Self {
+        Self { params }
+    }
+}
+
+impl Plugin for NoseHooverPlugin {
+    fn build(&self, app: &mut App) {
+        // when restarting, the chain continues from the state stored in the checkpoint.
+        let state = app.world.get_resource::<Checkpoint>()
+            .and_then(|checkpoint| checkpoint.nose_hoover.clone())
+            .unwrap_or_else(|| NoseHooverState::new(self.params.chain_length));
+        assert_eq!(state.xi.len(), self.params.chain_length, "the chain length differs from the checkpoint");
+
+        app.world.insert_resource(self.params);
+        app.world.insert_resource(state);
+        app.init_resource::<ExtendedEnergy>();
+
+        app.add_system_to_stage(IntegrationStages::BeginIntegration,
+            nose_hoover_close_step.label(NoseHooverSystems::CloseStep)
+                .after(IntegrationSystems::VelocityVerletIntegrateVelocity)
+                .after(IntegrationSystems::HalfKick)
+                .before(IntegrationSystems::CalcKineticEnergy));
+        app.add_system_to_stage(IntegrationStages::BeginIntegration,
+            nose_hoover_open_step.label(NoseHooverSystems::OpenStep)
+                .after(IntegrationSystems::CalcKineticEnergy));
+    }
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::setup::SetupPlugin;
+    #[allow(unused_imports)]
+    use crate::molecular_dynamics::integration::tests::spawn_ideal_gas;
+    #[allow(unused_imports)]
+    use crate::output::checkpoint::tests::{argon_app, assert_restart_matches};
+    #[allow(unused_imports)]
+    use bevy::tasks::TaskPool;
+    #[allow(unused_imports)]
+    use nalgebra::Vector3;
+
+    #[test]
+    fn test_conserved_energy_and_temperature() {
+        // an ideal argon gas starting at about 300 K, coupled to a 120 K chain.
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin { time_step: TimeStep::new(2e-15), ..default() });
+        app.add_plugin(IntegrationPlugin);
+        app.add_plugin(NoseHooverPlugin::new(NoseHooverChain::new(120.0, 1e-13).with_chain_length(4)));
+        spawn_ideal_gas(&mut app, 300.0);
+
+        let mut conserved = Vec::new();
+        let mut mean_temperature = 0.0;
+        for i in 0..4000 {
+            app.update();
+            conserved.push(conserved_energy(&app.world));
+            if i >= 2000 {
+                mean_temperature += app.world.get_resource::<Temperature>().unwrap().value / 2000.0;
+            }
+        }
+        let drift = (conserved[3999] / conserved[0] - 1.0).abs();
+        assert!(drift < 1e-6, "relative conserved energy drift {}", drift);
+        assert!((mean_temperature - 120.0).abs() < 6.0, "mean temperature {}", mean_temperature);
+        assert!(app.world.get_resource::<NoseHooverState>().unwrap().xi[0] != 0.0);
+    }
+
+    #[test]
+    fn test_nose_hoover_restart_matches_uninterrupted_run() {
+        let (app, restarted) = assert_restart_matches("nose_hoover_restart_test", |dir, restart| {
+            argon_app(dir, restart, Integrator::VelocityVerlet, |app| {
+                app.add_plugin(NoseHooverPlugin::new(NoseHooverChain::new(120.0, 1e-13)));
+            })
+        }, |_, _| {});
+        assert_eq!(
+            app.world.get_resource::<NoseHooverState>(),
+            restarted.world.get_resource::<NoseHooverState>()
+        );
+    }
+}
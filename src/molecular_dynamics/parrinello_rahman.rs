@@ -0,0 +1,233 @@
+//! The Parrinello-Rahman barostat, J. Appl. Phys. 52, 7182 (1981), which evolves the full
+//! cell matrix so that the box can change its shape, e.g. in solid phase transitions.
+//!
+//! The cell velocity v_g follows dv_g/dt = V (P_int - P) / W, and the box, the positions
+//! and the velocities follow dh/dt = v_g h, dx/dt = v + v_g x and dv/dt = F/m - v_g v.
+//! Only the upper triangle of v_g is used, so the box keeps the LAMMPS triclinic form and
+//! does not rotate. As in GROMACS, the mass of the cell is set from the compressibility
+//! and the period of the box oscillations, W = 3 V tau_p^2 / (4 pi^2 beta).
+
+use crate::atom::*;
+use crate::constant;
+use crate::molecular_dynamics::barostat::BarostatSystems;
+use crate::molecular_dynamics::energy::*;
+use crate::molecular_dynamics::integration::*;
+use crate::output::checkpoint::Checkpoint;
+use crate::simbox::SimBox;
+use bevy::prelude::*;
+use bevy::tasks::ComputeTaskPool;
+use nalgebra::Matrix3;
+
+/// the barostat parameters, in the units of the simulation. The compressibility is in
+/// inverse pressure units, and tau_p is the period of the oscillations of the box.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParrinelloRahman {
+    pub pressure: f64,
+    pub tau_p: f64,
+    pub compressibility: f64,
+}
+
+impl ParrinelloRahman {
+    pub fn new(pressure: f64, tau_p: f64, compressibility: f64) -> Self {
+        Self { pressure, tau_p, compressibility }
+    }
+
+    /// the mass of the cell for a box of the given volume.
+    pub fn cell_mass(&self, volume: f64) -> f64 {
+        3.0 * volume * self.tau_p * self.tau_p / (4.0 * constant::PI * constant::PI * self.compressibility)
+    }
+}
+
+/// the cell velocity and the mass of the cell, set from the initial volume.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParrinelloRahmanState {
+    pub v_g: Matrix3<f64>,
+    pub mass: f64,
+    /// the energy last added to the `ExtendedEnergy`.
+    pub energy: f64,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn parrinello_rahman_step(
+    pool: Res<ComputeTaskPool>,
+    batch_size: Res<BatchSize>,
+    barostat: Res<ParrinelloRahman>,
+    timestep: Res<TimeStep>,
+    pressure: Res<PressureTensor>,
+    mut simbox: ResMut<SimBox>,
+    mut state: ResMut<ParrinelloRahmanState>,
+    mut extended: ResMut<ExtendedEnergy>,
+    mut query: Query<(&mut Position, &mut Velocity), Without<Frozen>>,
+) {
+    let dt = timestep.delta;
+    let volume = simbox.volume();
+
+    // the cell is driven by the symmetric part of the pressure tensor
+    let p = (pressure.tensor + pressure.tensor.transpose()) / 2.0;
+    let force = (p - Matrix3::identity() * barostat.pressure).upper_triangle() * volume / state.mass;
+
+    // the extended energy of this step, with the cell velocity half way through its kick,
+    // so that it is evaluated at the same time as the kinetic and potential energies.
+    let energy = barostat.pressure * volume + 0.5 * state.mass * (state.v_g + force * dt / 2.0).norm_squared();
+    extended.value += energy - state.energy;
+    state.energy = energy;
+
+    state.v_g += force * dt;
+
+    let position_scaling = (state.v_g * dt).exp();
+    let velocity_scaling = (-state.v_g * dt).exp();
+    let origin = simbox.origin;
+    let h = position_scaling * simbox.cell_matrix();
+    simbox.set_cell_matrix(&h);
+    let new_box = *simbox;
+
+    query.par_for_each_mut(
+        &pool,
+        batch_size.0,
+        |(mut pos, mut vel)| {
+            pos.pos = origin + position_scaling * (pos.pos - origin);
+            vel.vel = velocity_scaling * vel.vel;
+            new_box.apply_boundaries(&mut pos.pos, &mut vel.vel);
+        }
+    );
+}
+
+pub struct ParrinelloRahmanPlugin {
+    pub barostat: ParrinelloRahman,
+}
+
+impl ParrinelloRahmanPlugin {
+    pub fn new(barostat: ParrinelloRahman) -> Self {
+        Self { barostat }
+    }
+}
+
+impl Plugin for ParrinelloRahmanPlugin {
+    fn build(&self, app: &mut App) {
+        assert!(!matches!(app.world.get_resource::<Integrator>(), Some(Integrator::MTK(_)) | Some(Integrator::Brownian(_))),
+            "the ParrinelloRahmanPlugin cannot be added to the MTK integrator, which has its own barostat, \
+            nor to Brownian dynamics, which has no velocities for the cell to scale");
+        let volume = app.world.get_resource::<SimBox>()
+            .expect("the SetupPlugin must be added before the ParrinelloRahmanPlugin")
+            .volume();
+        // when restarting, the cell continues from the checkpoint, with the mass set from
+        // the volume of the original run.
+        let state = app.world.get_resource::<Checkpoint>()
+            .and_then(|checkpoint| checkpoint.parrinello_rahman.clone())
+            .unwrap_or_else(|| ParrinelloRahmanState {
+                v_g: Matrix3::zeros(),
+                mass: self.barostat.cell_mass(volume),
+                energy: 0.0,
+            });
+        app.world.insert_resource(self.barostat);
+        app.world.insert_resource(state);
+        app.init_resource::<PressureTensor>();
+        app.init_resource::<ExtendedEnergy>();
+
+        // same ordering as `apply_barostat`.
+        app.add_system_to_stage(IntegrationStages::EndIntegration,
+            parrinello_rahman_step.label(BarostatSystems::ParrinelloRahman)
+                .after(IntegrationSystems::VelocityVerletIntegratePosition)
+                .after(IntegrationSystems::LeapfrogIntegratePosition)
+                .after(IntegrationSystems::PositionVerletIntegratePosition)
+                .after(IntegrationSystems::LangevinIntegrate)
+                .after(IntegrationSystems::RespaIntegrate)
+                .after(IntegrationSystems::BrownianIntegrate)
+                .after(IntegrationSystems::MTKIntegrate)
+                .before(IntegrationSystems::ClearForce));
+    }
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::molecular_dynamics::{lj_interaction::*, thermostat::*};
+    #[allow(unused_imports)]
+    use crate::{setup::SetupPlugin, units::UnitSystem};
+    #[allow(unused_imports)]
+    use crate::output::checkpoint::tests::{argon_app, assert_restart_matches};
+    #[allow(unused_imports)]
+    use bevy::tasks::TaskPool;
+    #[allow(unused_imports)]
+    use nalgebra::Vector3;
+
+    #[test]
+    fn test_fcc_argon_compression() {
+        // 2x2x2 unit cells of FCC argon at T = 0.1, in reduced units, compressed to P = 10.
+        let a = 1.56;
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin {
+            units: UnitSystem::argon_lj(),
+            time_step: TimeStep::new(0.005),
+            box_size: SimBox::new(Vector3::zeros(), 2.0 * a, 2.0 * a, 2.0 * a),
+            lj_cutoff: LJCutOff::new(1.3),
+            ..default()
+        });
+        app.add_plugin(LJPlugin);
+        app.add_plugin(IntegrationPlugin);
+        app.add_plugin(ThermostatPlugin::new(Thermostat::velocity_rescale(0.1, 0.5)));
+        app.add_plugin(ParrinelloRahmanPlugin::new(ParrinelloRahman::new(10.0, 1.0, 0.02)));
+
+        let basis = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(0.5, 0.5, 0.0),
+            Vector3::new(0.5, 0.0, 0.5),
+            Vector3::new(0.0, 0.5, 0.5),
+        ];
+        let mut id = 0;
+        for i in 0..2 {
+            for j in 0..2 {
+                for k in 0..2 {
+                    for b in basis.iter() {
+                        id += 1;
+                        let x = id as f64;
+                        app.world.spawn()
+                            .insert(Position { pos: (Vector3::new(i as f64, j as f64, k as f64) + b + Vector3::repeat(0.25)) * a })
+                            .insert(Velocity { vel: Vector3::new(x.sin(), x.cos(), (2.0 * x).sin()) * 0.3 })
+                            .insert(Force::default())
+                            .insert(OldForce::default())
+                            .insert(Mass { value: 1.0 })
+                            .insert(AtomType::new(String::from("Argon"), 1.0, 1.0))
+                            .insert(AtomID { id });
+                    }
+                }
+            }
+        }
+
+        let initial_volume = app.world.get_resource::<SimBox>().unwrap().volume();
+        let mut mean_pressure = 0.0;
+        let mut mean_volume = 0.0;
+        // the box oscillates with a period of about 400 steps, the means are taken over four periods
+        for i in 0..2000 {
+            app.update();
+            if i >= 400 {
+                mean_pressure += app.world.get_resource::<PressureTensor>().unwrap().pressure() / 1600.0;
+                mean_volume += app.world.get_resource::<SimBox>().unwrap().volume() / 1600.0;
+            }
+        }
+        assert!(mean_volume < 0.95 * initial_volume, "volume ratio {}", mean_volume / initial_volume);
+        assert!((mean_pressure - 10.0).abs() < 1.0, "mean pressure {}", mean_pressure);
+        // the FCC crystal is compressed without shear
+        let simbox = app.world.get_resource::<SimBox>().unwrap();
+        assert!(simbox.tilt.abs().max() < 0.05 * simbox.dimension.min(), "tilt {:?}", simbox.tilt);
+    }
+
+    #[test]
+    fn test_parrinello_rahman_restart_matches_uninterrupted_run() {
+        // the cell velocity and the mass of the cell, set from the initial volume, continue
+        // from the checkpoint
+        let (app, restarted) = assert_restart_matches("pr_restart_test", |dir, restart| {
+            argon_app(dir, restart, Integrator::VelocityVerlet, |app| {
+                app.add_plugin(ParrinelloRahmanPlugin::new(ParrinelloRahman::new(1e8, 1e-13, 1e-9)));
+            })
+        }, |_, _| {});
+        assert_eq!(
+            app.world.get_resource::<ParrinelloRahmanState>(),
+            restarted.world.get_resource::<ParrinelloRahmanState>()
+        );
+        let cell = |app: &App| app.world.get_resource::<SimBox>().unwrap().cell_matrix();
+        assert_eq!(cell(&app), cell(&restarted));
+    }
+}
@@ -0,0 +1,251 @@
+//! The r-RESPA multiple time step integrator of Tuckerman, Berne and Martyna,
+//! J. Chem. Phys. 97, 1990 (1992).
+//!
+//! The forces are split into levels, from the fastest, level 0, to the slowest, the outermost
+//! level. The force systems of the outermost level are the usual ones of the force stage and
+//! are integrated with the `TimeStep`, while each inner level has its own stage, added with
+//! `RespaAppExt::add_respa_force_system`, and is integrated with the time step of the level
+//! above divided by its loop count. For the levels 0, 1 and 2 with the loop counts n0 and n1,
+//! one step is
+//!
+//! kick F2 dt/2, n1 times [kick F1 h1/2, n0 times [kick F0 h0/2, drift h0, F0, kick F0 h0/2],
+//! F1, kick F1 h1/2], F2, kick F2 dt/2,
+//!
+//! with h1 = dt/n1 and h0 = h1/n0. As for the other integrators, the last outer half kick is
+//! done in `BeginIntegration` of the next update, once the force stage has evaluated F2.
+
+use crate::atom::*;
+use crate::molecular_dynamics::energy::*;
+use crate::molecular_dynamics::integration::*;
+use crate::simbox::SimBox;
+use crate::units::UnitSystem;
+use bevy::ecs::schedule::IntoSystemDescriptor;
+use bevy::prelude::*;
+use nalgebra::{Matrix3, Vector3};
+
+/// the loop counts of the levels, `loops[l]` is the number of steps of level l in one step
+/// of level l + 1, so there are `loops.len() + 1` levels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RespaParams {
+    pub loops: Vec<usize>,
+}
+
+impl RespaParams {
+    pub fn new(loops: Vec<usize>) -> Self {
+        Self { loops }
+    }
+
+    pub fn n_levels(&self) -> usize {
+        self.loops.len() + 1
+    }
+}
+
+/// the stages running the force systems of the inner levels.
+pub struct RespaStages {
+    pub stages: Vec<SystemStage>,
+}
+
+/// the forces of each level on an atom, the last one is the force of the outermost level.
+#[derive(Component, Clone)]
+pub struct LevelForces {
+    pub forces: Vec<Vector3<f64>>,
+}
+
+/// the potential energies and the virials of the inner levels at the current positions.
+#[derive(Clone, Default)]
+pub struct RespaState {
+    pub potential: Vec<f64>,
+    pub virial: Vec<Matrix3<f64>>,
+    pub initialized: bool,
+}
+
+pub trait RespaAppExt {
+    /// adds a force system to the given inner level, 0 being the fastest one.
+    fn add_respa_force_system<Params>(&mut self, level: usize, system: impl IntoSystemDescriptor<Params>) -> &mut Self;
+}
+
+impl RespaAppExt for App {
+    fn add_respa_force_system<Params>(&mut self, level: usize, system: impl IntoSystemDescriptor<Params>) -> &mut Self {
+        let mut stages = self.world.get_resource_mut::<RespaStages>()
+            .expect("the RESPA integrator must be chosen in the SetupPlugin and the IntegrationPlugin added first");
+        assert!(level < stages.stages.len(), "level {} is not an inner level, the outermost forces belong to the force stage", level);
+        stages.stages[level].add_system(system);
+        self
+    }
+}
+
+fn kick(world: &mut World, level: usize, h: f64) {
+    let mass_conversion = world.get_resource::<UnitSystem>().unwrap().mass_conversion();
+    let mut query = world.query_filtered::<(&mut Velocity, &LevelForces, &Mass), Without<Frozen>>();
+    for (mut vel, levels, mass) in query.iter_mut(world) {
+        vel.vel += levels.forces[level] / (mass_conversion * mass.value) / 2.0 * h;
+    }
+}
+
+fn drift(world: &mut World, h: f64) {
+    let simbox = *world.get_resource::<SimBox>().unwrap();
+    let mut query = world.query_filtered::<(&mut Position, &mut Velocity), Without<Frozen>>();
+    for (mut pos, mut vel) in query.iter_mut(world) {
+        pos.pos += vel.vel * h;
+        simbox.apply_boundaries(&mut pos.pos, &mut vel.vel);
+    }
+}
+
+/// evaluates the forces of an inner level, keeping its potential energy and virial apart
+/// from those of the step.
+fn evaluate(world: &mut World, stages: &mut RespaStages, state: &mut RespaState, level: usize) {
+    let potential = world.get_resource::<PotentialEnergy>().unwrap().value;
+    let virial = world.get_resource::<Virial>().unwrap().tensor;
+    for mut force in world.query::<&mut Force>().iter_mut(world) {
+        force.force = Vector3::zeros();
+    }
+
+    stages.stages[level].run(world);
+
+    for (force, mut levels) in world.query::<(&Force, &mut LevelForces)>().iter_mut(world) {
+        levels.forces[level] = force.force;
+    }
+    let mut total_potential = world.get_resource_mut::<PotentialEnergy>().unwrap();
+    state.potential[level] = total_potential.value - potential;
+    total_potential.value = potential;
+    let mut total_virial = world.get_resource_mut::<Virial>().unwrap();
+    state.virial[level] = total_virial.tensor - virial;
+    total_virial.tensor = virial;
+}
+
+fn respa_level(world: &mut World, stages: &mut RespaStages, state: &mut RespaState, loops: &[usize], level: usize, h: f64) {
+    kick(world, level, h);
+    if level == 0 {
+        drift(world, h);
+    } else {
+        let n = loops[level - 1];
+        for _ in 0..n {
+            respa_level(world, stages, state, loops, level - 1, h / n as f64);
+        }
+    }
+    evaluate(world, stages, state, level);
+    kick(world, level, h);
+}
+
+/// the outer half kick and the loops over the inner levels.
+pub fn respa_integrate(world: &mut World) {
+    let params = world.get_resource::<RespaParams>().unwrap().clone();
+    let dt = world.get_resource::<TimeStep>().unwrap().delta;
+    let outer = params.loops.len();
+
+    let new_atoms: Vec<Entity> = world
+        .query_filtered::<Entity, (With<Force>, Without<LevelForces>)>()
+        .iter(world)
+        .collect();
+    for entity in new_atoms {
+        world.entity_mut(entity).insert(LevelForces { forces: vec![Vector3::zeros(); outer + 1] });
+    }
+    for (force, mut levels) in world.query::<(&Force, &mut LevelForces)>().iter_mut(world) {
+        levels.forces[outer] = force.force;
+    }
+
+    world.resource_scope(|world, mut stages: Mut<RespaStages>| {
+        world.resource_scope(|world, mut state: Mut<RespaState>| {
+            if !state.initialized {
+                state.potential = vec![0.0; outer];
+                state.virial = vec![Matrix3::zeros(); outer];
+                for level in 0..outer {
+                    evaluate(world, &mut stages, &mut state, level);
+                }
+                state.initialized = true;
+            }
+
+            // the energy and the virial of the step include the inner levels at the current positions
+            world.get_resource_mut::<PotentialEnergy>().unwrap().value += state.potential.iter().sum::<f64>();
+            world.get_resource_mut::<Virial>().unwrap().tensor += state.virial.iter().sum::<Matrix3<f64>>();
+
+            kick(world, outer, dt);
+            if outer == 0 {
+                drift(world, dt);
+            } else {
+                let n = params.loops[outer - 1];
+                for _ in 0..n {
+                    respa_level(world, &mut stages, &mut state, &params.loops, outer - 1, dt / n as f64);
+                }
+            }
+        });
+    });
+
+    // the forces of the outermost level are stored like for the other integrators
+    for (mut force, levels) in world.query::<(&mut Force, &LevelForces)>().iter_mut(world) {
+        force.force = levels.forces[outer];
+    }
+    world.get_resource_mut::<CurStep>().unwrap().n += 1;
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::setup::SetupPlugin;
+    #[allow(unused_imports)]
+    use bevy::tasks::{ComputeTaskPool, TaskPool};
+
+    /// a stiff harmonic tether of every atom to the origin.
+    #[allow(dead_code)]
+    fn stiff_tether(mut potential: ResMut<PotentialEnergy>, mut query: Query<(&mut Force, &Position)>) {
+        for (mut force, pos) in query.iter_mut() {
+            force.force -= 100.0 * pos.pos;
+            potential.value += 50.0 * pos.pos.norm_squared();
+        }
+    }
+
+    /// a soft harmonic tether of every atom to (1, 0, 0).
+    #[allow(dead_code)]
+    fn soft_tether(mut potential: ResMut<PotentialEnergy>, mut query: Query<(&mut Force, &Position)>) {
+        let center = Vector3::new(1.0, 0.0, 0.0);
+        for (mut force, pos) in query.iter_mut() {
+            force.force -= pos.pos - center;
+            potential.value += 0.5 * (pos.pos - center).norm_squared();
+        }
+    }
+
+    #[test]
+    fn test_respa_energy_conservation() {
+        // the stiff tether has a period of 0.63, which the outer time step barely resolves.
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(
+            SetupPlugin {
+                units: UnitSystem::argon_lj(),
+                time_step: TimeStep::new(0.05),
+                box_size: SimBox::new(Vector3::repeat(-50.0), 100.0, 100.0, 100.0),
+                ..default()
+            }
+            .with_integrator(Integrator::Respa(RespaParams::new(vec![10])))
+        );
+        app.add_plugin(IntegrationPlugin);
+        app.add_stage_before(CoreStage::Update, "soft forces", SystemStage::parallel());
+        app.add_system_to_stage("soft forces", soft_tether);
+        app.add_respa_force_system(0, stiff_tether);
+        for i in 0..10 {
+            let x = i as f64;
+            app.world.spawn()
+                .insert(Position { pos: Vector3::new(x.sin(), x.cos(), 0.0) })
+                .insert(Velocity { vel: Vector3::new(0.0, 0.0, 1.0) })
+                .insert(Force::default())
+                .insert(OldForce::default())
+                .insert(Mass { value: 1.0 });
+        }
+
+        let mut energies = Vec::new();
+        for _ in 0..2000 {
+            app.update();
+            energies.push(total_energy(&app.world));
+        }
+        assert_eq!(app.world.get_resource::<CurStep>().unwrap().n, 2000);
+        // the impulses of the soft force make the energy oscillate with the stiff period,
+        // but it does not drift.
+        let max = energies.iter().cloned().fold(f64::MIN, f64::max);
+        let min = energies.iter().cloned().fold(f64::MAX, f64::min);
+        assert!((max - min) / energies[0] < 3e-3, "relative energy fluctuation {}", (max - min) / energies[0]);
+        let first = energies[..200].iter().sum::<f64>() / 200.0;
+        let last = energies[1800..].iter().sum::<f64>() / 200.0;
+        assert!((last - first).abs() / first < 1e-4, "relative energy drift {}", (last - first) / first);
+    }
+}
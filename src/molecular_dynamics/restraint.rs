@@ -0,0 +1,212 @@
+//! Harmonic position restraints, which tie atoms to reference coordinates with the energy
+//!
+//! E = k / 2 |r - r0|^2,
+//!
+//! where r - r0 is the minimum image of the displacement from the reference. Together with
+//! `Frozen` atoms, which the integrators do not move, they hold a substrate in place while a
+//! fluid moves over it, the restrained atoms still vibrating around their sites.
+//!
+//! Like the `fix spring/self` of LAMMPS, the restraints add their energy to the potential
+//! energy but nothing to the virial, as they are not pair interactions.
+
+use crate::atom::*;
+use crate::group::{select, Selection};
+use crate::molecular_dynamics::energy::PotentialEnergy;
+use crate::molecular_dynamics::lj_interaction::*;
+use crate::simbox::SimBox;
+use bevy::prelude::*;
+use nalgebra::Vector3;
+
+/// the reference position of an atom and the spring constant, in energy / length^2.
+#[derive(Clone, Copy, Component)]
+pub struct PositionRestraint {
+    pub reference: Vector3<f64>,
+    pub k: f64,
+}
+
+impl PositionRestraint {
+    pub fn new(reference: Vector3<f64>, k: f64) -> Self {
+        Self { reference, k }
+    }
+}
+
+/// freezes the selected atoms.
+pub fn freeze(world: &mut World, selection: &Selection) {
+    for entity in select(world, selection) {
+        world.entity_mut(entity).insert(Frozen);
+    }
+}
+
+/// restrains the selected atoms to their current positions.
+pub fn restrain(world: &mut World, selection: &Selection, k: f64) {
+    for entity in select(world, selection) {
+        let reference = world.get::<Position>(entity).expect("a restrained atom needs a position").pos;
+        world.entity_mut(entity).insert(PositionRestraint::new(reference, k));
+    }
+}
+
+pub fn calc_restraint_force(
+    simbox: Res<SimBox>,
+    mut potential: ResMut<PotentialEnergy>,
+    mut query: Query<(&mut Force, &Position, &PositionRestraint)>,
+) {
+    let mut restraint_energy = 0.0;
+    for (mut force, pos, restraint) in query.iter_mut() {
+        let d = simbox.minimum_image(pos.pos - restraint.reference);
+        force.force -= restraint.k * d;
+        restraint_energy += 0.5 * restraint.k * d.norm_squared();
+    }
+    potential.value += restraint_energy;
+}
+
+pub struct RestraintPlugin;
+
+impl Plugin for RestraintPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PotentialEnergy>();
+        add_force_stage(app);
+        app.add_system_to_stage(ForceStages::LJStage,
+            calc_restraint_force.label(ForceSystems::RestraintSystem)
+                .after(ForceSystems::LJSystem)
+                .after(ForceSystems::DPDSystem)
+                .after(ForceSystems::WallSystem)
+                .after(ForceSystems::ExternalFieldSystem)
+                .after(ForceSystems::UserForceSystem));
+    }
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::molecular_dynamics::{energy::*, integration::*};
+    #[allow(unused_imports)]
+    use crate::{setup::SetupPlugin, units::UnitSystem};
+    #[allow(unused_imports)]
+    use crate::output::checkpoint::tests::{argon_app, assert_restart_matches};
+    #[allow(unused_imports)]
+    use bevy::tasks::{ComputeTaskPool, TaskPool};
+
+    #[test]
+    fn test_restraints_and_frozen_atoms() {
+        // a frozen row of substrate atoms, a restrained atom above it, which vibrates around its
+        // site at the periodic boundary, and a free atom flying out of their reach.
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin {
+            units: UnitSystem::argon_lj(),
+            time_step: TimeStep::new(0.002),
+            box_size: SimBox::new(Vector3::zeros(), 8.0, 8.0, 8.0),
+            lj_cutoff: LJCutOff::new(2.5),
+            ..default()
+        });
+        app.add_plugin(LJPlugin);
+        app.add_plugin(RestraintPlugin);
+        app.add_plugin(IntegrationPlugin);
+        let spawn = |app: &mut App, id: u64, pos: Vector3<f64>, vel: Vector3<f64>| -> Entity {
+            app.world.spawn()
+                .insert(Position { pos })
+                .insert(Velocity { vel })
+                .insert(Force::default())
+                .insert(OldForce::default())
+                .insert(Mass { value: 1.0 })
+                .insert(AtomType::new(String::from("Argon"), 1.0, 1.0))
+                .insert(AtomID { id })
+                .id()
+        };
+        let substrate: Vec<Vector3<f64>> = (0..8).map(|i| Vector3::new(i as f64 + 0.5, 4.0, 1.0)).collect();
+        for (i, pos) in substrate.iter().enumerate() {
+            let atom = spawn(&mut app, i as u64 + 1, *pos, Vector3::new(1.0, 0.0, 0.0));
+            app.world.entity_mut(atom).insert(Frozen);
+        }
+        let site = Vector3::new(7.5, 4.0, 2.1);
+        let restrained = spawn(&mut app, 9, site, Vector3::new(1.0, 0.0, 0.0));
+        app.world.entity_mut(restrained).insert(PositionRestraint::new(site, 50.0));
+        spawn(&mut app, 10, Vector3::new(1.0, 4.0, 5.5), Vector3::new(1.0, 0.5, 0.0));
+
+        let mut energies = Vec::new();
+        for step in 0..1000 {
+            app.update();
+            energies.push(total_energy(&app.world));
+            if step == 0 {
+                // only the two moving atoms have degrees of freedom, all six of them, as the
+                // anchored atoms take up the momentum
+                let kinetic = app.world.get_resource::<KineticEnergy>().unwrap().value;
+                let temperature = app.world.get_resource::<Temperature>().unwrap().value;
+                assert!((temperature - 2.0 * kinetic / 6.0).abs() < 1e-12, "temperature {}", temperature);
+            }
+        }
+        let max = energies.iter().cloned().fold(f64::MIN, f64::max);
+        let min = energies.iter().cloned().fold(f64::MAX, f64::min);
+        assert!((max - min) / energies[0].abs() < 1e-3, "energy fluctuation {}", (max - min) / energies[0].abs());
+
+        let mut query = app.world.query::<(&Position, &AtomID)>();
+        for (pos, atom_id) in query.iter(&app.world) {
+            match atom_id.id {
+                1..=8 => assert_eq!(pos.pos, substrate[atom_id.id as usize - 1]),
+                9 => assert!(app.world.get_resource::<SimBox>().unwrap().minimum_image(pos.pos - site).norm() < 0.5, "restrained atom at {:?}", pos.pos),
+                _ => {},
+            }
+        }
+    }
+
+    #[test]
+    fn test_freeze_and_restrain_selections() {
+        // the selected atoms are frozen, or restrained to the positions they have when selected
+        let mut app = App::new();
+        app.add_plugin(SetupPlugin {
+            units: UnitSystem::argon_lj(),
+            box_size: SimBox::new(Vector3::zeros(), 8.0, 8.0, 8.0),
+            ..default()
+        });
+        for id in 1..=4 {
+            app.world.spawn()
+                .insert(Position { pos: Vector3::new(id as f64, 4.0, 4.0) })
+                .insert(AtomID { id });
+        }
+        freeze(&mut app.world, &Selection::parse("id 1:2").unwrap());
+        restrain(&mut app.world, &Selection::parse("id 2:3").unwrap(), 50.0);
+
+        let mut query = app.world.query::<(&Position, &AtomID, Option<&Frozen>, Option<&PositionRestraint>)>();
+        for (pos, atom_id, frozen, restraint) in query.iter(&app.world) {
+            assert_eq!(frozen.is_some(), atom_id.id <= 2, "atom {}", atom_id.id);
+            match restraint {
+                Some(restraint) => {
+                    assert!((2..=3).contains(&atom_id.id), "atom {}", atom_id.id);
+                    assert_eq!(restraint.reference, pos.pos);
+                    assert_eq!(restraint.k, 50.0);
+                }
+                None => assert!(!(2..=3).contains(&atom_id.id), "atom {}", atom_id.id),
+            }
+        }
+    }
+
+    #[test]
+    fn test_restrained_restart_matches_uninterrupted_run() {
+        // the frozen atoms stay in place and the restrained ones keep their sites after the restart
+        let (mut app, mut restarted) = assert_restart_matches("restraint_restart_test", |dir, restart| {
+            argon_app(dir, restart, Integrator::VelocityVerlet, |app| {
+                app.add_plugin(RestraintPlugin);
+            })
+        }, |id, atom| {
+            if id <= 5 {
+                atom.insert(Frozen);
+            } else if id <= 10 {
+                let reference = atom.get::<Position>().unwrap().pos;
+                atom.insert(PositionRestraint::new(reference, 1e-2));
+            }
+        });
+        let anchors = |app: &mut App| {
+            let mut query = app.world.query::<(&AtomID, Option<&Frozen>, Option<&PositionRestraint>)>();
+            let mut anchors: Vec<_> = query
+                .iter(&app.world)
+                .map(|(id, frozen, restraint)| {
+                    (id.id, frozen.is_some(), restraint.map(|restraint| (restraint.reference, restraint.k)))
+                })
+                .collect();
+            anchors.sort_by_key(|a| a.0);
+            anchors
+        };
+        assert_eq!(anchors(&mut app), anchors(&mut restarted));
+    }
+}
@@ -0,0 +1,356 @@
+//! Thermostats that couple the atoms to a heat bath once the velocities of a step are
+//! complete, before its kinetic energy is evaluated.
+//!
+//! The Berendsen thermostat relaxes the kinetic energy exponentially to the target, and the
+//! stochastic velocity rescaling of Bussi, Donadio and Parrinello, J. Chem. Phys. 126,
+//! 014101 (2007), adds a noise term so that the canonical ensemble is sampled. The Andersen
+//! thermostat instead gives single atoms new velocities from the Maxwell-Boltzmann
+//! distribution. Each acts on the temperature of all atoms, or of the atoms of its group
+//! only, if it has one.
+
+use crate::atom::*;
+use crate::group::{assert_group_defined, group_filter, AtomGroups, GroupMask};
+use crate::molecular_dynamics::energy::*;
+use crate::molecular_dynamics::integration::*;
+use crate::random::{RngPurpose, SimRng};
+use crate::units::UnitSystem;
+use bevy::prelude::*;
+use bevy::tasks::ComputeTaskPool;
+use nalgebra::Vector3;
+use rand::Rng;
+use rand_distr::{ChiSquared, Distribution, StandardNormal};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThermostatKind {
+    /// Berendsen weak coupling, the kinetic energy relaxes exponentially to the target.
+    Berendsen,
+    /// Bussi-Donadio-Parrinello stochastic velocity rescaling, samples the canonical ensemble.
+    VelocityRescale,
+}
+
+/// the thermostat parameters, the target temperature is in the temperature units
+/// and the coupling time in the time units of the simulation. The thermostat acts on
+/// the atoms of its group, or on all atoms.
+#[derive(Clone)]
+pub struct Thermostat {
+    pub kind: ThermostatKind,
+    pub target: f64,
+    pub tau: f64,
+    pub group: Option<String>,
+}
+
+impl Thermostat {
+    pub fn new(kind: ThermostatKind, target: f64, tau: f64) -> Self {
+        Self { kind, target, tau, group: None }
+    }
+
+    pub fn with_group(mut self, group: &str) -> Self {
+        self.group = Some(String::from(group));
+        self
+    }
+
+    pub fn berendsen(target: f64, tau: f64) -> Self {
+        Self::new(ThermostatKind::Berendsen, target, tau)
+    }
+
+    pub fn velocity_rescale(target: f64, tau: f64) -> Self {
+        Self::new(ThermostatKind::VelocityRescale, target, tau)
+    }
+
+    /// The kinetic energy after coupling the current kinetic energy to the bath for one time step.
+    fn rescaled_kinetic_energy<R: rand::Rng>(&self, kinetic: f64, n_dof: f64, dt: f64, kb: f64, rng: &mut R) -> f64 {
+        let target_kinetic = 0.5 * n_dof * kb * self.target;
+        match self.kind {
+            ThermostatKind::Berendsen => {
+                // the ratio of the target and the current temperature is the ratio of the kinetic energies.
+                let lambda_square = 1.0 + dt / self.tau * (target_kinetic / kinetic - 1.0);
+                kinetic * lambda_square.max(0.0)
+            }
+            ThermostatKind::VelocityRescale => {
+                // Bussi, Donadio and Parrinello, J. Chem. Phys. 126, 014101 (2007), appendix.
+                let c = (-dt / self.tau).exp();
+                let r1: f64 = StandardNormal.sample(rng);
+                let sum_r_square = if n_dof > 1.0 {
+                    ChiSquared::new(n_dof - 1.0).unwrap().sample(rng)
+                } else {
+                    0.0
+                };
+                kinetic
+                    + (1.0 - c) * (target_kinetic * (r1 * r1 + sum_r_square) / n_dof - kinetic)
+                    + 2.0 * r1 * (kinetic * target_kinetic / n_dof * c * (1.0 - c)).sqrt()
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn apply_thermostat(
+    pool: Res<ComputeTaskPool>,
+    batch_size: Res<BatchSize>,
+    thermostat: Res<Thermostat>,
+    timestep: Res<TimeStep>,
+    units: Res<UnitSystem>,
+    mut sim_rng: ResMut<SimRng>,
+    mut extended: ResMut<ExtendedEnergy>,
+    groups: Option<Res<AtomGroups>>,
+    mut query: Query<(&mut Velocity, &Mass, Option<&GroupMask>), Without<Frozen>>,
+    anchored: Query<(), Anchored>,
+) {
+    let mass_conversion = units.mass_conversion();
+    let filter = group_filter(&groups, &thermostat.group);
+    let kinetic: f64 = query
+        .iter()
+        .filter(|(_, _, mask)| filter.matches(*mask))
+        .map(|(vel, mass, _)| 0.5 * mass_conversion * mass.value * vel.vel.norm_squared())
+        .sum();
+    if kinetic <= 0.0 {
+        return;
+    }
+
+    // a group is part of a larger system, which takes up the momentum of its atoms.
+    let momentum_conserved = thermostat.group.is_none() && anchored.is_empty();
+    let n_dof = degrees_of_freedom(query.iter().filter(|(_, _, mask)| filter.matches(*mask)).count(), momentum_conserved);
+    let new_kinetic = thermostat.rescaled_kinetic_energy(kinetic, n_dof, timestep.delta, units.boltzmann(), &mut sim_rng.rng);
+    let alpha = (new_kinetic / kinetic).sqrt();
+
+    // the energy taken from the bath is stored, so that the conserved energy can be monitored.
+    extended.value -= new_kinetic - kinetic;
+
+    query.par_for_each_mut(
+        &pool,
+        batch_size.0,
+        |(mut vel, _mass, mask)| {
+            if filter.matches(mask) {
+                vel.vel *= alpha;
+            }
+        }
+    );
+}
+
+/// the Andersen thermostat, each atom collides with the bath with the given frequency,
+/// in inverse time units, and gets new velocities drawn from the Maxwell-Boltzmann
+/// distribution at the target temperature. Only the atoms of its group collide, if it has one.
+#[derive(Clone)]
+pub struct AndersenThermostat {
+    pub target: f64,
+    pub collision_frequency: f64,
+    pub group: Option<String>,
+}
+
+impl AndersenThermostat {
+    pub fn new(target: f64, collision_frequency: f64) -> Self {
+        Self { target, collision_frequency, group: None }
+    }
+
+    pub fn with_group(mut self, group: &str) -> Self {
+        self.group = Some(String::from(group));
+        self
+    }
+
+    /// the probability that an atom collides with the bath during a time step.
+    pub fn collision_probability(&self, dt: f64) -> f64 {
+        1.0 - (-self.collision_frequency * dt).exp()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn apply_andersen_thermostat(
+    pool: Res<ComputeTaskPool>,
+    batch_size: Res<BatchSize>,
+    thermostat: Res<AndersenThermostat>,
+    timestep: Res<TimeStep>,
+    cur_step: Res<CurStep>,
+    units: Res<UnitSystem>,
+    sim_rng: Res<SimRng>,
+    mut extended: ResMut<ExtendedEnergy>,
+    groups: Option<Res<AtomGroups>>,
+    mut query: Query<(&mut Velocity, &Mass, &AtomID, Option<&GroupMask>), Without<Frozen>>,
+) {
+    let mass_conversion = units.mass_conversion();
+    let filter = group_filter(&groups, &thermostat.group);
+    let kinetic = |query: &Query<(&mut Velocity, &Mass, &AtomID, Option<&GroupMask>), Without<Frozen>>| -> f64 {
+        query
+            .iter()
+            .map(|(vel, mass, _id, _mask)| 0.5 * mass_conversion * mass.value * vel.vel.norm_squared())
+            .sum()
+    };
+    let old_kinetic = kinetic(&query);
+
+    let probability = thermostat.collision_probability(timestep.delta);
+    let kt = units.boltzmann() * thermostat.target;
+    let step = cur_step.n;
+    query.par_for_each_mut(
+        &pool,
+        batch_size.0,
+        |(mut vel, mass, atom_id, mask)| {
+            let mut rng = sim_rng.atom_rng(RngPurpose::Andersen, step, atom_id.id);
+            if filter.matches(mask) && rng.gen::<f64>() < probability {
+                let sigma = (kt / (mass_conversion * mass.value)).sqrt();
+                vel.vel = sigma * Vector3::new(
+                    StandardNormal.sample(&mut rng),
+                    StandardNormal.sample(&mut rng),
+                    StandardNormal.sample(&mut rng),
+                );
+            }
+        }
+    );
+
+    // the energy taken from the bath, as for the rescaling thermostats.
+    extended.value -= kinetic(&query) - old_kinetic;
+}
+
+#[derive(PartialEq, Clone, Hash, Debug, Eq, SystemLabel)]
+pub enum ThermostatSystems {
+    Thermostat,
+    Andersen,
+}
+
+pub struct ThermostatPlugin {
+    pub thermostat: Thermostat,
+}
+
+impl ThermostatPlugin {
+    pub fn new(thermostat: Thermostat) -> Self {
+        Self { thermostat }
+    }
+}
+
+impl Plugin for ThermostatPlugin {
+    fn build(&self, app: &mut App) {
+        if let Some(group) = &self.thermostat.group {
+            assert_group_defined(app, group);
+        }
+        app.world.insert_resource(self.thermostat.clone());
+        app.init_resource::<ExtendedEnergy>();
+
+        // the velocities are rescaled once the velocities of the step are complete,
+        // and before the kinetic energy of the step is evaluated.
+        app.add_system_to_stage(IntegrationStages::BeginIntegration,
+            apply_thermostat.label(ThermostatSystems::Thermostat)
+                .after(IntegrationSystems::VelocityVerletIntegrateVelocity)
+                .after(IntegrationSystems::HalfKick)
+                .before(IntegrationSystems::CalcKineticEnergy));
+    }
+}
+
+pub struct AndersenThermostatPlugin {
+    pub thermostat: AndersenThermostat,
+}
+
+impl AndersenThermostatPlugin {
+    pub fn new(thermostat: AndersenThermostat) -> Self {
+        Self { thermostat }
+    }
+}
+
+impl Plugin for AndersenThermostatPlugin {
+    fn build(&self, app: &mut App) {
+        if let Some(group) = &self.thermostat.group {
+            assert_group_defined(app, group);
+        }
+        app.world.insert_resource(self.thermostat.clone());
+        app.init_resource::<ExtendedEnergy>();
+
+        // the collisions happen once the velocities of the step are complete.
+        app.add_system_to_stage(IntegrationStages::BeginIntegration,
+            apply_andersen_thermostat.label(ThermostatSystems::Andersen)
+                .after(IntegrationSystems::VelocityVerletIntegrateVelocity)
+                .after(IntegrationSystems::HalfKick)
+                .before(IntegrationSystems::CalcKineticEnergy));
+    }
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::setup::SetupPlugin;
+    #[allow(unused_imports)]
+    use crate::molecular_dynamics::integration::tests::spawn_ideal_gas;
+    #[allow(unused_imports)]
+    use bevy::tasks::TaskPool;
+    #[allow(unused_imports)]
+    use nalgebra::Vector3;
+
+    /// an ideal argon gas starting at about 300 K, coupled to a 120 K bath.
+    #[allow(dead_code)]
+    fn hot_gas_app(thermostat: impl Plugin) -> App {
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin { time_step: TimeStep::new(2e-15), ..default() });
+        app.add_plugin(IntegrationPlugin);
+        app.add_plugin(thermostat);
+        spawn_ideal_gas(&mut app, 300.0);
+        app
+    }
+
+    #[test]
+    fn test_thermostats_reach_target() {
+        let mut app = hot_gas_app(ThermostatPlugin::new(Thermostat::berendsen(120.0, 2e-14)));
+        for _ in 0..200 {
+            app.update();
+        }
+        let temperature = app.world.get_resource::<Temperature>().unwrap().value;
+        assert!((temperature - 120.0).abs() < 0.1, "berendsen temperature {}", temperature);
+
+        let mut app = hot_gas_app(ThermostatPlugin::new(Thermostat::velocity_rescale(120.0, 2e-14)));
+        let mut mean = 0.0;
+        for i in 0..2000 {
+            app.update();
+            if i >= 1000 {
+                mean += app.world.get_resource::<Temperature>().unwrap().value / 1000.0;
+            }
+        }
+        assert!((mean - 120.0).abs() < 5.0, "velocity rescale mean temperature {}", mean);
+
+        // without forces the kinetic energy only changes through the bath.
+        let conserved = crate::molecular_dynamics::energy::conserved_energy(&app.world);
+        let initial: f64 = (0..100).map(|i| {
+            let x = i as f64;
+            0.5 * crate::constant::AMU * 39.948 * 300.0f64.powi(2) * (x.sin().powi(2) + x.cos().powi(2) + (2.0 * x).sin().powi(2))
+        }).sum();
+        assert!((conserved / initial - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_group_thermostat() {
+        // with tau equal to the time step the Berendsen thermostat takes its group to the target
+        // at once, with all three degrees of freedom of each of its atoms.
+        let dt = 2e-15;
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin { time_step: TimeStep::new(dt), ..default() });
+        app.add_plugin(IntegrationPlugin);
+        app.add_plugin(crate::group::GroupPlugin::new("half", crate::group::Selection::parse("id 1:50").unwrap()));
+        app.add_plugin(ThermostatPlugin::new(Thermostat::berendsen(120.0, dt).with_group("half")));
+        spawn_ideal_gas(&mut app, 300.0);
+
+        let mut query = app.world.query::<(&Velocity, &Mass, &AtomID)>();
+        let mut kinetic = |in_group: bool, world: &World| -> f64 {
+            query.iter(world)
+                .filter(|(.., atom_id)| (atom_id.id <= 50) == in_group)
+                .map(|(vel, mass, _)| 0.5 * crate::constant::AMU * mass.value * vel.vel.norm_squared())
+                .sum()
+        };
+        let others = kinetic(false, &app.world);
+        app.update();
+
+        let expected = 1.5 * 50.0 * crate::constant::BOLTZCONST * 120.0;
+        let group_kinetic = kinetic(true, &app.world);
+        assert!((group_kinetic / expected - 1.0).abs() < 1e-9, "group kinetic energy {}", group_kinetic);
+        assert_eq!(kinetic(false, &app.world), others);
+    }
+
+    #[test]
+    fn test_andersen_thermostat() {
+        let mut app = hot_gas_app(AndersenThermostatPlugin::new(AndersenThermostat::new(120.0, 1e13)));
+        let mut mean = 0.0;
+        for i in 0..2000 {
+            app.update();
+            if i >= 1000 {
+                mean += app.world.get_resource::<Temperature>().unwrap().value / 1000.0;
+            }
+        }
+        assert!((mean - 120.0).abs() < 6.0, "andersen mean temperature {}", mean);
+    }
+}
@@ -0,0 +1,293 @@
+//! Confining walls for slab and pore simulations, with the wall potentials of the LAMMPS
+//! `fix wall` commands. Each wall is a plane, either a face of the box, which follows the box
+//! when it changes, or an arbitrary plane, and acts on the atoms at a distance r < rc on the
+//! side its normal points to, with
+//!
+//! LJ 9-3:     E = epsilon [2/15 (sigma/r)^9 - (sigma/r)^3],
+//! LJ 10-4-3:  E = 2 pi epsilon [2/5 (sigma/r)^10 - (sigma/r)^4 - sqrt(2) sigma^3 / (3 (r + 0.61 sigma / sqrt(2))^3)],
+//! harmonic:   E = k (r - rc)^2.
+//!
+//! The LJ energies are shifted to zero at the cutoff, and the harmonic wall only pushes the
+//! atoms closer than rc away from it.
+
+use crate::atom::*;
+use crate::constant;
+use crate::molecular_dynamics::energy::{PotentialEnergy, Virial};
+use crate::molecular_dynamics::lj_interaction::*;
+use crate::simbox::SimBox;
+use bevy::prelude::*;
+use nalgebra::Vector3;
+
+/// where a wall is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WallPosition {
+    /// the lower or the upper face of the box along a non periodic axis, facing into the box.
+    Face { axis: usize, high: bool },
+    /// the plane through a point, facing the side the normal points to.
+    Plane { point: Vector3<f64>, normal: Vector3<f64> },
+}
+
+impl WallPosition {
+    /// a point of the wall and its unit normal, pointing to the atoms it acts on.
+    pub fn plane(&self, simbox: &SimBox) -> (Vector3<f64>, Vector3<f64>) {
+        match *self {
+            WallPosition::Face { axis, high } => {
+                // the face is spanned by the two other edge vectors, so its normal is the
+                // reciprocal vector of the axis, the row of the inverse cell matrix.
+                let h = simbox.cell_matrix();
+                let inverse = h.try_inverse().expect("the box has a zero volume");
+                let normal = inverse.row(axis).transpose().normalize();
+                if high {
+                    (simbox.origin + h.column(axis), -normal)
+                } else {
+                    (simbox.origin, normal)
+                }
+            }
+            WallPosition::Plane { point, normal } => (point, normal.normalize()),
+        }
+    }
+}
+
+/// the interaction of the atoms with a wall, in the units of the simulation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WallPotential {
+    LJ93 { epsilon: f64, sigma: f64, cutoff: f64 },
+    LJ1043 { epsilon: f64, sigma: f64, cutoff: f64 },
+    Harmonic { k: f64, cutoff: f64 },
+}
+
+impl WallPotential {
+    pub fn cutoff(&self) -> f64 {
+        match *self {
+            WallPotential::LJ93 { cutoff, .. }
+            | WallPotential::LJ1043 { cutoff, .. }
+            | WallPotential::Harmonic { cutoff, .. } => cutoff,
+        }
+    }
+
+    /// the energy and the force pushing the atom away from the wall, -dE/dr, at the distance r.
+    fn unshifted(&self, r: f64) -> (f64, f64) {
+        match *self {
+            WallPotential::LJ93 { epsilon, sigma, .. } => {
+                let sr3 = (sigma / r).powi(3);
+                let sr9 = sr3 * sr3 * sr3;
+                (epsilon * (2.0 / 15.0 * sr9 - sr3), epsilon * (6.0 / 5.0 * sr9 - 3.0 * sr3) / r)
+            }
+            WallPotential::LJ1043 { epsilon, sigma, .. } => {
+                let sr4 = (sigma / r).powi(4);
+                let sr10 = sr4 * sr4 * (sigma / r).powi(2);
+                let shift = r + 0.61 / constant::SQRT2 * sigma;
+                let sigma3 = sigma.powi(3);
+                let energy = 2.0 * constant::PI * epsilon
+                    * (2.0 / 5.0 * sr10 - sr4 - constant::SQRT2 * sigma3 / (3.0 * shift.powi(3)));
+                let force = 2.0 * constant::PI * epsilon
+                    * ((4.0 * sr10 - 4.0 * sr4) / r - constant::SQRT2 * sigma3 / shift.powi(4));
+                (energy, force)
+            }
+            WallPotential::Harmonic { k, cutoff } => (k * (r - cutoff).powi(2), -2.0 * k * (r - cutoff)),
+        }
+    }
+
+    /// the energy, shifted to zero at the cutoff, and the force at the distance r from the wall.
+    pub fn energy_and_force(&self, r: f64) -> (f64, f64) {
+        let (energy, force) = self.unshifted(r);
+        let (cutoff_energy, _) = self.unshifted(self.cutoff());
+        (energy - cutoff_energy, force)
+    }
+}
+
+/// a wall, acting on the atoms of the given types, or on all atoms if no type is given.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Wall {
+    pub position: WallPosition,
+    pub potential: WallPotential,
+    pub atom_types: Vec<String>,
+}
+
+impl Wall {
+    pub fn new(position: WallPosition, potential: WallPotential) -> Self {
+        Self { position, potential, atom_types: Vec::new() }
+    }
+
+    pub fn with_atom_types(mut self, atom_types: Vec<String>) -> Self {
+        self.atom_types = atom_types;
+        self
+    }
+
+    pub fn acts_on(&self, atom_type: &AtomType) -> bool {
+        self.atom_types.is_empty() || self.atom_types.contains(&atom_type.name)
+    }
+}
+
+/// all the walls of the simulation.
+#[derive(Clone, Default)]
+pub struct Walls {
+    pub walls: Vec<Wall>,
+}
+
+pub fn calc_wall_force(
+    walls: Res<Walls>,
+    simbox: Res<SimBox>,
+    mut potential: ResMut<PotentialEnergy>,
+    mut virial: ResMut<Virial>,
+    mut query: Query<(&mut Force, &Position, &AtomType)>,
+) {
+    let mut wall_energy = 0.0;
+    for wall in walls.walls.iter() {
+        let (point, normal) = wall.position.plane(&simbox);
+        let cutoff = wall.potential.cutoff();
+        for (mut force, pos, atom_type) in query.iter_mut() {
+            if !wall.acts_on(atom_type) {
+                continue;
+            }
+            // the distance to the wall, the atoms behind the wall do not feel it.
+            let r = (pos.pos - point).dot(&normal);
+            if r <= 0.0 || r >= cutoff {
+                continue;
+            }
+            let (energy, wall_force) = wall.potential.energy_and_force(r);
+            let f = wall_force * normal;
+            force.force += f;
+            // like for a pair, with the separation from the wall to the atom
+            virial.tensor += r * normal * f.transpose();
+            wall_energy += energy;
+        }
+    }
+    potential.value += wall_energy;
+}
+
+/// adds a wall, several walls are added with several plugins.
+pub struct WallPlugin {
+    pub wall: Wall,
+}
+
+impl WallPlugin {
+    pub fn new(wall: Wall) -> Self {
+        Self { wall }
+    }
+}
+
+impl Plugin for WallPlugin {
+    fn build(&self, app: &mut App) {
+        // the atoms would go through a face of a periodic axis, into the back of the wall.
+        if let WallPosition::Face { axis, .. } = self.wall.position {
+            let simbox = app.world.get_resource::<SimBox>().expect("the SetupPlugin must be added before the WallPlugin");
+            assert!(!simbox.boundary[axis].is_periodic(), "a wall on a face of the box needs a non periodic axis, the axis {} is periodic", axis);
+        }
+        app.init_resource::<PotentialEnergy>();
+        app.init_resource::<Virial>();
+        add_force_stage(app);
+
+        // every WallPlugin pushes its wall to `Walls`, and a single `calc_wall_force` adds up
+        // the forces of all of them in one pass over the atoms.
+        if !app.world.contains_resource::<Walls>() {
+            app.init_resource::<Walls>();
+            app.add_system_to_stage(ForceStages::LJStage,
+                calc_wall_force.label(ForceSystems::WallSystem)
+                    .after(ForceSystems::LJSystem)
+                    .after(ForceSystems::DPDSystem));
+        }
+        app.world.get_resource_mut::<Walls>().unwrap().walls.push(self.wall.clone());
+    }
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::molecular_dynamics::{energy::total_energy, integration::*};
+    #[allow(unused_imports)]
+    use crate::{setup::SetupPlugin, simbox::Boundary, units::UnitSystem};
+    #[allow(unused_imports)]
+    use bevy::tasks::{ComputeTaskPool, TaskPool};
+
+    #[test]
+    fn test_walls() {
+        // the forces are the derivatives of the energies
+        let potentials = [
+            WallPotential::LJ93 { epsilon: 1.0, sigma: 1.0, cutoff: 2.5 },
+            WallPotential::LJ1043 { epsilon: 1.0, sigma: 1.0, cutoff: 2.5 },
+            WallPotential::Harmonic { k: 10.0, cutoff: 1.0 },
+        ];
+        for potential in potentials.iter() {
+            for r in [0.8, 0.95, 1.2, 2.0] {
+                if r >= potential.cutoff() {
+                    continue;
+                }
+                let h = 1e-6;
+                let numerical = -(potential.energy_and_force(r + h).0 - potential.energy_and_force(r - h).0) / (2.0 * h);
+                let (_, force) = potential.energy_and_force(r);
+                assert!((numerical - force).abs() < 1e-5 * force.abs().max(1.0), "{:?} at {}: {} {}", potential, r, numerical, force);
+            }
+        }
+
+        // the faces of a tilted box face into it, with the normal perpendicular to the two
+        // edge vectors spanning them
+        let simbox = SimBox::triclinic(Vector3::new(1.0, -2.0, 0.5), Vector3::new(4.0, 5.0, 6.0), Vector3::new(1.0, -1.5, 2.0));
+        let h = simbox.cell_matrix();
+        for axis in 0..3 {
+            for high in [false, true] {
+                let (point, normal) = WallPosition::Face { axis, high }.plane(&simbox);
+                let s = simbox.fractional(point - simbox.origin);
+                assert!((s[axis] - if high { 1.0 } else { 0.0 }).abs() < 1e-12, "{} {} {:?}", axis, high, s);
+                assert!(((normal.norm() - 1.0).abs()) < 1e-12);
+                for edge in (0..3).filter(|&d| d != axis) {
+                    assert!(normal.dot(&h.column(edge)).abs() < 1e-12, "{} {} {:?}", axis, high, normal);
+                }
+                let center = simbox.from_fractional(Vector3::repeat(0.5));
+                assert!(normal.dot(&(center - point)) > 0.0, "{} {} {:?}", axis, high, normal);
+            }
+        }
+
+        // an argon atom bounces between two LJ 9-3 walls on the z faces, while a neon atom,
+        // which the walls do not act on, leaves the box.
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin {
+            units: UnitSystem::argon_lj(),
+            time_step: TimeStep::new(0.001),
+            box_size: SimBox::new(Vector3::zeros(), 10.0, 10.0, 5.0)
+                .with_boundary([Boundary::Periodic, Boundary::Periodic, Boundary::Fixed]),
+            ..default()
+        });
+        let lj93 = WallPotential::LJ93 { epsilon: 1.0, sigma: 1.0, cutoff: 2.0 };
+        for high in [false, true] {
+            app.add_plugin(WallPlugin::new(
+                Wall::new(WallPosition::Face { axis: 2, high }, lj93).with_atom_types(vec![String::from("Argon")])
+            ));
+        }
+        app.add_plugin(IntegrationPlugin);
+        for (id, name) in [(1, "Argon"), (2, "Neon")] {
+            app.world.spawn()
+                .insert(Position { pos: Vector3::new(2.0 * id as f64, 5.0, 2.5) })
+                .insert(Velocity { vel: Vector3::new(0.0, 0.0, 2.0) })
+                .insert(Force::default())
+                .insert(OldForce::default())
+                .insert(Mass { value: 1.0 })
+                .insert(AtomType::new(String::from(name), 1.0, 1.0))
+                .insert(AtomID { id });
+        }
+
+        let mut energies = Vec::new();
+        let mut max_virial: f64 = 0.0;
+        for _ in 0..5000 {
+            app.update();
+            energies.push(total_energy(&app.world));
+            max_virial = max_virial.max(app.world.get_resource::<Virial>().unwrap().tensor[(2, 2)].abs());
+        }
+        let max = energies.iter().cloned().fold(f64::MIN, f64::max);
+        let min = energies.iter().cloned().fold(f64::MAX, f64::min);
+        // the energy fluctuates with the steep repulsion of the walls during the bounces
+        assert!((max - min) / energies[0] < 1e-3, "relative energy fluctuation {}", (max - min) / energies[0]);
+        assert!(max_virial > 0.0);
+
+        let mut query = app.world.query::<(&Position, &AtomType)>();
+        for (pos, atom_type) in query.iter(&app.world) {
+            if atom_type.name == "Argon" {
+                assert!(pos.pos.z > 0.0 && pos.pos.z < 5.0, "argon at {:?}", pos.pos);
+            } else {
+                assert!((pos.pos.z - 12.5).abs() < 1e-9, "neon at {:?}", pos.pos);
+            }
+        }
+    }
+}
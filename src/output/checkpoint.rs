@@ -0,0 +1,683 @@
+//! Binary checkpoint files, used to restart a simulation exactly where it stopped.
+//!
+//! All floating point values are stored with their full bit pattern (little endian), and the
+//! atoms are stored in the order the queries visit them and spawned again in that order, so
+//! the forces and the sums over the atoms are added up in the same order after a restart. A
+//! restarted trajectory thus reproduces the uninterrupted one bit for bit, as long as the
+//! atoms carry no other components than those the checkpoint stores, and the groups.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use nalgebra::{Matrix3, Vector3};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::atom::*;
+use crate::molecular_dynamics::energy::ExtendedEnergy;
+use crate::molecular_dynamics::atom_changes::NextAtomID;
+use crate::molecular_dynamics::brownian::Diffusion;
+use crate::molecular_dynamics::integration::{CurStep, OldForce, SimTime, TimeStep};
+use crate::molecular_dynamics::mtk::MTKState;
+use crate::molecular_dynamics::nose_hoover::NoseHooverState;
+use crate::molecular_dynamics::parrinello_rahman::ParrinelloRahmanState;
+use crate::molecular_dynamics::restraint::PositionRestraint;
+use crate::output::file::OutputStages;
+use crate::random::{RandomSeed, SimRng};
+use crate::simbox::{Boundary, SimBox};
+use bevy::prelude::*;
+
+const MAGIC: &[u8; 4] = b"MDCK";
+const VERSION: u32 = 11;
+
+/// the per atom state stored in a checkpoint.
+#[derive(Clone)]
+pub struct AtomState {
+    pub id: u64,
+    pub molecule: Option<u64>,
+    pub atom_type: AtomType,
+    pub mass: f64,
+    /// the charge of the atoms the electric fields act on.
+    pub charge: Option<f64>,
+    pub pos: Vector3<f64>,
+    pub vel: Vector3<f64>,
+    pub old_force: Vector3<f64>,
+    pub frozen: bool,
+    pub restraint: Option<PositionRestraint>,
+    /// the diffusion coefficient of the atoms with their own in Brownian dynamics.
+    pub diffusion: Option<f64>,
+}
+
+/// The full state of a simulation at the end of a step. The positions and velocities are
+/// stored as the integrator keeps them, e.g. at half steps for leapfrog and position verlet,
+/// so a restart has to use the same integrator.
+#[derive(Clone)]
+pub struct Checkpoint {
+    pub cur_step: u64,
+    /// the simulated time, and the time step of the next step, which may have been adapted.
+    pub time: f64,
+    pub time_step: f64,
+    pub simbox: SimBox,
+    pub rng: SimRng,
+    /// the energy exchanged with the thermostats and barostats so far.
+    pub extended_energy: f64,
+    pub nose_hoover: Option<NoseHooverState>,
+    /// the cell velocity and the chains of the MTK integrator.
+    pub mtk: Option<MTKState>,
+    /// the cell velocity and the mass of the cell of the Parrinello-Rahman barostat.
+    pub parrinello_rahman: Option<ParrinelloRahmanState>,
+    /// the id of the next atom added during the run, once atoms were added or deleted.
+    pub next_atom_id: Option<u64>,
+    /// atoms in the order the queries visit them.
+    pub atoms: Vec<AtomState>,
+}
+
+impl Checkpoint {
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        writer.write_all(MAGIC)?;
+        writer.write_u32::<LittleEndian>(VERSION)?;
+        writer.write_u64::<LittleEndian>(self.cur_step)?;
+        writer.write_f64::<LittleEndian>(self.time)?;
+        writer.write_f64::<LittleEndian>(self.time_step)?;
+        write_vector(writer, &self.simbox.origin)?;
+        write_vector(writer, &self.simbox.dimension)?;
+        write_vector(writer, &self.simbox.tilt)?;
+        for boundary in self.simbox.boundary.iter() {
+            writer.write_u8(write_boundary(boundary))?;
+        }
+        write_rng(writer, &self.rng)?;
+        writer.write_f64::<LittleEndian>(self.extended_energy)?;
+        write_nose_hoover(writer, &self.nose_hoover)?;
+        write_mtk(writer, &self.mtk)?;
+        write_parrinello_rahman(writer, &self.parrinello_rahman)?;
+        write_optional_u64(writer, self.next_atom_id)?;
+
+        writer.write_u64::<LittleEndian>(self.atoms.len() as u64)?;
+        for atom in self.atoms.iter() {
+            writer.write_u64::<LittleEndian>(atom.id)?;
+            write_optional_u64(writer, atom.molecule)?;
+            let name = atom.atom_type.name.as_bytes();
+            writer.write_u64::<LittleEndian>(name.len() as u64)?;
+            writer.write_all(name)?;
+            writer.write_f64::<LittleEndian>(atom.atom_type.lj_params.sigma)?;
+            writer.write_f64::<LittleEndian>(atom.atom_type.lj_params.epsilon)?;
+            writer.write_f64::<LittleEndian>(atom.mass)?;
+            write_optional_f64(writer, atom.charge)?;
+            write_vector(writer, &atom.pos)?;
+            write_vector(writer, &atom.vel)?;
+            write_vector(writer, &atom.old_force)?;
+            writer.write_u8(atom.frozen as u8)?;
+            write_restraint(writer, &atom.restraint)?;
+            write_optional_f64(writer, atom.diffusion)?;
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, io::Error> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a checkpoint file"));
+        }
+        let version = reader.read_u32::<LittleEndian>()?;
+        if version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported checkpoint version {}", version),
+            ));
+        }
+
+        let cur_step = reader.read_u64::<LittleEndian>()?;
+        let time = reader.read_f64::<LittleEndian>()?;
+        let time_step = reader.read_f64::<LittleEndian>()?;
+        let origin = read_vector(reader)?;
+        let dimension = read_vector(reader)?;
+        let tilt = read_vector(reader)?;
+        let mut boundary = [Boundary::Periodic; 3];
+        for b in boundary.iter_mut() {
+            *b = read_boundary(reader.read_u8()?)?;
+        }
+        let simbox = SimBox { origin, dimension, tilt, boundary };
+        let rng = read_rng(reader)?;
+        let extended_energy = reader.read_f64::<LittleEndian>()?;
+        let nose_hoover = read_nose_hoover(reader)?;
+        let mtk = read_mtk(reader)?;
+        let parrinello_rahman = read_parrinello_rahman(reader)?;
+        let next_atom_id = read_optional_u64(reader)?;
+
+        let n_atoms = reader.read_u64::<LittleEndian>()?;
+        let mut atoms = Vec::with_capacity(n_atoms as usize);
+        for _ in 0..n_atoms {
+            let id = reader.read_u64::<LittleEndian>()?;
+            let molecule = read_optional_u64(reader)?;
+            let name_len = reader.read_u64::<LittleEndian>()?;
+            let mut name = vec![0u8; name_len as usize];
+            reader.read_exact(&mut name)?;
+            let name = String::from_utf8(name)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let sigma = reader.read_f64::<LittleEndian>()?;
+            let epsilon = reader.read_f64::<LittleEndian>()?;
+            let mass = reader.read_f64::<LittleEndian>()?;
+            let charge = read_optional_f64(reader)?;
+            let pos = read_vector(reader)?;
+            let vel = read_vector(reader)?;
+            let old_force = read_vector(reader)?;
+            let frozen = reader.read_u8()? != 0;
+            let restraint = read_restraint(reader)?;
+            let diffusion = read_optional_f64(reader)?;
+            atoms.push(AtomState {
+                id,
+                molecule,
+                atom_type: AtomType::new(name, sigma, epsilon),
+                mass,
+                charge,
+                pos,
+                vel,
+                old_force,
+                frozen,
+                restraint,
+                diffusion,
+            });
+        }
+
+        Ok(Self { cur_step, time, time_step, simbox, rng, extended_energy, nose_hoover, mtk, parrinello_rahman, next_atom_id, atoms })
+    }
+
+    pub fn write_to_file(&self, filename: &str) -> Result<(), io::Error> {
+        let file = File::create(Path::new(filename))?;
+        let mut writer = BufWriter::new(file);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn read_from_file(filename: &str) -> Result<Self, io::Error> {
+        let file = File::open(Path::new(filename))?;
+        let mut reader = BufReader::new(file);
+        Self::read(&mut reader)
+    }
+}
+
+fn write_vector<W: Write>(writer: &mut W, v: &Vector3<f64>) -> Result<(), io::Error> {
+    for i in 0..3 {
+        writer.write_f64::<LittleEndian>(v[i])?;
+    }
+    Ok(())
+}
+
+fn read_vector<R: Read>(reader: &mut R) -> Result<Vector3<f64>, io::Error> {
+    let x = reader.read_f64::<LittleEndian>()?;
+    let y = reader.read_f64::<LittleEndian>()?;
+    let z = reader.read_f64::<LittleEndian>()?;
+    Ok(Vector3::new(x, y, z))
+}
+
+fn write_matrix<W: Write>(writer: &mut W, m: &Matrix3<f64>) -> Result<(), io::Error> {
+    for x in m.iter() {
+        writer.write_f64::<LittleEndian>(*x)?;
+    }
+    Ok(())
+}
+
+fn read_matrix<R: Read>(reader: &mut R) -> Result<Matrix3<f64>, io::Error> {
+    let mut m = Matrix3::zeros();
+    for x in m.iter_mut() {
+        *x = reader.read_f64::<LittleEndian>()?;
+    }
+    Ok(m)
+}
+
+fn write_optional_u64<W: Write>(writer: &mut W, value: Option<u64>) -> Result<(), io::Error> {
+    match value {
+        None => writer.write_u8(0),
+        Some(value) => {
+            writer.write_u8(1)?;
+            writer.write_u64::<LittleEndian>(value)
+        }
+    }
+}
+
+fn read_optional_u64<R: Read>(reader: &mut R) -> Result<Option<u64>, io::Error> {
+    if reader.read_u8()? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(reader.read_u64::<LittleEndian>()?))
+}
+
+fn write_optional_f64<W: Write>(writer: &mut W, value: Option<f64>) -> Result<(), io::Error> {
+    match value {
+        None => writer.write_u8(0),
+        Some(value) => {
+            writer.write_u8(1)?;
+            writer.write_f64::<LittleEndian>(value)
+        }
+    }
+}
+
+fn read_optional_f64<R: Read>(reader: &mut R) -> Result<Option<f64>, io::Error> {
+    if reader.read_u8()? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(reader.read_f64::<LittleEndian>()?))
+}
+
+fn write_restraint<W: Write>(writer: &mut W, restraint: &Option<PositionRestraint>) -> Result<(), io::Error> {
+    match restraint {
+        None => writer.write_u8(0),
+        Some(restraint) => {
+            writer.write_u8(1)?;
+            write_vector(writer, &restraint.reference)?;
+            writer.write_f64::<LittleEndian>(restraint.k)
+        }
+    }
+}
+
+fn read_restraint<R: Read>(reader: &mut R) -> Result<Option<PositionRestraint>, io::Error> {
+    if reader.read_u8()? == 0 {
+        return Ok(None);
+    }
+    let reference = read_vector(reader)?;
+    let k = reader.read_f64::<LittleEndian>()?;
+    Ok(Some(PositionRestraint::new(reference, k)))
+}
+
+fn write_boundary(boundary: &Boundary) -> u8 {
+    match boundary {
+        Boundary::Periodic => 0,
+        Boundary::Fixed => 1,
+        Boundary::ShrinkWrapped => 2,
+        Boundary::Reflective => 3,
+    }
+}
+
+fn read_boundary(code: u8) -> Result<Boundary, io::Error> {
+    match code {
+        0 => Ok(Boundary::Periodic),
+        1 => Ok(Boundary::Fixed),
+        2 => Ok(Boundary::ShrinkWrapped),
+        3 => Ok(Boundary::Reflective),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown boundary {}", code))),
+    }
+}
+
+fn write_rng<W: Write>(writer: &mut W, rng: &SimRng) -> Result<(), io::Error> {
+    writer.write_u64::<LittleEndian>(rng.seed.0)?;
+    writer.write_all(&rng.rng.get_seed())?;
+    writer.write_u64::<LittleEndian>(rng.rng.get_stream())?;
+    writer.write_u128::<LittleEndian>(rng.rng.get_word_pos())
+}
+
+fn read_rng<R: Read>(reader: &mut R) -> Result<SimRng, io::Error> {
+    let seed = RandomSeed(reader.read_u64::<LittleEndian>()?);
+    let mut key = [0u8; 32];
+    reader.read_exact(&mut key)?;
+    let mut rng = ChaCha8Rng::from_seed(key);
+    rng.set_stream(reader.read_u64::<LittleEndian>()?);
+    rng.set_word_pos(reader.read_u128::<LittleEndian>()?);
+    Ok(SimRng { seed, rng })
+}
+
+fn write_chain<W: Write>(writer: &mut W, state: &NoseHooverState) -> Result<(), io::Error> {
+    writer.write_u64::<LittleEndian>(state.xi.len() as u64)?;
+    for (xi, v_xi) in state.xi.iter().zip(state.v_xi.iter()) {
+        writer.write_f64::<LittleEndian>(*xi)?;
+        writer.write_f64::<LittleEndian>(*v_xi)?;
+    }
+    writer.write_f64::<LittleEndian>(state.energy)
+}
+
+fn read_chain<R: Read>(reader: &mut R) -> Result<NoseHooverState, io::Error> {
+    let chain_length = reader.read_u64::<LittleEndian>()? as usize;
+    let mut state = NoseHooverState::new(chain_length);
+    for j in 0..chain_length {
+        state.xi[j] = reader.read_f64::<LittleEndian>()?;
+        state.v_xi[j] = reader.read_f64::<LittleEndian>()?;
+    }
+    state.energy = reader.read_f64::<LittleEndian>()?;
+    Ok(state)
+}
+
+fn write_nose_hoover<W: Write>(writer: &mut W, state: &Option<NoseHooverState>) -> Result<(), io::Error> {
+    match state {
+        None => writer.write_u8(0),
+        Some(state) => {
+            writer.write_u8(1)?;
+            write_chain(writer, state)
+        }
+    }
+}
+
+fn read_nose_hoover<R: Read>(reader: &mut R) -> Result<Option<NoseHooverState>, io::Error> {
+    if reader.read_u8()? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(read_chain(reader)?))
+}
+
+fn write_mtk<W: Write>(writer: &mut W, state: &Option<MTKState>) -> Result<(), io::Error> {
+    match state {
+        None => writer.write_u8(0),
+        Some(state) => {
+            writer.write_u8(1)?;
+            write_matrix(writer, &state.v_g)?;
+            write_chain(writer, &state.particle_chain)?;
+            write_chain(writer, &state.cell_chain)?;
+            writer.write_f64::<LittleEndian>(state.energy)
+        }
+    }
+}
+
+fn read_mtk<R: Read>(reader: &mut R) -> Result<Option<MTKState>, io::Error> {
+    if reader.read_u8()? == 0 {
+        return Ok(None);
+    }
+    let v_g = read_matrix(reader)?;
+    let particle_chain = read_chain(reader)?;
+    let cell_chain = read_chain(reader)?;
+    let energy = reader.read_f64::<LittleEndian>()?;
+    Ok(Some(MTKState { v_g, particle_chain, cell_chain, energy }))
+}
+
+fn write_parrinello_rahman<W: Write>(writer: &mut W, state: &Option<ParrinelloRahmanState>) -> Result<(), io::Error> {
+    match state {
+        None => writer.write_u8(0),
+        Some(state) => {
+            writer.write_u8(1)?;
+            write_matrix(writer, &state.v_g)?;
+            writer.write_f64::<LittleEndian>(state.mass)?;
+            writer.write_f64::<LittleEndian>(state.energy)
+        }
+    }
+}
+
+fn read_parrinello_rahman<R: Read>(reader: &mut R) -> Result<Option<ParrinelloRahmanState>, io::Error> {
+    if reader.read_u8()? == 0 {
+        return Ok(None);
+    }
+    let v_g = read_matrix(reader)?;
+    let mass = reader.read_f64::<LittleEndian>()?;
+    let energy = reader.read_f64::<LittleEndian>()?;
+    Ok(Some(ParrinelloRahmanState { v_g, mass, energy }))
+}
+
+/// where and how often the checkpoint files are written.
+#[derive(Clone)]
+pub struct CheckpointOutput {
+    pub name: String,
+    pub interval: u64,
+}
+
+impl CheckpointOutput {
+    pub fn new(name: String, interval: u64) -> Self {
+        Self { name, interval }
+    }
+
+    /// the checkpoint file written at the given step.
+    pub fn filename(&self, step: u64) -> String {
+        format!("{}_{}.chk", self.name, step)
+    }
+}
+
+type AtomStateQuery<'a> = (
+    &'a AtomID,
+    Option<&'a MoleculeID>,
+    &'a AtomType,
+    &'a Mass,
+    Option<&'a Charge>,
+    &'a Position,
+    &'a Velocity,
+    &'a OldForce,
+    Option<&'a Frozen>,
+    Option<&'a PositionRestraint>,
+    Option<&'a Diffusion>,
+);
+
+#[allow(clippy::too_many_arguments)]
+pub fn write_checkpoint(
+    output: Res<CheckpointOutput>,
+    cur_step: Res<CurStep>,
+    sim_time: Res<SimTime>,
+    timestep: Res<TimeStep>,
+    simbox: Res<SimBox>,
+    rng: Res<SimRng>,
+    extended: Option<Res<ExtendedEnergy>>,
+    nose_hoover: Option<Res<NoseHooverState>>,
+    mtk: Option<Res<MTKState>>,
+    parrinello_rahman: Option<Res<ParrinelloRahmanState>>,
+    next_atom_id: Option<Res<NextAtomID>>,
+    query: Query<AtomStateQuery>,
+) {
+    if cur_step.n == 0 || !cur_step.n.is_multiple_of(output.interval) {
+        return;
+    }
+
+    let atoms: Vec<AtomState> = query
+        .iter()
+        .map(|(id, molecule, atom_type, mass, charge, pos, vel, old_force, frozen, restraint, diffusion)| AtomState {
+            id: id.id,
+            molecule: molecule.map(|molecule| molecule.id),
+            atom_type: atom_type.clone(),
+            mass: mass.value,
+            charge: charge.map(|charge| charge.value),
+            pos: pos.pos,
+            vel: vel.vel,
+            old_force: old_force.0.force,
+            frozen: frozen.is_some(),
+            restraint: restraint.copied(),
+            diffusion: diffusion.map(|diffusion| diffusion.coefficient),
+        })
+        .collect();
+
+    let checkpoint = Checkpoint {
+        cur_step: cur_step.n,
+        time: sim_time.t,
+        time_step: timestep.delta,
+        simbox: *simbox,
+        rng: rng.clone(),
+        extended_energy: extended.map_or(0.0, |e| e.value),
+        nose_hoover: nose_hoover.map(|state| state.clone()),
+        mtk: mtk.map(|state| state.clone()),
+        parrinello_rahman: parrinello_rahman.map(|state| state.clone()),
+        next_atom_id: next_atom_id.map(|next| next.id),
+        atoms,
+    };
+    let filename = output.filename(cur_step.n);
+    if let Err(why) = checkpoint.write_to_file(&filename) {
+        panic!("couldn't write checkpoint {}: {}", filename, why);
+    }
+}
+
+/// Spawns the atoms stored in the restart checkpoint in their order, so the atoms are iterated
+/// in the same order as in the original run. The queries visit the tables of the atoms in the
+/// order they were created, and an atom is added to its table with its last component, so the
+/// optional components go first, and no other atom ends up in the tables passed on the way.
+pub fn restore_atoms(
+    mut commands: Commands,
+    meshes: Option<ResMut<Assets<Mesh>>>,
+    materials: Option<ResMut<Assets<StandardMaterial>>>,
+    checkpoint: Res<Checkpoint>,
+) {
+    let mut render = meshes.zip(materials);
+    for atom in checkpoint.atoms.iter() {
+        let mut entity = commands.spawn();
+        if let Some(charge) = atom.charge {
+            entity.insert(Charge { value: charge });
+        }
+        if let Some(molecule) = atom.molecule {
+            entity.insert(MoleculeID { id: molecule });
+        }
+        if atom.frozen {
+            entity.insert(Frozen);
+        }
+        if let Some(restraint) = atom.restraint {
+            entity.insert(restraint);
+        }
+        if let Some(diffusion) = atom.diffusion {
+            entity.insert(Diffusion::new(diffusion));
+        }
+        entity.insert_bundle((
+            Position { pos: atom.pos },
+            AtomID { id: atom.id },
+            Velocity { vel: atom.vel },
+            Force::default(),
+            OldForce(Force { force: atom.old_force }),
+            Mass { value: atom.mass },
+            Atom,
+            atom.atom_type.clone(),
+        ));
+        if let Some((meshes, materials)) = render.as_mut() {
+            entity.insert_bundle(atom_render_bundle(meshes, materials));
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Hash, Debug, Eq, SystemLabel)]
+pub enum CheckpointSystems {
+    WriteCheckpoint,
+}
+
+/// writes a checkpoint every `interval` steps, the checkpoints are written in the
+/// file output stage, so this plugin needs to be added after the `OutputPlugin`.
+pub struct CheckpointPlugin {
+    pub output: CheckpointOutput,
+}
+
+impl CheckpointPlugin {
+    pub fn new(name: String, interval: u64) -> Self {
+        Self { output: CheckpointOutput::new(name, interval) }
+    }
+}
+
+impl Plugin for CheckpointPlugin {
+    fn build(&self, app: &mut App) {
+        app.world.insert_resource(self.output.clone());
+        app.add_system_to_stage(OutputStages::FileOutput, write_checkpoint.label(CheckpointSystems::WriteCheckpoint));
+    }
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::{
+        molecular_dynamics::{integration::*, lj_interaction::*},
+        output::file::{OutputPlugin, TrjName, OutInterval},
+        setup::SetupPlugin,
+    };
+    #[allow(unused_imports)]
+    use bevy::tasks::{ComputeTaskPool, TaskPool};
+    #[allow(unused_imports)]
+    use bevy::ecs::world::EntityMut;
+    #[allow(unused_imports)]
+    use std::path::PathBuf;
+
+    /// a directory of its own for the files of a test, so that the tests can run at the same
+    /// time, removed with its files when the test ends.
+    pub struct TestDir(PathBuf);
+
+    impl TestDir {
+        pub fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("md_ecs_{}_{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        /// the path of a file in the directory.
+        pub fn file(&self, name: &str) -> String {
+            self.0.join(name).to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// liquid argon with the given integrator, writing its trajectory to the directory, to
+    /// which the test adds its plugins.
+    pub fn argon_app(dir: &TestDir, restart: Option<String>, integrator: Integrator, plugins: fn(&mut App)) -> App {
+        let mut setup = SetupPlugin {
+            time_step: TimeStep::new(2e-15),
+            number_steps: Step::new(10),
+            box_size: SimBox::new(Vector3::new(0.0, 0.0, 0.0), 2e-9, 2e-9, 2e-9),
+            lj_cutoff: LJCutOff::new(9e-10),
+            trj_name: TrjName::new(dir.file("trajectory")),
+            output_interval: OutInterval::new(1000),
+            atom_number: AtomNumber::new(27),
+            ..default()
+        }
+        .with_integrator(integrator);
+        setup.restart = restart;
+
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(setup);
+        app.add_plugin(LJPlugin);
+        app.add_plugin(IntegrationPlugin);
+        plugins(&mut app);
+        app.add_plugin(OutputPlugin);
+        app
+    }
+
+    /// runs 10 steps writing a checkpoint every 5, and the last 5 steps again from the
+    /// checkpoint of the step 5, which must end with the atoms in the same state and the same
+    /// box dimensions. `make_app` builds the app writing its files to the directory of the
+    /// test, and `decorate` adds the components of the test to each atom as it is spawned.
+    /// Returns the uninterrupted and the restarted app, for the test to compare the rest of
+    /// the state it restores.
+    pub fn assert_restart_matches(name: &str, make_app: impl Fn(&TestDir, Option<String>) -> App, decorate: fn(u64, &mut EntityMut)) -> (App, App) {
+        let dir = TestDir::new(name);
+        let checkpoint_name = dir.file("restart");
+        let mut app = make_app(&dir, None);
+        app.add_plugin(CheckpointPlugin::new(checkpoint_name.clone(), 5));
+        for i in 0..27u64 {
+            let lattice = Vector3::new((i % 3) as f64, ((i / 3) % 3) as f64, (i / 9) as f64) * 6.6e-10;
+            let vel = Vector3::new(100.0 * (i as f64).sin(), 100.0 * (i as f64).cos(), 50.0);
+            let mut atom = app.world.spawn();
+            atom.insert(Position { pos: lattice })
+                .insert(AtomID { id: i + 1 })
+                .insert(Velocity { vel })
+                .insert(Force::default())
+                .insert(OldForce::default())
+                .insert(Mass { value: 39.948 })
+                .insert(Atom)
+                .insert(AtomType::new(String::from("Argon"), 3.4e-10, 1.654e-21));
+            decorate(i + 1, &mut atom);
+        }
+        for _ in 0..10 {
+            app.update();
+        }
+
+        let mut restarted = make_app(&dir, Some(CheckpointOutput::new(checkpoint_name, 5).filename(5)));
+        assert_eq!(restarted.world.get_resource::<CurStep>().unwrap().n, 5);
+        for _ in 0..5 {
+            restarted.update();
+        }
+
+        let final_state = |app: &mut App| {
+            let mut query = app.world.query::<(&AtomID, &Position, &Velocity)>();
+            let mut state: Vec<_> = query
+                .iter(&app.world)
+                .map(|(id, pos, vel)| (id.id, pos.pos, vel.vel))
+                .collect();
+            state.sort_by_key(|s| s.0);
+            state
+        };
+        let expected = final_state(&mut app);
+        let actual = final_state(&mut restarted);
+        assert_eq!(expected.len(), actual.len());
+        for (e, a) in expected.iter().zip(actual.iter()) {
+            assert_eq!(e, a);
+        }
+        assert_eq!(app.world.get_resource::<SimBox>().unwrap().dimension, restarted.world.get_resource::<SimBox>().unwrap().dimension);
+        (app, restarted)
+    }
+
+    #[test]
+    fn test_restart_matches_uninterrupted_run() {
+        assert_restart_matches("restart_test", |dir, restart| {
+            argon_app(dir, restart, Integrator::VelocityVerlet, |_| {})
+        }, |_, _| {});
+    }
+}
@@ -1,17 +1,20 @@
 use bevy::prelude::*;
 use crate::atom::*;
-use crate::molecular_dynamics::integration::CurStep;
+use crate::molecular_dynamics::integration::{CurStep, SimTime};
 use crate::simbox::SimBox;
 
 pub fn console_output(
     cur_step: Res<CurStep>,
+    sim_time: Option<Res<SimTime>>,
     query: Query<&Atom>,
     simbox: Res<SimBox>,
 ) {
     if cur_step.n % 10 == 0 {
         let atom_number = query.iter().count();
 
-        println!("Step {}, {} atoms, box origin: {}, {}, {}.", cur_step.n, atom_number, simbox.origin.x, simbox.origin.y, simbox.origin.z);
+        let time = sim_time.map_or(0.0, |sim_time| sim_time.t);
+
+        println!("Step {}, time {}, {} atoms, box origin: {}, {}, {}.", cur_step.n, time, atom_number, simbox.origin.x, simbox.origin.y, simbox.origin.z);
     }
 }
 
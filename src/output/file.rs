@@ -8,8 +8,9 @@ use nalgebra::Vector3;
 /// this file is for defnining the function for outputing
 /// the lammps like trajectry file that can be read by ovito.
 use crate::atom::*;
-use crate::simbox::SimBox;
-use crate::molecular_dynamics::integration::{OldForce, CurStep, IntegrationStages, IntegrationSystems};
+use crate::group::{assert_group_defined, group_filter, AtomGroups, GroupMask};
+use crate::simbox::{Boundary, SimBox};
+use crate::molecular_dynamics::integration::{OldForce, CurStep, IntegrationStages};
 use bevy::prelude::*;
 
 
@@ -64,6 +65,19 @@ impl Default for TrjName {
     }
 }
 
+/// the group of atoms written to the trajectory, all atoms are written without it. It is
+/// inserted before the `OutputPlugin` is added.
+#[derive(Clone)]
+pub struct TrjGroup {
+    pub group: String,
+}
+
+impl TrjGroup {
+    pub fn new(group: &str) -> Self {
+        Self { group: String::from(group) }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct OutInterval {
     pub interval: u64,
@@ -85,21 +99,32 @@ pub struct FrameHeader {
     cur_step: u64,
     atom_number: usize,
     origin: Vector3<f64>,
-    dimension: Vector3<f64>
+    dimension: Vector3<f64>,
+    tilt: Vector3<f64>,
+    boundary: [Boundary; 3],
 }
 
 
 
+/// writes a frame every `interval` steps, once the positions of the next step are known.
+/// What the `Position` and `Velocity` of a frame stand for depends on the integrator:
+/// with leapfrog the velocities are the half step velocities v(t - dt/2) of the positions
+/// x(t), and with position verlet the positions are x(t + dt/2) of the velocities v(t).
 pub fn lammps_trj (
     trj_name: Res<TrjName>,
     interval: Res<OutInterval>,
     cur_step: Res<CurStep>,
     simbox: Res<SimBox>,
-    query: Query<(&Position, &Velocity, &OldForce, &Mass, &AtomID)>,
+    trj_group: Option<Res<TrjGroup>>,
+    groups: Option<Res<AtomGroups>>,
+    query: Query<(&Position, &Velocity, &OldForce, &Mass, &AtomID, Option<&GroupMask>)>,
 ) {
-    let atom_number = query.iter().count();
+    let filter = group_filter(&groups, &trj_group.map(|trj_group| trj_group.group.clone()));
+    let atom_number = query.iter().filter(|(.., mask)| filter.matches(*mask)).count();
     let origin = simbox.origin;
     let dimension = simbox.dimension;
+    let tilt = simbox.tilt;
+    let boundary = simbox.boundary;
 
     if cur_step.n % interval.interval == 0{
         let filename = format!("{}_{}.trj", trj_name.name, cur_step.n);
@@ -110,10 +135,10 @@ pub fn lammps_trj (
              Ok(file) => file,
         };
         let mut writer = BufWriter::new(file);
-        let header = FrameHeader {cur_step: cur_step.n, atom_number, origin, dimension};
+        let header = FrameHeader {cur_step: cur_step.n, atom_number, origin, dimension, tilt, boundary};
         write_frame_header(&mut writer, header);
 
-        for (pos, vel, old_force, mass, atom_id) in query.iter() {
+        for (pos, vel, old_force, mass, atom_id, _mask) in query.iter().filter(|(.., mask)| filter.matches(*mask)) {
             write_atom(&mut writer, atom_id.id, Compose(pos.clone(), old_force.0.clone()));
         }
     }
@@ -124,11 +149,16 @@ pub fn first_trj (
     trj_name: Res<TrjName>,
     cur_step: Res<CurStep>,
     simbox: Res<SimBox>,
-    query: Query<(&Position, &Velocity, &OldForce, &Mass, &AtomID)>,
+    trj_group: Option<Res<TrjGroup>>,
+    groups: Option<Res<AtomGroups>>,
+    query: Query<(&Position, &Velocity, &OldForce, &Mass, &AtomID, Option<&GroupMask>)>,
 ) {
-    let atom_number = query.iter().count();
+    let filter = group_filter(&groups, &trj_group.map(|trj_group| trj_group.group.clone()));
+    let atom_number = query.iter().filter(|(.., mask)| filter.matches(*mask)).count();
     let origin = simbox.origin;
-    let dimension = simbox.dimension;    
+    let dimension = simbox.dimension;
+    let tilt = simbox.tilt;
+    let boundary = simbox.boundary;
 
 
     if cur_step.n == 0 {
@@ -140,10 +170,10 @@ pub fn first_trj (
              Ok(file) => file,
         };
         let mut writer = BufWriter::new(file);
-        let header = FrameHeader {cur_step: cur_step.n, atom_number, origin, dimension};
+        let header = FrameHeader {cur_step: cur_step.n, atom_number, origin, dimension, tilt, boundary};
         write_frame_header(&mut writer, header);
 
-        for (pos, vel, old_force, mass, atom_id) in query.iter() {
+        for (pos, vel, old_force, mass, atom_id, _mask) in query.iter().filter(|(.., mask)| filter.matches(*mask)) {
             write_atom(&mut writer, atom_id.id, Compose(pos.clone(), old_force.0.clone()));
         }
     }
@@ -160,14 +190,30 @@ fn write_frame_header<W: Write> (writer: &mut W, header: FrameHeader) -> Result<
     //
     writeln!(writer, "{}", header.atom_number)?;
     //
-    writeln!(writer, "ITEM: BOX BOUNDS pp pp pp")?;
-
-    // -X X
-    writeln!(writer, "{} {}", header.origin.x, header.origin.x + header.dimension.x)?;
-    // -Y Y
-    writeln!(writer, "{} {}", header.origin.y, header.origin.y + header.dimension.y)?;
-    // -Z Z
-    writeln!(writer, "{} {}", header.origin.z, header.origin.z + header.dimension.z)?;
+    let styles = header.boundary.map(|boundary| boundary.lammps_style()).join(" ");
+    if header.tilt == Vector3::zeros() {
+        writeln!(writer, "ITEM: BOX BOUNDS {}", styles)?;
+
+        // -X X
+        writeln!(writer, "{} {}", header.origin.x, header.origin.x + header.dimension.x)?;
+        // -Y Y
+        writeln!(writer, "{} {}", header.origin.y, header.origin.y + header.dimension.y)?;
+        // -Z Z
+        writeln!(writer, "{} {}", header.origin.z, header.origin.z + header.dimension.z)?;
+    }
+    else {
+        // LAMMPS writes the bounding box of a triclinic box, followed by its tilt factors
+        writeln!(writer, "ITEM: BOX BOUNDS xy xz yz {}", styles)?;
+        let (xy, xz, yz) = (header.tilt.x, header.tilt.y, header.tilt.z);
+        let x_low = header.origin.x + 0.0f64.min(xy).min(xz).min(xy + xz);
+        let x_high = header.origin.x + header.dimension.x + 0.0f64.max(xy).max(xz).max(xy + xz);
+        let y_low = header.origin.y + 0.0f64.min(yz);
+        let y_high = header.origin.y + header.dimension.y + 0.0f64.max(yz);
+
+        writeln!(writer, "{} {} {}", x_low, x_high, xy)?;
+        writeln!(writer, "{} {} {}", y_low, y_high, xz)?;
+        writeln!(writer, "{} {} {}", header.origin.z, header.origin.z + header.dimension.z, yz)?;
+    }
 
     // ITEM: ATOMS id type x y z vx vy vz speed speed2d temp
     writeln!(
@@ -201,11 +247,14 @@ pub enum OutputSystems {
 pub struct OutputPlugin;
 impl Plugin for OutputPlugin {
     fn build(&self, app: &mut App) {
+        if let Some(trj_group) = app.world.get_resource::<TrjGroup>() {
+            assert_group_defined(app, &trj_group.group);
+        }
 
         /// this system is currently running in every update, however my purpose was to output the trajectory of 
         /// the very first frame, which in principle should work when I add the system as startup system, but that didn't 
         /// work somehow, so I put this system here for now until I figure out a better way to do it
-        app.add_system_to_stage(IntegrationStages::BeginIntegration, first_trj.before(IntegrationSystems::VelocityVerletIntegratePosition));
+        app.add_system_to_stage(IntegrationStages::BeginIntegration, first_trj);
         app.add_stage_after(IntegrationStages::EndIntegration, OutputStages::FileOutput, SystemStage::parallel());
         app.add_system_to_stage(OutputStages::FileOutput, lammps_trj.label(OutputSystems::OvitoTrj));
     }
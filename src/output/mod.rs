@@ -1,4 +1,5 @@
 pub mod console;
 pub mod file;
+pub mod checkpoint;
 
 
@@ -1,5 +1,6 @@
 use bevy::prelude::*;
 use crate::atom::*;
+use crate::group::{assert_group_defined, group_filter, AtomGroups, GroupMask};
 use crate::output::file::{OutputStages, OutputSystems};
 use crate::simbox::*;
 use crate::molecular_dynamics::integration::{Step, CurStep};
@@ -11,19 +12,23 @@ use std::io::{self, BufWriter};
 use std::path::Path;
 
 
+/// the radial distribution function between the atoms of two groups, or of all atoms.
 #[derive(Clone)]
 pub struct RDF {
-    pub atom_a: String,
-    pub atom_b: String,
+    pub group_a: Option<String>,
+    pub group_b: Option<String>,
     pub n_bins: usize,
     pub range: f64,
+    /// the bins and the sum of g(r) over the frames evaluated.
     pub rdf_cum: (Vec<f64>, Vec<f64>),
+    pub n_frames: u64,
     pub filename: String,
 }
 
 
 impl RDF {
-    pub fn new(a: String, b:String, bins: usize, rmax: f64, filename: String) -> Self {
+    /// the rdf between all atoms.
+    pub fn new(bins: usize, rmax: f64, filename: String) -> Self {
 
         // initialize the r vector and the rho vector respectively
         let mut rs = vec![0.0; bins];
@@ -37,14 +42,28 @@ impl RDF {
             rs[i] = bin_width * i as f64;
         }
         Self { 
-            atom_a: a, 
-            atom_b: b, 
+            group_a: None, 
+            group_b: None, 
             n_bins: bins, 
             range: rmax, 
             rdf_cum: (rs, rhos),
+            n_frames: 0,
             filename,
         }
     }
+
+    /// the rdf of the atoms of the group b around the atoms of the group a.
+    pub fn with_groups(mut self, a: &str, b: &str) -> Self {
+        self.group_a = Some(String::from(a));
+        self.group_b = Some(String::from(b));
+        self
+    }
+
+    /// g(r) averaged over the frames evaluated so far.
+    pub fn average(&self) -> Vec<f64> {
+        let n_frames = self.n_frames.max(1) as f64;
+        self.rdf_cum.1.iter().map(|g| g / n_frames).collect()
+    }
 }
 
 fn calc_rdf (
@@ -52,84 +71,59 @@ fn calc_rdf (
     tot_step: Res<Step>,
     mut rdf_data: ResMut<RDF>,
     simbox: Res<SimBox>,
-    mut query: Query<(&AtomType, &Position)>,
+    groups: Option<Res<AtomGroups>>,
+    query: Query<(&Position, Option<&GroupMask>)>,
 ) {
-    // calculating the normalization parameter and the range of the rdf data.
-    let volume = simbox.dimension.x * simbox.dimension.y * simbox.dimension.z;
-    let rho_mean = (query.iter().count() as f64) / volume;
+    let filter_a = group_filter(&groups, &rdf_data.group_a);
+    let filter_b = group_filter(&groups, &rdf_data.group_b);
     let bin_width = rdf_data.range / rdf_data.n_bins as f64;
 
+    // the number of ordered pairs of different atoms, the first in a and the second in b,
+    // which sets, with the volume, the density of b seen from an atom of a.
+    let n_a = query.iter().filter(|(_, mask)| filter_a.matches(*mask)).count();
+    let n_b = query.iter().filter(|(_, mask)| filter_b.matches(*mask)).count();
+    let n_ab = query.iter().filter(|(_, mask)| filter_a.matches(*mask) && filter_b.matches(*mask)).count();
+    let n_pairs = (n_a * n_b - n_ab) as f64;
+    if n_pairs == 0.0 {
+        return;
+    }
+
     // we need to loop over all pairs, which means this could be more efficient 
     // if we merge this calculation to the force evaluation part, where we also evaluate 
     // the distance between each pair of particles. Might consider add a system resource
     // to serve as a option.
-    const K: usize = 2;
-    let mut particle_pairs = query.iter_combinations_mut::<K>();
-
-    let mut n_checked_pair = 0.0;
-
-    while let Some([(atom1, pos1), (atom2, pos2)]) 
-    = particle_pairs.fetch_next() {
-
-        if rdf_data.atom_a == rdf_data.atom_b {
-            // for the case where 
-            if atom1.name == rdf_data.atom_a && atom2.name == rdf_data.atom_a {
-                let mut r = pos1.pos - pos2.pos;
-
-                // treating the pbc 
-                r[0] = r[0] - simbox.dimension.x * (r[0]/simbox.dimension.x).round(); 
-                r[1] = r[1] - simbox.dimension.y * (r[1]/simbox.dimension.y).round();
-                r[2] = r[2] - simbox.dimension.z * (r[2]/simbox.dimension.z).round();
-
-                let distance = r.norm();
-
-                // we are only considering those inside the cut off distance 
-                if distance >= rdf_data.range {
-                    continue;
-                }
-                // the bin index for a certain distance is determined by module divide by the bin width 
-                let rdf_index =(distance / bin_width).floor() as usize;
-                let shell_vol = (4.0 / 3.0) * constant::PI * bin_width.powf(3.0) * ((rdf_index+1).pow(3) - rdf_index.pow(3)) as f64;
-                let addition = 2.0 / (shell_vol);// add the counting and normalize it by shell volume and rho_mean at the same time
-                rdf_data.rdf_cum.1[rdf_index] += addition; 
-                n_checked_pair += 1.0;
-            }
+    let mut counts = vec![0.0; rdf_data.n_bins];
+    for [(pos1, mask1), (pos2, mask2)] in query.iter_combinations() {
+        // each pair counts once for each order in which it is a pair of an atom of a and one of b.
+        let weight = (filter_a.matches(mask1) && filter_b.matches(mask2)) as u8
+            + (filter_a.matches(mask2) && filter_b.matches(mask1)) as u8;
+        if weight == 0 {
+            continue;
         }
 
-        else {
-            // for the case where we have different atom types, so the two atoms in the pair need to be different from each other. 
-            if (atom1.name == rdf_data.atom_a && atom2.name == rdf_data.atom_b) || (atom1.name == rdf_data.atom_b && atom2.name == rdf_data.atom_a) {
-                let mut r = pos1.pos - pos2.pos;
-
-                // treating the pbc 
-                r[0] = r[0] - simbox.dimension.x * (r[0]/simbox.dimension.x).round(); 
-                r[1] = r[1] - simbox.dimension.y * (r[1]/simbox.dimension.y).round();
-                r[2] = r[2] - simbox.dimension.z * (r[2]/simbox.dimension.z).round();
-
-                let distance = r.norm();
-
-                // we are only considering those inside the cut off distance 
-                if distance >= rdf_data.range {
-                    continue;
-                }
-                // the bin index for a certain distance is determined by module divide by the bin width 
-                let rdf_index =(distance / bin_width).floor() as usize;
-                let shell_vol = (4.0 / 3.0) * constant::PI * bin_width.powf(3.0) * ((rdf_index+1).pow(3) - rdf_index.pow(3)) as f64;
-                let addition = 2.0 / (shell_vol);// add the counting and normalize it by shell volume and rho_mean at the same time
-                rdf_data.rdf_cum.1[rdf_index] += addition; 
-                n_checked_pair += 1.0;
-            }
+        // treating the pbc 
+        let distance = simbox.minimum_image(pos1.pos - pos2.pos).norm();
+
+        // we are only considering those inside the cut off distance 
+        if distance >= rdf_data.range {
+            continue;
         }
-        
+        // the bin index for a certain distance is determined by module divide by the bin width 
+        let rdf_index = ((distance / bin_width).floor() as usize).min(rdf_data.n_bins - 1);
+        counts[rdf_index] += weight as f64;
     }
-    // normalization over all the particle pairs evaluated.
-    rdf_data.rdf_cum.1 = rdf_data.rdf_cum.1.clone().into_iter().map(|x| x/(2.0*n_checked_pair * rho_mean)).collect();
+
+    // normalization by the shell volume and the number of pairs of an ideal gas in it.
+    let volume = simbox.volume();
+    for (rdf_index, count) in counts.into_iter().enumerate() {
+        let shell_vol = (4.0 / 3.0) * constant::PI * bin_width.powf(3.0) * ((rdf_index+1).pow(3) - rdf_index.pow(3)) as f64;
+        rdf_data.rdf_cum.1[rdf_index] += count * volume / (n_pairs * shell_vol);
+    }
+    rdf_data.n_frames += 1;
 
 
     // normalization over all the frames and write to file at the last step.
     if cur_step.n == tot_step.n {
-        rdf_data.rdf_cum.1 = rdf_data.rdf_cum.1.clone().into_iter().map(|x| x/(tot_step.n as f64)).collect();
-
         let filename = rdf_data.filename.clone();
         let path = Path::new(&filename);
         let display = path.display();
@@ -139,7 +133,7 @@ fn calc_rdf (
         };
 
         let mut writer = BufWriter::new(file);
-        write_rdf(&mut writer, rdf_data.rdf_cum.clone());
+        write_rdf(&mut writer, (rdf_data.rdf_cum.0.clone(), rdf_data.average()));
         
     }
 
@@ -174,7 +168,53 @@ impl RDFPlugin {
 }
 impl Plugin for RDFPlugin {
     fn build(&self, app: &mut App) {
+        for group in [&self.params.group_a, &self.params.group_b].into_iter().flatten() {
+            assert_group_defined(app, group);
+        }
         app.world.insert_resource(self.params.clone());
         app.add_system_to_stage(QuantityCalcStage, calc_rdf.label(QuantityCalcSystems::RdfCalc));
     }
 }
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::group::{GroupPlugin, Selection};
+    #[allow(unused_imports)]
+    use crate::setup::SetupPlugin;
+    #[allow(unused_imports)]
+    use nalgebra::Vector3;
+    #[allow(unused_imports)]
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_rdf_of_groups_tends_to_one() {
+        // atoms placed at random have g(r) = 1 between any two groups, here a fifth of the
+        // atoms with the rest, and the fifth with itself.
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(42);
+        for b in ["rest", "fifth"] {
+            let mut app = App::new();
+            app.add_plugin(SetupPlugin {
+                box_size: SimBox::new(Vector3::zeros(), 10.0, 10.0, 10.0),
+                number_steps: Step::new(100),
+                ..default()
+            });
+            app.add_plugin(GroupPlugin::new("fifth", Selection::parse("id 1:200").unwrap()));
+            app.add_plugin(GroupPlugin::new("rest", Selection::parse("id 201:1000").unwrap()));
+            app.insert_resource(RDF::new(2, 4.0, String::new()).with_groups("fifth", b));
+            app.add_system(calc_rdf);
+            for id in 1..=1000 {
+                let pos = Vector3::new(rng.gen(), rng.gen(), rng.gen()) * 10.0;
+                app.world.spawn().insert(Position { pos }).insert(AtomID { id });
+            }
+            app.update();
+
+            let rdf = app.world.get_resource::<RDF>().unwrap();
+            assert_eq!(rdf.n_frames, 1);
+            for g in rdf.average() {
+                assert!((g - 1.0).abs() < 0.1, "g(r) {} between fifth and {}", g, b);
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Seeded random number generation, so that simulations are reproducible.
+//!
+//! All the stochastic parts of the simulation draw their random numbers from the
+//! `SimRng` resource. Systems running in parallel use `SimRng::atom_rng`, which gives
+//! each atom its own stream for each step and each `RngPurpose`, so the random numbers an
+//! atom sees do not depend on how the atoms are distributed over the threads, and two
+//! stochastic systems used together do not draw the same numbers. The pair forces use
+//! `SimRng::pair_rng` in the same way, with a stream for each pair of atoms.
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+/// the seed of the random number generator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RandomSeed(pub u64);
+
+impl Default for RandomSeed {
+    fn default() -> Self {
+        RandomSeed(42)
+    }
+}
+
+/// what the per atom random numbers are drawn for, each purpose gets its own streams.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RngPurpose {
+    Langevin,
+    Andersen,
+    DPD,
+    Brownian,
+}
+
+impl RngPurpose {
+    /// the key the seed is mixed with, different from the one of `SimRng::stream_rng`.
+    fn key(&self) -> u64 {
+        (*self as u64 + 1).wrapping_mul(0xD1B5_4A32_D192_ED03)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SimRng {
+    pub seed: RandomSeed,
+    pub rng: ChaCha8Rng,
+}
+
+impl SimRng {
+    pub fn new(seed: RandomSeed) -> Self {
+        Self { seed, rng: ChaCha8Rng::seed_from_u64(seed.0) }
+    }
+
+    /// A generator for the given atom in the given step, independent of the main stream,
+    /// of the other purposes and of the order in which the atoms are visited.
+    pub fn atom_rng(&self, purpose: RngPurpose, step: u64, atom_id: u64) -> ChaCha8Rng {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed.0 ^ purpose.key());
+        // each step gets 2^32 words per atom.
+        rng.set_stream(atom_id.wrapping_add(1));
+        rng.set_word_pos((step as u128) << 32);
+        rng
+    }
+
+    /// A generator for the given pair of atoms in the given step, the same for both orders of
+    /// the pair, and independent of the order in which the pairs are visited.
+    pub fn pair_rng(&self, purpose: RngPurpose, step: u64, atom_id1: u64, atom_id2: u64) -> ChaCha8Rng {
+        let (low, high) = (atom_id1.min(atom_id2), atom_id1.max(atom_id2));
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed.0 ^ purpose.key() ^ high.wrapping_add(1).wrapping_mul(0xBF58_476D_1CE4_E5B9));
+        rng.set_stream(low.wrapping_add(1));
+        rng.set_word_pos((step as u128) << 32);
+        rng
+    }
+
+    /// A generator for a numbered stream, e.g. one per batch of a parallel system.
+    pub fn stream_rng(&self, step: u64, stream: u64) -> ChaCha8Rng {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed.0 ^ 0x9E37_79B9_7F4A_7C15);
+        rng.set_stream(stream);
+        rng.set_word_pos((step as u128) << 32);
+        rng
+    }
+}
+
+impl Default for SimRng {
+    fn default() -> Self {
+        Self::new(RandomSeed::default())
+    }
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::{atom::*, setup::SetupPlugin};
+    #[allow(unused_imports)]
+    use bevy::prelude::*;
+    #[allow(unused_imports)]
+    use rand::Rng;
+
+    #[allow(dead_code)]
+    fn created_positions(seed: u64) -> Vec<(u64, Position)> {
+        let mut app = App::new();
+        app.add_plugin(SetupPlugin { atom_number: AtomNumber::new(10), ..default() }.with_seed(seed));
+        app.add_startup_system(create_atoms);
+        app.update();
+        let mut query = app.world.query::<(&AtomID, &Position)>();
+        let mut positions: Vec<(u64, Position)> = query.iter(&app.world).map(|(id, pos)| (id.id, pos.clone())).collect();
+        positions.sort_by_key(|p| p.0);
+        positions
+    }
+
+    #[test]
+    fn test_seeded_atom_creation() {
+        let first = created_positions(7);
+        let second = created_positions(7);
+        let other = created_positions(8);
+        for (a, b) in first.iter().zip(second.iter()) {
+            assert_eq!(a.1.pos, b.1.pos);
+        }
+        assert_ne!(first[0].1.pos, other[0].1.pos);
+    }
+
+    #[test]
+    fn test_atom_streams() {
+        let sim_rng = SimRng::new(RandomSeed(1));
+        let x: f64 = sim_rng.atom_rng(RngPurpose::Langevin, 3, 5).gen();
+        assert_eq!(x, sim_rng.atom_rng(RngPurpose::Langevin, 3, 5).gen::<f64>());
+        assert_ne!(x, sim_rng.atom_rng(RngPurpose::Langevin, 4, 5).gen::<f64>());
+        assert_ne!(x, sim_rng.atom_rng(RngPurpose::Langevin, 3, 6).gen::<f64>());
+        // the thermostats used together draw different numbers for the same atom and step
+        assert_ne!(x, sim_rng.atom_rng(RngPurpose::Andersen, 3, 5).gen::<f64>());
+        assert_ne!(x, sim_rng.atom_rng(RngPurpose::Brownian, 3, 5).gen::<f64>());
+
+        // a pair draws the same numbers whichever atom comes first
+        let y: f64 = sim_rng.pair_rng(RngPurpose::DPD, 3, 5, 9).gen();
+        assert_eq!(y, sim_rng.pair_rng(RngPurpose::DPD, 3, 9, 5).gen::<f64>());
+        assert_ne!(y, sim_rng.pair_rng(RngPurpose::DPD, 4, 5, 9).gen::<f64>());
+        assert_ne!(y, sim_rng.pair_rng(RngPurpose::DPD, 3, 5, 8).gen::<f64>());
+        assert_ne!(y, sim_rng.pair_rng(RngPurpose::DPD, 3, 6, 9).gen::<f64>());
+    }
+}
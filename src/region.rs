@@ -0,0 +1,167 @@
+//! Geometric regions, used to select atoms, to create atoms in a part of the box, to delete
+//! atoms and to apply external fields locally.
+//!
+//! The coordinates of a region are relative to the origin of the `SimBox`, so a region follows
+//! the box when its origin moves, and the positions of the atoms are wrapped into the box
+//! along the periodic axes before they are tested. The spheres and cylinders are measured
+//! with the minimum image, so they reach across the periodic faces of the box.
+
+use crate::atom::*;
+use crate::molecular_dynamics::atom_changes::despawn_atom;
+use crate::simbox::SimBox;
+use bevy::prelude::*;
+use nalgebra::Vector3;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Region {
+    /// the box between the low and high corners.
+    Block { low: Vector3<f64>, high: Vector3<f64> },
+    Sphere { center: Vector3<f64>, radius: f64 },
+    /// the cylinder around the line through the center along an axis, between the low and
+    /// high coordinates along that axis.
+    Cylinder { axis: usize, center: Vector3<f64>, radius: f64, low: f64, high: f64 },
+    /// the half space on the side of the plane through the point which the normal points to.
+    HalfSpace { point: Vector3<f64>, normal: Vector3<f64> },
+    Union(Vec<Region>),
+    Intersection(Vec<Region>),
+}
+
+impl Region {
+    pub fn block(low: Vector3<f64>, high: Vector3<f64>) -> Self {
+        Region::Block { low, high }
+    }
+
+    pub fn sphere(center: Vector3<f64>, radius: f64) -> Self {
+        Region::Sphere { center, radius }
+    }
+
+    /// an infinite cylinder.
+    pub fn cylinder(axis: usize, center: Vector3<f64>, radius: f64) -> Self {
+        Self::bounded_cylinder(axis, center, radius, f64::NEG_INFINITY, f64::INFINITY)
+    }
+
+    /// a cylinder between the low and high coordinates along its axis.
+    pub fn bounded_cylinder(axis: usize, center: Vector3<f64>, radius: f64, low: f64, high: f64) -> Self {
+        Region::Cylinder { axis, center, radius, low, high }
+    }
+
+    pub fn half_space(point: Vector3<f64>, normal: Vector3<f64>) -> Self {
+        Region::HalfSpace { point, normal: normal.normalize() }
+    }
+
+    pub fn union(self, other: Region) -> Self {
+        Region::Union(vec![self, other])
+    }
+
+    pub fn intersection(self, other: Region) -> Self {
+        Region::Intersection(vec![self, other])
+    }
+
+    /// whether the position is inside the region of the box.
+    pub fn contains(&self, pos: &Vector3<f64>, simbox: &SimBox) -> bool {
+        let relative = simbox.wrap(*pos) - simbox.origin;
+        match self {
+            Region::Block { low, high } => (0..3).all(|d| low[d] <= relative[d] && relative[d] <= high[d]),
+            Region::Sphere { center, radius } => simbox.minimum_image(relative - center).norm() <= *radius,
+            Region::Cylinder { axis, center, radius, low, high } => {
+                let mut radial = simbox.minimum_image(relative - center);
+                radial[*axis] = 0.0;
+                radial.norm() <= *radius && *low <= relative[*axis] && relative[*axis] <= *high
+            }
+            Region::HalfSpace { point, normal } => (relative - point).dot(normal) >= 0.0,
+            Region::Union(regions) => regions.iter().any(|region| region.contains(pos, simbox)),
+            Region::Intersection(regions) => regions.iter().all(|region| region.contains(pos, simbox)),
+        }
+    }
+}
+
+/// named regions, which the selections refer to with `region NAME`.
+#[derive(Clone, Default)]
+pub struct Regions {
+    pub regions: Vec<(String, Region)>,
+}
+
+impl Regions {
+    pub fn add(&mut self, name: &str, region: Region) {
+        assert!(self.get(name).is_none(), "the region {} is defined twice", name);
+        self.regions.push((String::from(name), region));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Region> {
+        self.regions.iter().find(|(other, _)| other == name).map(|(_, region)| region)
+    }
+}
+
+/// the region `create_atoms` places the atoms in, instead of the whole box.
+#[derive(Clone)]
+pub struct AtomRegion {
+    pub region: Region,
+}
+
+/// deletes the atoms inside the region, and returns how many were deleted.
+pub fn delete_atoms(world: &mut World, region: &Region) -> usize {
+    let simbox = *world.get_resource::<SimBox>().expect("deleting atoms in a region needs a box");
+    let inside: Vec<Entity> = world
+        .query_filtered::<(Entity, &Position), With<AtomID>>()
+        .iter(world)
+        .filter(|(_, pos)| region.contains(&pos.pos, &simbox))
+        .map(|(entity, _)| entity)
+        .collect();
+    for entity in inside.iter() {
+        despawn_atom(world, *entity);
+    }
+    inside.len()
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::simbox::Boundary;
+
+    #[test]
+    fn test_regions() {
+        let simbox = SimBox::new(Vector3::repeat(-5.0), 10.0, 10.0, 10.0)
+            .with_boundary([Boundary::Periodic, Boundary::Periodic, Boundary::Fixed]);
+        let at = |x: f64, y: f64, z: f64| Vector3::new(x, y, z);
+
+        let block = Region::block(at(0.0, 0.0, 0.0), at(2.0, 10.0, 10.0));
+        assert!(block.contains(&at(-4.0, 0.0, 0.0), &simbox));
+        // the positions are wrapped along the periodic axes only
+        assert!(block.contains(&at(6.0, 0.0, 0.0), &simbox));
+        assert!(!block.contains(&at(-4.0, 0.0, 6.0), &simbox));
+
+        // a sphere at a corner of the box reaches across the periodic faces
+        let sphere = Region::sphere(at(0.0, 0.0, 5.0), 1.0);
+        assert!(sphere.contains(&at(4.5, -4.5, 0.0), &simbox));
+        assert!(!sphere.contains(&at(4.5, -4.5, 1.5), &simbox));
+
+        let cylinder = Region::cylinder(2, at(5.0, 5.0, 0.0), 1.0);
+        assert!(cylinder.contains(&at(0.5, 0.0, -4.9), &simbox));
+        assert!(!cylinder.contains(&at(1.5, 0.0, 0.0), &simbox));
+        let bounded = Region::bounded_cylinder(2, at(5.0, 5.0, 0.0), 1.0, 0.0, 5.0);
+        assert!(bounded.contains(&at(0.5, 0.0, -4.9), &simbox));
+        assert!(!bounded.contains(&at(0.5, 0.0, 0.5), &simbox));
+
+        let upper = Region::half_space(at(0.0, 0.0, 5.0), at(0.0, 0.0, 2.0));
+        assert!(upper.contains(&at(0.0, 0.0, 1.0), &simbox));
+        assert!(!upper.contains(&at(0.0, 0.0, -1.0), &simbox));
+
+        let both = block.clone().intersection(upper.clone());
+        let either = block.union(upper);
+        assert!(both.contains(&at(-4.0, 0.0, 1.0), &simbox));
+        assert!(!both.contains(&at(-4.0, 0.0, -1.0), &simbox));
+        assert!(either.contains(&at(-4.0, 0.0, -1.0), &simbox));
+        assert!(!either.contains(&at(0.0, 0.0, -1.0), &simbox));
+
+        // deleting the atoms in the sphere
+        let mut world = World::new();
+        world.insert_resource(simbox);
+        for (id, pos) in [at(4.5, -4.5, 0.0), at(4.5, -4.5, 1.5), at(-4.5, 4.5, -0.5)].into_iter().enumerate() {
+            world.spawn().insert(Position { pos }).insert(AtomID { id: id as u64 + 1 });
+        }
+        assert_eq!(delete_atoms(&mut world, &sphere), 2);
+        let ids: Vec<u64> = world.query::<&AtomID>().iter(&world).map(|atom_id| atom_id.id).collect();
+        assert_eq!(ids, vec![2]);
+    }
+}
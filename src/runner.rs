@@ -0,0 +1,271 @@
+//! Driving the simulation until completion or until a stop condition is met.
+
+use bevy::prelude::*;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::atom::{Force, Position, Velocity};
+use crate::molecular_dynamics::energy::{self, KineticEnergy, PotentialEnergy};
+use crate::molecular_dynamics::integration::{CurStep, Step};
+
+/// Conditions that end a run before `CurStep` reaches `Step`.
+pub enum StopCondition {
+    /// stop once the run has taken longer than the given wall-clock time.
+    WallClock(Duration),
+    /// stop when the conserved energy deviates from the energy of the first step by more
+    /// than the tolerance times the larger of its magnitude and the scale, an energy such
+    /// as N kB T, as the conserved energy of a liquid can be close to zero.
+    EnergyBlowUp { tolerance: f64, scale: f64 },
+    /// stop when any position, velocity or force is NaN or infinite.
+    NaN,
+    /// stop when the user supplied predicate on the world returns true.
+    Predicate(Box<dyn Fn(&World) -> bool>),
+}
+
+impl StopCondition {
+    fn reason(&self) -> StopReason {
+        match self {
+            StopCondition::WallClock(_) => StopReason::WallClock,
+            StopCondition::EnergyBlowUp { .. } => StopReason::EnergyBlowUp,
+            StopCondition::NaN => StopReason::NaN,
+            StopCondition::Predicate(_) => StopReason::Predicate,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Completed,
+    WallClock,
+    EnergyBlowUp,
+    NaN,
+    Predicate,
+    /// an update did not advance `CurStep`, e.g. as no integrator was added.
+    Stalled,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            StopReason::Completed => "completed",
+            StopReason::WallClock => "wall-clock limit reached",
+            StopReason::EnergyBlowUp => "energy blow-up",
+            StopReason::NaN => "non-finite value detected",
+            StopReason::Predicate => "user condition met",
+            StopReason::Stalled => "the step did not advance",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// The summary of a finished run.
+#[derive(Debug, Clone, Copy)]
+pub struct RunSummary {
+    pub reason: StopReason,
+    pub steps: u64,
+    pub wall_time: Duration,
+    pub kinetic_energy: f64,
+    pub potential_energy: f64,
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Run finished ({}) after {} steps in {:.3} s.", self.reason, self.steps, self.wall_time.as_secs_f64())?;
+        write!(
+            f,
+            "Final energies: kinetic {:e}, potential {:e}, total {:e}.",
+            self.kinetic_energy,
+            self.potential_energy,
+            self.kinetic_energy + self.potential_energy
+        )
+    }
+}
+
+/// Runs the app until `CurStep` reaches `Step`, one of the stop conditions is met or an
+/// update does not advance `CurStep`.
+pub struct Runner {
+    pub conditions: Vec<StopCondition>,
+    /// print the summary to the console when the run finishes.
+    pub verbose: bool,
+}
+
+impl Default for Runner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Runner {
+    pub fn new() -> Self {
+        Self { conditions: Vec::new(), verbose: true }
+    }
+
+    pub fn with_condition(mut self, condition: StopCondition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+
+    pub fn run(&self, app: &mut App) -> RunSummary {
+        let start = Instant::now();
+        let mut reference_energy: Option<f64> = None;
+        let mut reason = StopReason::Completed;
+
+        while current_step(&app.world) < total_step(&app.world) {
+            let step = current_step(&app.world);
+            app.update();
+            if current_step(&app.world) <= step {
+                reason = StopReason::Stalled;
+                break;
+            }
+
+            let energy = energy::conserved_energy(&app.world);
+            let reference = *reference_energy.get_or_insert(energy);
+
+            if let Some(stop) = self.check_conditions(&mut app.world, start, energy, reference) {
+                reason = stop;
+                break;
+            }
+        }
+
+        let summary = RunSummary {
+            reason,
+            steps: current_step(&app.world),
+            wall_time: start.elapsed(),
+            kinetic_energy: app.world.get_resource::<KineticEnergy>().map_or(0.0, |e| e.value),
+            potential_energy: app.world.get_resource::<PotentialEnergy>().map_or(0.0, |e| e.value),
+        };
+        if self.verbose {
+            println!("{}", summary);
+        }
+        summary
+    }
+
+    fn check_conditions(&self, world: &mut World, start: Instant, energy: f64, reference: f64) -> Option<StopReason> {
+        for condition in self.conditions.iter() {
+            let stop = match condition {
+                StopCondition::WallClock(limit) => start.elapsed() > *limit,
+                StopCondition::EnergyBlowUp { tolerance, scale } => {
+                    !energy.is_finite() || (energy - reference).abs() > tolerance * reference.abs().max(*scale)
+                }
+                StopCondition::NaN => has_non_finite(world),
+                StopCondition::Predicate(predicate) => predicate(world),
+            };
+            if stop {
+                return Some(condition.reason());
+            }
+        }
+        None
+    }
+}
+
+fn current_step(world: &World) -> u64 {
+    world.get_resource::<CurStep>().map_or(0, |step| step.n)
+}
+
+fn total_step(world: &World) -> u64 {
+    world.get_resource::<Step>().map_or(0, |step| step.n)
+}
+
+fn has_non_finite(world: &mut World) -> bool {
+    let finite = |v: &nalgebra::Vector3<f64>| v.iter().all(|x| x.is_finite());
+    let mut query = world.query::<(&Position, Option<&Velocity>, Option<&Force>)>();
+    query.iter(world).any(|(pos, vel, force)| {
+        !finite(&pos.pos)
+            || vel.is_some_and(|v| !finite(&v.vel))
+            || force.is_some_and(|f| !finite(&f.force))
+    })
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::{
+        atom::*,
+        molecular_dynamics::integration::{IntegrationPlugin, OldForce, TimeStep},
+        setup::SetupPlugin,
+    };
+    #[allow(unused_imports)]
+    use bevy::tasks::{ComputeTaskPool, TaskPool};
+    #[allow(unused_imports)]
+    use nalgebra::Vector3;
+
+    #[test]
+    fn test_runner_completion_and_nan() {
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin { number_steps: Step::new(20), ..default() });
+        app.add_plugin(IntegrationPlugin);
+        app.world.spawn()
+            .insert(Position::default())
+            .insert(Velocity { vel: Vector3::new(1.0, 0.0, 0.0) })
+            .insert(Force::default())
+            .insert(OldForce::default())
+            .insert(Mass { value: 39.948 });
+
+        let summary = Runner::new().with_condition(StopCondition::NaN).run(&mut app);
+        assert_eq!(summary.reason, StopReason::Completed);
+        assert_eq!(summary.steps, 20);
+
+        // a non-finite velocity is caught after the next step
+        app.world.insert_resource(Step::new(40));
+        let mut query = app.world.query::<&mut Velocity>();
+        query.iter_mut(&mut app.world).for_each(|mut vel| vel.vel.x = f64::NAN);
+        let summary = Runner::new().with_condition(StopCondition::NaN).run(&mut app);
+        assert_eq!(summary.reason, StopReason::NaN);
+        assert_eq!(summary.steps, 21);
+    }
+
+    #[test]
+    fn test_runner_stalled() {
+        // without an integrator nothing advances the step
+        let mut app = App::new();
+        app.insert_resource(ComputeTaskPool(TaskPool::new()));
+        app.add_plugin(SetupPlugin { number_steps: Step::new(20), ..default() });
+        let summary = Runner::new().run(&mut app);
+        assert_eq!(summary.reason, StopReason::Stalled);
+        assert_eq!(summary.steps, 0);
+    }
+
+    /// a constant force of 1e-12 N along z on every atom, which adds no potential energy.
+    #[allow(dead_code)]
+    fn push(mut query: Query<&mut Force>) {
+        for mut force in query.iter_mut() {
+            force.force.z += 1e-12;
+        }
+    }
+
+    #[test]
+    fn test_runner_energy_blow_up() {
+        // an atom pushed from rest gains energy the potential energy does not count, from a
+        // conserved energy of almost zero after the first step.
+        let pushed_atom = || {
+            let mut app = App::new();
+            app.insert_resource(ComputeTaskPool(TaskPool::new()));
+            app.add_plugin(SetupPlugin {
+                time_step: TimeStep::new(1e-15),
+                number_steps: Step::new(20),
+                ..default()
+            });
+            app.add_plugin(IntegrationPlugin);
+            app.add_stage_before(CoreStage::Update, "push", SystemStage::parallel());
+            app.add_system_to_stage("push", push);
+            app.world.spawn()
+                .insert(Position::default())
+                .insert(Velocity { vel: Vector3::zeros() })
+                .insert(Force::default())
+                .insert(OldForce::default())
+                .insert(Mass { value: 1.0 });
+            app
+        };
+        // the kinetic energy reaches F^2 t^2 / 2m of about 1.2e-25 J, within a tenth of the
+        // energy scale of 1e-23 J
+        let runner = Runner::new().with_condition(StopCondition::EnergyBlowUp { tolerance: 0.1, scale: 1e-23 });
+        assert_eq!(runner.run(&mut pushed_atom()).reason, StopReason::Completed);
+        // but not of an energy scale of 1e-25 J
+        let runner = Runner::new().with_condition(StopCondition::EnergyBlowUp { tolerance: 0.1, scale: 1e-25 });
+        let summary = runner.run(&mut pushed_atom());
+        assert_eq!(summary.reason, StopReason::EnergyBlowUp);
+        assert!(summary.steps < 20);
+    }
+}
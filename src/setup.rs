@@ -2,11 +2,19 @@ use bevy::prelude::*;
 use crate::{
     atom::AtomNumber,
     molecular_dynamics::{
-        integration::{TimeStep, Step, BatchSize, CurStep},
-        lj_interaction::LJCutOff
+        integration::{TimeStep, Step, BatchSize, CurStep, SimTime, Integrator},
+        atom_changes::NextAtomID,
+        lj_interaction::LJCutOff,
+        energy::ExtendedEnergy,
+    },
+    simbox::{SimBox, ShrinkWrapMargin},
+    region::{AtomRegion, Region},
+    random::{RandomSeed, SimRng},
+    units::UnitSystem,
+    output::{
+        file::{TrjName, OutInterval},
+        checkpoint::{Checkpoint, restore_atoms},
     },
-    simbox::{SimBox},
-    output::file::{TrjName, OutInterval},
 };
 use nalgebra::{Vector3};
 
@@ -18,13 +26,18 @@ use nalgebra::{Vector3};
 
 #[derive(Clone)]
 pub struct SetupPlugin {
-    // atoms information
+    // the unit system all the other parameters are given in
+    pub units: UnitSystem,
+
+    // atoms information, and the region of the box the atoms are created in
     pub atom_number: AtomNumber,
+    pub atom_region: Option<Region>,
 
     // integration parameters
     pub time_step: TimeStep,
     pub number_steps: Step,
     pub batch_size: BatchSize,
+    pub integrator: Integrator,
 
     // simulation box parameters
     pub box_size: SimBox,
@@ -36,6 +49,12 @@ pub struct SetupPlugin {
     pub cur_step: CurStep,
     pub trj_name: TrjName,
     pub output_interval: OutInterval,
+
+    // random number generation
+    pub random_seed: RandomSeed,
+
+    // restart parameters, the checkpoint file to restart from
+    pub restart: Option<String>,
 }
 
 impl SetupPlugin {
@@ -64,27 +83,67 @@ impl SetupPlugin {
         let output_interval = OutInterval::new(interval);
 
         Self {
+            units: UnitSystem::default(),
             atom_number,
+            atom_region: None,
             time_step,
             number_steps,
             batch_size,
+            integrator: Integrator::default(),
             box_size,
 
             lj_cutoff,
             cur_step,
             trj_name,
-            output_interval
+            output_interval,
+            random_seed: RandomSeed::default(),
+            restart: None,
         }
     }
+
+    /// run the simulation in the given unit system, the other parameters of the
+    /// plugin are expected to be given in the same units.
+    pub fn with_units(mut self, units: UnitSystem) -> Self {
+        self.units = units;
+        self
+    }
+
+    /// integrate the equations of motion with the given integrator instead of velocity verlet.
+    pub fn with_integrator(mut self, integrator: Integrator) -> Self {
+        self.integrator = integrator;
+        self
+    }
+
+    /// seed the random number generator used by all the stochastic parts of the simulation.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.random_seed = RandomSeed(seed);
+        self
+    }
+
+    /// create the atoms in the given region of the box instead of the whole box.
+    pub fn with_atom_region(mut self, region: Region) -> Self {
+        self.atom_region = Some(region);
+        self
+    }
+
+    /// restart the simulation from the given checkpoint file.
+    pub fn with_restart(mut self, filename: String) -> Self {
+        self.restart = Some(filename);
+        self
+    }
 }
 impl Default for SetupPlugin {
     fn default() -> Self {
         Self { 
+            units: UnitSystem::default(),
+
             atom_number: AtomNumber::default(),
+            atom_region: None,
 
             time_step: TimeStep::default(), 
             number_steps: Step::default(), 
             batch_size: default(), 
+            integrator: Integrator::default(),
 
             box_size: SimBox::default(), 
 
@@ -92,7 +151,11 @@ impl Default for SetupPlugin {
 
             cur_step: CurStep::init(),
             trj_name: TrjName::default(), 
-            output_interval: OutInterval::default()
+            output_interval: OutInterval::default(),
+
+            random_seed: RandomSeed::default(),
+
+            restart: None,
         }
     }
 }
@@ -102,24 +165,58 @@ impl Default for SetupPlugin {
 
 impl Plugin for SetupPlugin {
     fn build(&self, app: &mut App) {
+        // add the unit system
+        app.world.insert_resource(self.units);
+
         // add atom information
         app.world.insert_resource(self.atom_number);
+        if let Some(region) = &self.atom_region {
+            app.world.insert_resource(AtomRegion { region: region.clone() });
+        }
 
         // add integration parameters
         app.world.insert_resource(self.batch_size);
         app.world.insert_resource(self.number_steps);
         app.world.insert_resource(self.time_step);
+        app.world.insert_resource(self.integrator.clone());
 
         // add simulation box parameters
         app.world.insert_resource(self.box_size);
+        app.world.insert_resource(ShrinkWrapMargin::new(&self.box_size));
 
         // add lennard jones parameters
         app.world.insert_resource(self.lj_cutoff);
 
         // add output paramters
         app.world.insert_resource(self.cur_step);
+        app.world.insert_resource(SimTime::default());
         app.world.insert_resource(self.output_interval);
         app.world.insert_resource(self.trj_name.clone());
+
+        // add the seeded random number generator
+        app.world.insert_resource(self.random_seed);
+        app.world.insert_resource(SimRng::new(self.random_seed));
+
+        // restart from a checkpoint, this overrides the box, the current step, the simulated time
+        // and the number of atoms, and the atoms are restored from the checkpoint instead of being created.
+        if let Some(filename) = &self.restart {
+            let checkpoint = match Checkpoint::read_from_file(filename) {
+                Err(why) => panic!("couldn't read checkpoint {}: {}", filename, why),
+                Ok(checkpoint) => checkpoint,
+            };
+            app.world.insert_resource(checkpoint.simbox);
+            app.world.insert_resource(CurStep { n: checkpoint.cur_step });
+            app.world.insert_resource(SimTime { t: checkpoint.time });
+            app.world.insert_resource(checkpoint.rng.seed);
+            app.world.insert_resource(checkpoint.rng.clone());
+            app.world.insert_resource(ExtendedEnergy { value: checkpoint.extended_energy });
+            app.world.insert_resource(AtomNumber::new(checkpoint.atoms.len() as u64));
+            if let Some(id) = checkpoint.next_atom_id {
+                app.world.insert_resource(NextAtomID { id });
+            }
+            app.world.insert_resource(checkpoint);
+            app.add_startup_system(restore_atoms.label(SetupSystems::RestoreAtoms));
+        }
     
         //app.add_system_to_stage(CoreStage::Update, deflag_new_atoms.label(InitiateSystems::DeflagNewAtoms));
     }
@@ -128,6 +225,7 @@ impl Plugin for SetupPlugin {
 
 
 
+
 pub mod tests {
     #[allow(unused_imports)]
     use super::*;
@@ -146,4 +244,5 @@ pub mod tests {
 #[derive(PartialEq, Clone, Hash, Debug, Eq, SystemLabel)]
 pub enum SetupSystems {
     CreateAtoms,
+    RestoreAtoms,
 }
\ No newline at end of file
@@ -1,19 +1,222 @@
 use bevy::prelude::*;
-use nalgebra::{Vector3};
+use nalgebra::{Matrix3, Vector3};
 
+/// the boundary condition along one axis of the box, as in the LAMMPS `boundary` command.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Boundary {
+    /// the atoms leaving the box enter it again from the opposite face.
+    #[default]
+    Periodic,
+    /// non-periodic, the faces stay where they are and the atoms may leave the box.
+    Fixed,
+    /// non-periodic, the faces follow the extent of the atoms.
+    ShrinkWrapped,
+    /// non-periodic, the atoms reaching a face bounce back into the box like from a mirror.
+    Reflective,
+}
+
+impl Boundary {
+    pub fn is_periodic(&self) -> bool {
+        *self == Boundary::Periodic
+    }
+
+    /// the style of both faces in the LAMMPS `BOX BOUNDS` header, reflective walls are
+    /// fixed faces there.
+    pub fn lammps_style(&self) -> &'static str {
+        match self {
+            Boundary::Periodic => "pp",
+            Boundary::Fixed | Boundary::Reflective => "ff",
+            Boundary::ShrinkWrapped => "ss",
+        }
+    }
+}
+
+/// The simulation box, following the LAMMPS convention for triclinic boxes: the edge vectors
+/// are a = (lx, 0, 0), b = (xy, ly, 0) and c = (xz, yz, lz), where the lengths lx, ly, lz
+/// are the `dimension` and the tilt factors xy, xz, yz are the `tilt`. The box is
+/// orthorhombic when the tilt factors are zero. The faces of the non-periodic axes are the
+/// planes at the origin and at the origin plus the length along that axis, so these faces must
+/// not be tilted: a non-periodic x needs xy = xz = 0 and a non-periodic y needs yz = 0, while
+/// the faces of z are always perpendicular to it.
 #[derive(Debug, Clone, Copy)]
 pub struct SimBox {
     pub origin: Vector3<f64>,
     pub dimension: Vector3<f64>,
+    /// the tilt factors (xy, xz, yz).
+    pub tilt: Vector3<f64>,
+    /// the boundary conditions along x, y and z.
+    pub boundary: [Boundary; 3],
 }
 
 impl SimBox {
     pub fn new(origin: Vector3<f64>, x_len: f64, y_len: f64, z_len: f64) -> Self {
         Self {
             origin,
-            dimension: Vector3::new(x_len, y_len, z_len)
+            dimension: Vector3::new(x_len, y_len, z_len),
+            tilt: Vector3::zeros(),
+            boundary: [Boundary::Periodic; 3],
+        }
+    }
+
+    /// a triclinic box, with the tilt factors (xy, xz, yz).
+    pub fn triclinic(origin: Vector3<f64>, dimension: Vector3<f64>, tilt: Vector3<f64>) -> Self {
+        Self { origin, dimension, tilt, boundary: [Boundary::Periodic; 3] }
+    }
+
+    /// the box with the given boundary conditions along x, y and z.
+    pub fn with_boundary(mut self, boundary: [Boundary; 3]) -> Self {
+        self.boundary = boundary;
+        if let Some(axis) = self.tilted_face() {
+            panic!("the faces of the non periodic axis {} are tilted, by the tilt factors {:?}", axis, self.tilt);
+        }
+        self
+    }
+
+    /// the first non-periodic axis whose faces are not perpendicular to it, if any.
+    pub fn tilted_face(&self) -> Option<usize> {
+        let tilted = [self.tilt.x != 0.0 || self.tilt.y != 0.0, self.tilt.z != 0.0, false];
+        (0..3).find(|&d| !self.boundary[d].is_periodic() && tilted[d])
+    }
+
+    /// the box with the given cell matrix, whose columns are the edge vectors. Only the upper
+    /// triangle is used, as the edge vectors of the box are always in that form.
+    pub fn from_cell_matrix(origin: Vector3<f64>, h: &Matrix3<f64>) -> Self {
+        Self::triclinic(origin, Vector3::new(h[(0, 0)], h[(1, 1)], h[(2, 2)]), Vector3::new(h[(0, 1)], h[(0, 2)], h[(1, 2)]))
+    }
+
+    /// changes the edge vectors of the box, keeping its origin and boundary conditions, which
+    /// must still allow the new tilt factors.
+    pub fn set_cell_matrix(&mut self, h: &Matrix3<f64>) {
+        let boundary = self.boundary;
+        *self = Self::from_cell_matrix(self.origin, h).with_boundary(boundary);
+    }
+
+    /// the cell matrix, whose columns are the edge vectors a, b and c.
+    pub fn cell_matrix(&self) -> Matrix3<f64> {
+        Matrix3::new(
+            self.dimension.x, self.tilt.x, self.tilt.y,
+            0.0, self.dimension.y, self.tilt.z,
+            0.0, 0.0, self.dimension.z,
+        )
+    }
+
+    /// whether the axes are periodic, as 1.0 or 0.0 to mask the periodic images.
+    fn periodic_mask(&self) -> Vector3<f64> {
+        Vector3::from_fn(|d, _| if self.boundary[d].is_periodic() { 1.0 } else { 0.0 })
+    }
+
+    pub fn is_triclinic(&self) -> bool {
+        self.tilt != Vector3::zeros()
+    }
+
+    pub fn volume(&self) -> f64 {
+        self.dimension.x * self.dimension.y * self.dimension.z
+    }
+
+    /// the smallest distance between opposite faces of the box.
+    pub fn min_width(&self) -> f64 {
+        let h = self.cell_matrix();
+        let (a, b, c) = (h.column(0), h.column(1), h.column(2));
+        let volume = self.volume();
+        (volume / b.cross(&c).norm()).min(volume / c.cross(&a).norm()).min(volume / a.cross(&b).norm())
+    }
+
+    /// the fractional coordinates of a vector, i.e. its components along the edge vectors.
+    pub fn fractional(&self, r: Vector3<f64>) -> Vector3<f64> {
+        // back substitution with the upper triangular cell matrix
+        let (lx, ly, lz) = (self.dimension.x, self.dimension.y, self.dimension.z);
+        let (xy, xz, yz) = (self.tilt.x, self.tilt.y, self.tilt.z);
+        let sz = r.z / lz;
+        let sy = (r.y - yz * sz) / ly;
+        let sx = (r.x - xy * sy - xz * sz) / lx;
+        Vector3::new(sx, sy, sz)
+    }
+
+    /// the position with the given fractional coordinates.
+    pub fn from_fractional(&self, s: Vector3<f64>) -> Vector3<f64> {
+        self.origin + self.cell_matrix() * s
+    }
+
+    /// the minimum image of the separation vector r between two atoms, along the periodic
+    /// axes. In a triclinic box the image is only guaranteed to be the shortest one when it is
+    /// shorter than half the smallest width of the box, which is all the cutoffs need.
+    pub fn minimum_image(&self, r: Vector3<f64>) -> Vector3<f64> {
+        if self.is_triclinic() {
+            let s = self.fractional(r);
+            return r - self.cell_matrix() * s.map(f64::round).component_mul(&self.periodic_mask());
+        }
+        let image = |d: usize| {
+            if self.boundary[d].is_periodic() {
+                r[d] - self.dimension[d] * (r[d] / self.dimension[d]).round()
+            } else {
+                r[d]
+            }
+        };
+        Vector3::new(image(0), image(1), image(2))
+    }
+
+    /// the image of the position inside the box, along the periodic axes.
+    pub fn wrap(&self, pos: Vector3<f64>) -> Vector3<f64> {
+        let s = self.fractional(pos - self.origin);
+        pos - self.cell_matrix() * s.map(f64::floor).component_mul(&self.periodic_mask())
+    }
+
+    /// wraps the position along the periodic axes, and mirrors the position and the velocity
+    /// of an atom which went through a face of a reflective axis. Returns the factors the
+    /// velocity was multiplied by, -1 along the axes where it was mirrored and 1 elsewhere.
+    pub fn apply_boundaries(&self, pos: &mut Vector3<f64>, vel: &mut Vector3<f64>) -> Vector3<f64> {
+        *pos = self.wrap(*pos);
+        let mut mirror = Vector3::repeat(1.0);
+        for d in 0..3 {
+            if self.boundary[d] != Boundary::Reflective {
+                continue;
+            }
+            let low = self.origin[d];
+            let high = self.origin[d] + self.dimension[d];
+            if pos[d] < low {
+                pos[d] = 2.0 * low - pos[d];
+                vel[d] = -vel[d];
+                mirror[d] = -1.0;
+            } else if pos[d] > high {
+                pos[d] = 2.0 * high - pos[d];
+                vel[d] = -vel[d];
+                mirror[d] = -1.0;
+            }
+        }
+        mirror
+    }
+
+    /// moves the faces of the shrink-wrapped axes to the lowest and highest coordinates
+    /// of the atoms along them, widened by the margin on both sides.
+    pub fn shrink_wrap(&mut self, low: &Vector3<f64>, high: &Vector3<f64>, margin: &ShrinkWrapMargin) {
+        for d in 0..3 {
+            if self.boundary[d] == Boundary::ShrinkWrapped {
+                self.origin[d] = low[d] - margin.margin[d];
+                self.dimension[d] = high[d] - low[d] + 2.0 * margin.margin[d];
+            }
         }
     }
+
+    /// scales the box around its origin by the factors mu along x, y and z, so that the
+    /// positions scaled in the same way stay at the same fractional coordinates. The tilt
+    /// factors are scaled along x and y with the edge vectors.
+    pub fn scale(&mut self, mu: &Vector3<f64>) {
+        self.set_cell_matrix(&(Matrix3::from_diagonal(mu) * self.cell_matrix()));
+    }
+}
+
+/// the distance between the faces of the shrink-wrapped axes and the outermost atoms, 1e-4 of
+/// the lengths of the initial box like in LAMMPS, so that the box keeps a thickness when the
+/// atoms are all in a plane, e.g. a single atom or a flat substrate.
+#[derive(Debug, Clone, Copy)]
+pub struct ShrinkWrapMargin {
+    pub margin: Vector3<f64>,
+}
+
+impl ShrinkWrapMargin {
+    pub fn new(simbox: &SimBox) -> Self {
+        Self { margin: simbox.dimension * 1e-4 }
+    }
 }
 
 impl Default for SimBox {
@@ -22,6 +225,92 @@ impl Default for SimBox {
     }
 }
 
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_triclinic_box() {
+        let simbox = SimBox::triclinic(Vector3::new(-1.0, 0.0, 2.0), Vector3::new(4.0, 5.0, 6.0), Vector3::new(1.0, -1.5, 2.0));
+        assert!((simbox.volume() - simbox.cell_matrix().determinant()).abs() < 1e-12);
+
+        for i in 0..100 {
+            let x = i as f64;
+            let r = Vector3::new(7.0 * x.sin(), 9.0 * (1.3 * x).cos(), 11.0 * (0.7 * x).sin());
+
+            // the minimum image is an image of r, and the shortest one within half the width of the box
+            let image = simbox.minimum_image(r);
+            let mut shortest = f64::MAX;
+            for n in 0..343 {
+                let shift = Vector3::new((n % 7) as f64, ((n / 7) % 7) as f64, (n / 49) as f64) - Vector3::repeat(3.0);
+                shortest = shortest.min((r + simbox.cell_matrix() * shift).norm());
+            }
+            if shortest < simbox.min_width() / 2.0 {
+                assert!((image.norm() - shortest).abs() < 1e-9, "{:?} {} {}", r, image.norm(), shortest);
+            }
+            let s = simbox.fractional(image - r);
+            assert!((s - s.map(f64::round)).norm() < 1e-9);
+
+            // the wrapped position is inside the box, and an image of the position
+            let pos = simbox.origin + r;
+            let s = simbox.fractional(simbox.wrap(pos) - simbox.origin);
+            assert!(s.min() >= 0.0 && s.max() < 1.0, "{:?}", s);
+            assert!(simbox.minimum_image(simbox.wrap(pos) - pos).norm() < 1e-9);
+        }
+
+        // scaling keeps the fractional coordinates of the scaled positions
+        let mu = Vector3::new(1.1, 0.9, 1.05);
+        let pos = simbox.from_fractional(Vector3::new(0.2, 0.7, 0.4));
+        let mut scaled = simbox;
+        scaled.scale(&mu);
+        let new_pos = simbox.origin + (pos - simbox.origin).component_mul(&mu);
+        assert!((scaled.fractional(new_pos - scaled.origin) - Vector3::new(0.2, 0.7, 0.4)).norm() < 1e-12);
+        assert!((scaled.volume() / simbox.volume() - mu.product()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_boundary_conditions() {
+        let simbox = SimBox::new(Vector3::zeros(), 10.0, 10.0, 10.0)
+            .with_boundary([Boundary::Periodic, Boundary::Fixed, Boundary::Reflective]);
+
+        // only the periodic axis has images
+        let r = simbox.minimum_image(Vector3::new(8.0, 8.0, 8.0));
+        assert_eq!(r, Vector3::new(-2.0, 8.0, 8.0));
+
+        // the atom is wrapped along x, keeps going along y and bounces back along z
+        let mut pos = Vector3::new(10.5, 11.0, 10.25);
+        let mut vel = Vector3::new(1.0, 1.0, 1.0);
+        let mirror = simbox.apply_boundaries(&mut pos, &mut vel);
+        assert!((pos - Vector3::new(0.5, 11.0, 9.75)).norm() < 1e-12, "{:?}", pos);
+        assert_eq!(mirror, Vector3::new(1.0, 1.0, -1.0));
+        assert_eq!(vel, Vector3::new(1.0, 1.0, -1.0));
+
+        let mut simbox = simbox.with_boundary([Boundary::Periodic, Boundary::ShrinkWrapped, Boundary::Fixed]);
+        let margin = ShrinkWrapMargin::new(&simbox);
+        simbox.shrink_wrap(&Vector3::new(1.0, -2.0, 3.0), &Vector3::new(4.0, 5.0, 6.0), &margin);
+        assert_eq!(simbox.origin, Vector3::new(0.0, -2.001, 0.0));
+        assert_eq!(simbox.dimension, Vector3::new(10.0, 7.002, 10.0));
+        assert_eq!(simbox.boundary.map(|boundary| boundary.lammps_style()).join(" "), "pp ss ff");
+
+        // the faces of z stay perpendicular to it whatever the tilt, those of x and y do not
+        let tilted = SimBox::triclinic(Vector3::zeros(), Vector3::new(10.0, 10.0, 10.0), Vector3::new(1.0, 0.0, 2.0));
+        assert_eq!(tilted.with_boundary([Boundary::Periodic, Boundary::Periodic, Boundary::Reflective]).tilted_face(), None);
+        assert_eq!(SimBox { boundary: [Boundary::Periodic, Boundary::Fixed, Boundary::Fixed], ..tilted }.tilted_face(), Some(1));
+        assert_eq!(SimBox { boundary: [Boundary::ShrinkWrapped, Boundary::Periodic, Boundary::Periodic], ..tilted }.tilted_face(), Some(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "the faces of the non periodic axis 0 are tilted")]
+    fn test_tilted_non_periodic_face() {
+        // a barostat tilting the box in xy, whose x faces are reflective
+        let mut simbox = SimBox::new(Vector3::zeros(), 10.0, 10.0, 10.0)
+            .with_boundary([Boundary::Reflective, Boundary::Periodic, Boundary::Periodic]);
+        let mut h = simbox.cell_matrix();
+        h[(0, 1)] = 0.5;
+        simbox.set_cell_matrix(&h);
+    }
+}
+
 /* 
 #[derive(Debug, Clone, Copy)]
 pub struct BoxBound {
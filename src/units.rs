@@ -0,0 +1,131 @@
+//! Unit systems for the simulation.
+//!
+//! The simulation runs in the units of the chosen `UnitSystem`: positions, velocities,
+//! forces, energies and the time step are all expressed in those units, and so are the
+//! inputs given in `SetupPlugin` and the quantities written to the output files.
+//! `Mass` values are given in atomic mass units, except for reduced LJ units where they
+//! are in units of the reference mass.
+//!
+//! The available systems follow the LAMMPS conventions:
+//!
+//! | system | length | time | energy   | mass  | temperature |
+//! |--------|--------|------|----------|-------|-------------|
+//! | SI     | m      | s    | J        | amu   | K           |
+//! | Real   | Å      | fs   | kcal/mol | g/mol | K           |
+//! | Metal  | Å      | ps   | eV       | g/mol | K           |
+//! | LJ     | σ      | τ    | ε        | m     | ε/k_B       |
+
+use crate::constant;
+
+/// A physical quantity, used to convert values between SI and the simulation units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantity {
+    Length,
+    Time,
+    Mass,
+    Energy,
+    Velocity,
+    Force,
+    Temperature,
+    Pressure,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum UnitSystem {
+    #[default]
+    SI,
+    Real,
+    Metal,
+    /// reduced Lennard-Jones units, defined by sigma (m), epsilon (J) and the
+    /// reference mass (amu).
+    LJ { sigma: f64, epsilon: f64, mass: f64 },
+}
+
+impl UnitSystem {
+    /// reduced units for argon, sigma = 3.4 Å, epsilon = 1.654e-21 J and m = 39.948 amu.
+    pub fn argon_lj() -> Self {
+        UnitSystem::LJ { sigma: 3.4e-10, epsilon: 1.654e-21, mass: 39.948 }
+    }
+
+    /// The size of one simulation unit of the given quantity in SI units.
+    pub fn si_value(&self, quantity: Quantity) -> f64 {
+        match quantity {
+            Quantity::Length => match self {
+                UnitSystem::SI => 1.0,
+                UnitSystem::Real | UnitSystem::Metal => 1e-10,
+                UnitSystem::LJ { sigma, .. } => *sigma,
+            },
+            Quantity::Energy => match self {
+                UnitSystem::SI => 1.0,
+                UnitSystem::Real => constant::KCAL_PER_MOL,
+                UnitSystem::Metal => constant::ELECTRONVOLT,
+                UnitSystem::LJ { epsilon, .. } => *epsilon,
+            },
+            Quantity::Mass => match self {
+                UnitSystem::SI | UnitSystem::Real | UnitSystem::Metal => constant::AMU,
+                UnitSystem::LJ { mass, .. } => mass * constant::AMU,
+            },
+            Quantity::Time => match self {
+                UnitSystem::SI => 1.0,
+                UnitSystem::Real => 1e-15,
+                UnitSystem::Metal => 1e-12,
+                UnitSystem::LJ { sigma, epsilon, mass } => sigma * (mass * constant::AMU / epsilon).sqrt(),
+            },
+            Quantity::Temperature => match self {
+                UnitSystem::LJ { epsilon, .. } => epsilon / constant::BOLTZCONST,
+                _ => 1.0,
+            },
+            Quantity::Velocity => self.si_value(Quantity::Length) / self.si_value(Quantity::Time),
+            Quantity::Force => self.si_value(Quantity::Energy) / self.si_value(Quantity::Length),
+            Quantity::Pressure => self.si_value(Quantity::Energy) / self.si_value(Quantity::Length).powi(3),
+        }
+    }
+
+    /// converts a value in SI units to simulation units.
+    pub fn from_si(&self, quantity: Quantity, value: f64) -> f64 {
+        value / self.si_value(quantity)
+    }
+
+    /// converts a value in simulation units to SI units.
+    pub fn to_si(&self, quantity: Quantity, value: f64) -> f64 {
+        value * self.si_value(quantity)
+    }
+
+    /// The Boltzmann constant in simulation units of energy per temperature.
+    pub fn boltzmann(&self) -> f64 {
+        constant::BOLTZCONST * self.si_value(Quantity::Temperature) / self.si_value(Quantity::Energy)
+    }
+
+    /// The factor converting `Mass` values to the mass unit consistent with the energy,
+    /// length and time units, i.e. the acceleration is `force / (mass_conversion() * mass)`.
+    /// It is the atomic mass unit in SI units and 1 in reduced units.
+    pub fn mass_conversion(&self) -> f64 {
+        let length = self.si_value(Quantity::Length);
+        let time = self.si_value(Quantity::Time);
+        self.si_value(Quantity::Mass) * length * length / (self.si_value(Quantity::Energy) * time * time)
+    }
+}
+
+pub mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_mass_conversion() {
+        assert_eq!(UnitSystem::SI.mass_conversion(), constant::AMU);
+        assert_approx_eq!(UnitSystem::argon_lj().mass_conversion(), 1.0, 1e-12);
+        // the LAMMPS ftm2v factors for real and metal units.
+        assert_approx_eq!(UnitSystem::Real.mass_conversion() * (1.0 / 48.88821291 / 48.88821291), 1.0, 1e-8);
+        assert_approx_eq!(UnitSystem::Metal.mass_conversion() / 1.0364269e-4, 1.0, 1e-7);
+    }
+
+    #[test]
+    fn test_boltzmann() {
+        assert_eq!(UnitSystem::SI.boltzmann(), constant::BOLTZCONST);
+        assert_approx_eq!(UnitSystem::argon_lj().boltzmann(), 1.0, 1e-12);
+        assert_approx_eq!(UnitSystem::Metal.boltzmann() / 8.617333262e-5, 1.0, 1e-9);
+        assert_approx_eq!(UnitSystem::Real.boltzmann() / 0.0019872043, 1.0, 1e-7);
+    }
+}